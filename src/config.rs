@@ -1,10 +1,108 @@
-use std::env;
+use std::{collections::HashSet, env, fs};
+
+use log::error;
+use serde::Deserialize;
+
+use crate::{
+    error::{AppError, AppResult},
+    paths::split_list,
+    torrent::{
+        category::Category, client::Torrent, notifier::NotificationConfig, policy::PolicyConfig,
+        webhook::Webhook,
+    },
+};
 
 #[derive(Debug, Clone)]
 pub struct ServerConfig {
     pub discovery_addr: String,
     pub server_addr: String,
     pub transmission_url: String,
+    pub download_dirs: Vec<String>,
+    pub library_roots: Vec<String>,
+    /// Where state edited through the API is kept
+    pub data_dir: String,
+    /// Bytes to keep free after adding a torrent
+    pub disk_reserve: u64,
+    pub disk_pause_below: Option<u64>,
+    pub disk_resume_above: Option<u64>,
+    pub categories: Vec<Category>,
+    pub policies: PolicyConfig,
+    pub webhooks: Vec<Webhook>,
+    pub notifications: NotificationConfig,
+    pub delete_policy: DeletePolicy,
+}
+
+/// Whether removing a torrent may also delete its downloaded data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeletePolicy {
+    Deny,
+    /// Every torrent has to be confirmed by passing its infohash
+    Confirm,
+    Allow,
+}
+
+impl DeletePolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "deny" => Some(DeletePolicy::Deny),
+            "confirm" => Some(DeletePolicy::Confirm),
+            "allow" => Some(DeletePolicy::Allow),
+            _ => None,
+        }
+    }
+
+    pub fn check(&self, torrents: &[&Torrent], confirm: &[String]) -> AppResult<()> {
+        match self {
+            DeletePolicy::Allow => Ok(()),
+            DeletePolicy::Deny => Err(AppError::ValidationError(
+                "Deleting torrent data is disabled".into(),
+            )),
+            DeletePolicy::Confirm => {
+                let missing: Vec<_> = torrents
+                    .iter()
+                    .filter(|t| !confirm.iter().any(|c| c.eq_ignore_ascii_case(&t.hash)))
+                    .map(|t| t.id.to_string())
+                    .collect();
+                if missing.is_empty() {
+                    Ok(())
+                } else {
+                    Err(AppError::ValidationError(format!(
+                        "Confirm data deletion with the infohash of torrents: {}",
+                        missing.join(", ")
+                    )))
+                }
+            }
+        }
+    }
+}
+
+// Structured settings that do not fit in environment variables,
+// read from the JSON file named by CONFIG_FILE
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct FileConfig {
+    categories: Vec<Category>,
+    policies: PolicyConfig,
+    webhooks: Vec<Webhook>,
+    notifications: NotificationConfig,
+}
+
+impl FileConfig {
+    fn load() -> Self {
+        let path = match env::var("CONFIG_FILE") {
+            Ok(path) => path,
+            Err(_) => return Self::default(),
+        };
+
+        let loaded = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| serde_json::from_str(&text).map_err(|e| e.to_string()));
+
+        loaded.unwrap_or_else(|e| {
+            error!("Could not load config file {}: {}", path, e);
+            Self::default()
+        })
+    }
 }
 
 impl Default for ServerConfig {
@@ -13,6 +111,17 @@ impl Default for ServerConfig {
             discovery_addr: "0.0.0.0:9191".to_string(),
             server_addr: "0.0.0.0:9090".to_string(),
             transmission_url: "http://127.0.0.1:9091/transmission/rpc".to_string(),
+            download_dirs: Vec::new(),
+            library_roots: Vec::new(),
+            data_dir: "data".to_string(),
+            disk_reserve: 1 << 30,
+            disk_pause_below: None,
+            disk_resume_above: None,
+            categories: Vec::new(),
+            policies: PolicyConfig::default(),
+            webhooks: Vec::new(),
+            notifications: NotificationConfig::default(),
+            delete_policy: DeletePolicy::Confirm,
         }
     }
 }
@@ -20,11 +129,83 @@ impl Default for ServerConfig {
 impl ServerConfig {
     pub fn from_env() -> Self {
         let default = Self::default();
+        let mut file = FileConfig::load();
+        file.policies.rules.retain(|rule| match rule.validate() {
+            Ok(_) => true,
+            Err(e) => {
+                error!("Ignoring seeding policy: {}", e);
+                false
+            }
+        });
+        file.webhooks.retain(|hook| match hook.validate() {
+            Ok(_) => true,
+            Err(e) => {
+                error!("Ignoring webhook: {}", e);
+                false
+            }
+        });
+        // Names are looked up ignoring case, so "Movies" and "movies" are the same category
+        let mut names = HashSet::new();
+        file.categories.retain(|category| match category.validate() {
+            Ok(_) if names.insert(category.name.to_lowercase()) => true,
+            Ok(_) => {
+                error!("Ignoring category {}: the name is taken", category.name);
+                false
+            }
+            Err(e) => {
+                error!("Ignoring category: {}", e);
+                false
+            }
+        });
         
         Self {
             discovery_addr: env::var("D_ADDR").unwrap_or(default.discovery_addr),
             server_addr: env::var("S_ADDR").unwrap_or(default.server_addr),
             transmission_url: env::var("TURL").unwrap_or(default.transmission_url),
+            download_dirs: env::var("DOWNLOAD_DIRS")
+                .map(|v| split_list(&v))
+                .unwrap_or(default.download_dirs),
+            library_roots: env::var("LIBRARY_ROOTS")
+                .map(|v| split_list(&v))
+                .unwrap_or(default.library_roots),
+            data_dir: env::var("DATA_DIR").unwrap_or(default.data_dir),
+            disk_reserve: gigabytes("DISK_RESERVE_GB").unwrap_or(default.disk_reserve),
+            disk_pause_below: gigabytes("DISK_PAUSE_BELOW_GB"),
+            disk_resume_above: gigabytes("DISK_RESUME_ABOVE_GB"),
+            categories: file.categories,
+            policies: file.policies,
+            webhooks: file.webhooks,
+            notifications: file.notifications,
+            delete_policy: env::var("DELETE_DATA")
+                .ok()
+                .and_then(|v| {
+                    let policy = DeletePolicy::parse(&v);
+                    if policy.is_none() {
+                        error!("Unknown DELETE_DATA policy: {}", v);
+                    }
+                    policy
+                })
+                .unwrap_or(default.delete_policy),
+        }
+    }
+
+    /// Directories torrents may be added to: the configured download dirs plus every category's.
+    pub fn allowed_dirs(&self) -> Vec<String> {
+        self.download_dirs
+            .iter()
+            .cloned()
+            .chain(self.categories.iter().map(|c| c.download_dir.clone()))
+            .collect()
+    }
+}
+
+fn gigabytes(name: &str) -> Option<u64> {
+    let value = env::var(name).ok()?;
+    match value.trim().parse::<f64>() {
+        Ok(gb) if gb >= 0.0 => Some((gb * (1u64 << 30) as f64) as u64),
+        _ => {
+            error!("Invalid {}: {}", name, value);
+            None
         }
     }
 }
@@ -67,4 +248,39 @@ impl Default for L337xToSelectors {
             magnet: "a[href^=magnet]".to_string(),
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn torrent(id: i64, hash: &str) -> Torrent {
+        Torrent {
+            id,
+            hash: hash.to_string(),
+            status: "done".to_string(),
+            progress: 100,
+            ..testing::torrent()
+        }
+    }
+
+    #[test]
+    fn delete_policy_parse() {
+        assert_eq!(DeletePolicy::parse(" Allow"), Some(DeletePolicy::Allow));
+        assert_eq!(DeletePolicy::parse("deny"), Some(DeletePolicy::Deny));
+        assert_eq!(DeletePolicy::parse("yes"), None);
+    }
+
+    #[test]
+    fn delete_policy_check() {
+        let a = torrent(1, "aaaa");
+        let b = torrent(2, "bbbb");
+        let torrents = vec![&a, &b];
+        let confirm = |hashes: &[&str]| hashes.iter().map(|h| h.to_string()).collect::<Vec<_>>();
+
+        assert!(DeletePolicy::Allow.check(&torrents, &[]).is_ok());
+        assert!(DeletePolicy::Deny.check(&torrents, &confirm(&["aaaa", "bbbb"])).is_err());
+        assert!(DeletePolicy::Confirm.check(&torrents, &confirm(&["AAAA", "bbbb"])).is_ok());
+        assert!(DeletePolicy::Confirm.check(&torrents, &confirm(&["aaaa"])).is_err());
+    }
+}
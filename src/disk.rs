@@ -0,0 +1,59 @@
+use std::io;
+
+use crate::torrent::client::Client;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Space {
+    pub free: u64,
+    pub total: u64,
+}
+
+/// Free and total bytes of the filesystem holding `path`, for the current user.
+#[cfg(unix)]
+pub fn space(path: &str) -> io::Result<Space> {
+    let c_path = std::ffi::CString::new(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let block = stat.f_frsize as u64;
+    Ok(Space {
+        free: stat.f_bavail as u64 * block,
+        total: stat.f_blocks as u64 * block,
+    })
+}
+
+#[cfg(not(unix))]
+pub fn space(_path: &str) -> io::Result<Space> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Free space is only available on unix",
+    ))
+}
+
+/// Free bytes in `path`, asking Transmission first as it may run on another host.
+pub async fn free_space(client: &Client, path: &str) -> Option<u64> {
+    match client.free_space(path).await {
+        Ok(free) => Some(free),
+        Err(_) => space(path).ok().map(|s| s.free),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn space_of_existing_dir() {
+        let space = space("/").unwrap();
+        assert!(space.total > 0);
+        assert!(space.free <= space.total);
+    }
+
+    #[test]
+    fn space_of_missing_dir() {
+        assert!(space("/does/not/exist").is_err());
+        assert!(space("bad\0path").is_err());
+    }
+}
@@ -2,16 +2,14 @@ use std::fmt;
 
 #[derive(Debug)]
 pub enum AppError {
-    #[allow(dead_code)]
     TransmissionError(Box<dyn std::error::Error + Send + Sync>),
     HttpError(reqwest::Error),
+    IoError(std::io::Error),
     EnvironmentError(String),
-    #[allow(dead_code)]
     ParseError(String),
-    #[allow(dead_code)]
     NetworkError(String),
-    #[allow(dead_code)]
     ValidationError(String),
+    InsufficientStorage(String),
 }
 
 impl fmt::Display for AppError {
@@ -19,10 +17,12 @@ impl fmt::Display for AppError {
         match self {
             AppError::TransmissionError(e) => write!(f, "Transmission error: {}", e),
             AppError::HttpError(e) => write!(f, "HTTP error: {}", e),
+            AppError::IoError(e) => write!(f, "IO error: {}", e),
             AppError::EnvironmentError(msg) => write!(f, "Environment error: {}", msg),
             AppError::ParseError(msg) => write!(f, "Parse error: {}", msg),
             AppError::NetworkError(msg) => write!(f, "Network error: {}", msg),
             AppError::ValidationError(msg) => write!(f, "Validation error: {}", msg),
+            AppError::InsufficientStorage(msg) => write!(f, "Insufficient storage: {}", msg),
         }
     }
 }
@@ -38,6 +38,12 @@ impl From<reqwest::Error> for AppError {
     }
 }
 
+impl From<std::io::Error> for AppError {
+    fn from(error: std::io::Error) -> Self {
+        AppError::IoError(error)
+    }
+}
+
 impl From<std::env::VarError> for AppError {
     fn from(error: std::env::VarError) -> Self {
         AppError::EnvironmentError(error.to_string())
@@ -0,0 +1,650 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use chrono::{Datelike, Timelike};
+use futures::stream;
+use serde::{Deserialize, Serialize};
+use tokio::{fs::File, io::AsyncReadExt};
+
+use crate::files::FileStream;
+
+const CHUNK_SIZE: u64 = 64 * 1024;
+const BLOCK: u64 = 512;
+/// Sizes and offsets from here on need the zip64 extensions.
+const ZIP64_LIMIT: u64 = 0xFFFF_FFFF;
+/// Name characters a plain tar header holds.
+const TAR_NAME: usize = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Zip,
+    Tar,
+}
+
+impl Format {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Zip => "zip",
+            Format::Tar => "tar",
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Format::Zip => "application/zip",
+            Format::Tar => "application/x-tar",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Member {
+    path: PathBuf,
+    /// Path in the archive, "/" separated
+    name: String,
+    size: u64,
+    modified: SystemTime,
+    mode: u32,
+}
+
+impl Member {
+    fn zip64(&self) -> bool {
+        self.size >= ZIP64_LIMIT
+    }
+}
+
+#[cfg(unix)]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o777
+}
+
+#[cfg(not(unix))]
+fn mode(_: &fs::Metadata) -> u32 {
+    0o644
+}
+
+// Symlinks are left out so nothing outside the folder ends up in the archive
+fn collect(path: &Path, name: String, members: &mut Vec<Member>) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        let mut entries: Vec<_> = fs::read_dir(path)?.collect::<io::Result<_>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let child = format!("{}/{}", name, entry.file_name().to_string_lossy());
+            collect(&entry.path(), child, members)?;
+        }
+    } else if metadata.is_file() {
+        members.push(Member {
+            path: path.to_path_buf(),
+            name,
+            size: metadata.len(),
+            modified: metadata.modified().unwrap_or(UNIX_EPOCH),
+            mode: mode(&metadata),
+        });
+    }
+    Ok(())
+}
+
+/// An archive of a file or folder, whose size is known before it is written.
+pub struct Export {
+    format: Format,
+    members: Vec<Member>,
+}
+
+impl Export {
+    /// Archives `root` with its own name as the top folder.
+    pub fn new(root: &Path, format: Format) -> io::Result<Self> {
+        let name = root
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Nothing to export"))?;
+        let mut members = Vec::new();
+        collect(root, name, &mut members)?;
+        Ok(Self { format, members })
+    }
+
+    /// Bytes of the whole archive.
+    pub fn len(&self) -> u64 {
+        match self.format {
+            Format::Tar => {
+                let members: u64 = self
+                    .members
+                    .iter()
+                    .map(|m| tar_header_len(&m.name) + padded(m.size))
+                    .sum();
+                members + 2 * BLOCK
+            }
+            Format::Zip => {
+                let mut offset = 0;
+                let mut directory = 0;
+                for member in &self.members {
+                    directory += central_len(member, offset);
+                    offset += local_len(member) + member.size + descriptor_len(member);
+                }
+                offset + directory + end_len(self.members.len(), offset, directory)
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn stream(self) -> FileStream {
+        let writer = Writer {
+            format: self.format,
+            members: self.members,
+            step: Step::Header(0),
+            file: None,
+            remaining: 0,
+            crc: crc32fast::Hasher::new(),
+            written: 0,
+            entries: Vec::new(),
+        };
+        Box::pin(stream::unfold(writer, |mut writer| async move {
+            let next = writer.next().await;
+            if let Some(Err(_)) = next {
+                writer.step = Step::Done;
+            }
+            next.map(|chunk| (chunk, writer))
+        }))
+    }
+}
+
+/// The value of a `Content-Disposition` header offering `name` as a download.
+pub fn attachment(name: &str) -> String {
+    let plain: String = name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    let encoded: String = name
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect();
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        plain, encoded
+    )
+}
+
+fn padded(size: u64) -> u64 {
+    size.div_ceil(BLOCK) * BLOCK
+}
+
+// Names too long for the header go in a GNU long name entry before it
+fn tar_header_len(name: &str) -> u64 {
+    if name.len() < TAR_NAME {
+        BLOCK
+    } else {
+        2 * BLOCK + padded(name.len() as u64 + 1)
+    }
+}
+
+fn tar_block(name: &str, size: u64, mode: u32, mtime: u64, kind: u8) -> Vec<u8> {
+    let mut block = vec![0u8; BLOCK as usize];
+    let name = name.as_bytes();
+    block[..name.len().min(TAR_NAME)].copy_from_slice(&name[..name.len().min(TAR_NAME)]);
+    let octal = |field: &mut [u8], value: u64| {
+        let text = format!("{:0width$o}", value, width = field.len() - 1);
+        field[..text.len()].copy_from_slice(text.as_bytes());
+    };
+    octal(&mut block[100..108], mode as u64);
+    octal(&mut block[108..116], 0);
+    octal(&mut block[116..124], 0);
+    if size < 0o777_7777_7777 {
+        octal(&mut block[124..136], size);
+    } else {
+        // Base-256 for files of 8 GiB and more
+        block[124] = 0x80;
+        block[128..136].copy_from_slice(&size.to_be_bytes());
+    }
+    octal(&mut block[136..148], mtime);
+    block[156] = kind;
+    block[257..265].copy_from_slice(b"ustar  \0");
+
+    block[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = block.iter().map(|b| *b as u32).sum();
+    let text = format!("{:06o}\0 ", checksum);
+    block[148..156].copy_from_slice(text.as_bytes());
+    block
+}
+
+fn tar_header(member: &Member) -> Vec<u8> {
+    let mtime = member
+        .modified
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let mut header = Vec::new();
+    if member.name.len() >= TAR_NAME {
+        let mut long = member.name.clone().into_bytes();
+        long.push(0);
+        header.extend(tar_block(
+            "././@LongLink",
+            long.len() as u64,
+            0o644,
+            0,
+            b'L',
+        ));
+        long.resize(padded(long.len() as u64) as usize, 0);
+        header.extend(long);
+    }
+    header.extend(tar_block(
+        &member.name,
+        member.size,
+        member.mode,
+        mtime,
+        b'0',
+    ));
+    header
+}
+
+fn local_len(member: &Member) -> u64 {
+    30 + member.name.len() as u64 + if member.zip64() { 20 } else { 0 }
+}
+
+fn descriptor_len(member: &Member) -> u64 {
+    if member.zip64() {
+        24
+    } else {
+        16
+    }
+}
+
+fn central_extra_len(member: &Member, offset: u64) -> u64 {
+    let fields = if member.zip64() { 16 } else { 0 } + if offset >= ZIP64_LIMIT { 8 } else { 0 };
+    if fields > 0 {
+        4 + fields
+    } else {
+        0
+    }
+}
+
+fn central_len(member: &Member, offset: u64) -> u64 {
+    46 + member.name.len() as u64 + central_extra_len(member, offset)
+}
+
+fn needs_zip64_end(count: usize, offset: u64, size: u64) -> bool {
+    count >= 0xFFFF || offset >= ZIP64_LIMIT || size >= ZIP64_LIMIT
+}
+
+fn end_len(count: usize, offset: u64, size: u64) -> u64 {
+    if needs_zip64_end(count, offset, size) {
+        56 + 20 + 22
+    } else {
+        22
+    }
+}
+
+fn dos_time(time: SystemTime) -> (u16, u16) {
+    let time = chrono::DateTime::<chrono::Local>::from(time);
+    if time.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let date = (((time.year() - 1980).min(127) as u16) << 9)
+        | ((time.month() as u16) << 5)
+        | time.day() as u16;
+    let clock =
+        ((time.hour() as u16) << 11) | ((time.minute() as u16) << 5) | (time.second() / 2) as u16;
+    (clock, date)
+}
+
+/// Version 4.5 brings zip64, 2.0 is enough otherwise.
+fn zip_version(zip64: bool) -> u16 {
+    if zip64 {
+        45
+    } else {
+        20
+    }
+}
+
+// Sizes and CRC follow the data in a descriptor; names are UTF-8
+const ZIP_FLAGS: u16 = 0x0008 | 0x0800;
+
+fn local_header(member: &Member) -> Vec<u8> {
+    let (clock, date) = dos_time(member.modified);
+    let zip64 = member.zip64();
+    let mut header = Vec::with_capacity(local_len(member) as usize);
+    header.extend(&0x0403_4b50u32.to_le_bytes());
+    header.extend(&zip_version(zip64).to_le_bytes());
+    header.extend(&ZIP_FLAGS.to_le_bytes());
+    header.extend(&0u16.to_le_bytes());
+    header.extend(&clock.to_le_bytes());
+    header.extend(&date.to_le_bytes());
+    header.extend(&0u32.to_le_bytes());
+    let size = if zip64 { ZIP64_LIMIT as u32 } else { 0 };
+    header.extend(&size.to_le_bytes());
+    header.extend(&size.to_le_bytes());
+    header.extend(&(member.name.len() as u16).to_le_bytes());
+    header.extend(&(if zip64 { 20u16 } else { 0 }).to_le_bytes());
+    header.extend(member.name.as_bytes());
+    if zip64 {
+        header.extend(&1u16.to_le_bytes());
+        header.extend(&16u16.to_le_bytes());
+        header.extend(&[0; 16]);
+    }
+    header
+}
+
+fn descriptor(member: &Member, crc: u32) -> Vec<u8> {
+    let mut descriptor = Vec::with_capacity(descriptor_len(member) as usize);
+    descriptor.extend(&0x0807_4b50u32.to_le_bytes());
+    descriptor.extend(&crc.to_le_bytes());
+    for _ in 0..2 {
+        if member.zip64() {
+            descriptor.extend(&member.size.to_le_bytes());
+        } else {
+            descriptor.extend(&(member.size as u32).to_le_bytes());
+        }
+    }
+    descriptor
+}
+
+fn central_header(member: &Member, crc: u32, offset: u64) -> Vec<u8> {
+    let (clock, date) = dos_time(member.modified);
+    let zip64 = member.zip64();
+    let extra = central_extra_len(member, offset);
+    let mut header = Vec::with_capacity(central_len(member, offset) as usize);
+    header.extend(&0x0201_4b50u32.to_le_bytes());
+    // Made on unix, so the mode below is used
+    header.extend(&(0x0300 | zip_version(zip64)).to_le_bytes());
+    header.extend(&zip_version(zip64 || offset >= ZIP64_LIMIT).to_le_bytes());
+    header.extend(&ZIP_FLAGS.to_le_bytes());
+    header.extend(&0u16.to_le_bytes());
+    header.extend(&clock.to_le_bytes());
+    header.extend(&date.to_le_bytes());
+    header.extend(&crc.to_le_bytes());
+    let size = member.size.min(ZIP64_LIMIT) as u32;
+    header.extend(&size.to_le_bytes());
+    header.extend(&size.to_le_bytes());
+    header.extend(&(member.name.len() as u16).to_le_bytes());
+    header.extend(&(extra as u16).to_le_bytes());
+    header.extend(&[0; 6]);
+    header.extend(&((0o100_000 | member.mode) << 16).to_le_bytes());
+    header.extend(&(offset.min(ZIP64_LIMIT) as u32).to_le_bytes());
+    header.extend(member.name.as_bytes());
+    if extra > 0 {
+        header.extend(&1u16.to_le_bytes());
+        header.extend(&((extra - 4) as u16).to_le_bytes());
+        if zip64 {
+            header.extend(&member.size.to_le_bytes());
+            header.extend(&member.size.to_le_bytes());
+        }
+        if offset >= ZIP64_LIMIT {
+            header.extend(&offset.to_le_bytes());
+        }
+    }
+    header
+}
+
+fn end_records(count: usize, offset: u64, size: u64) -> Vec<u8> {
+    let mut end = Vec::new();
+    if needs_zip64_end(count, offset, size) {
+        end.extend(&0x0606_4b50u32.to_le_bytes());
+        end.extend(&44u64.to_le_bytes());
+        end.extend(&(0x0300 | zip_version(true)).to_le_bytes());
+        end.extend(&zip_version(true).to_le_bytes());
+        end.extend(&[0; 8]);
+        end.extend(&(count as u64).to_le_bytes());
+        end.extend(&(count as u64).to_le_bytes());
+        end.extend(&size.to_le_bytes());
+        end.extend(&offset.to_le_bytes());
+
+        end.extend(&0x0706_4b50u32.to_le_bytes());
+        end.extend(&0u32.to_le_bytes());
+        end.extend(&(offset + size).to_le_bytes());
+        end.extend(&1u32.to_le_bytes());
+    }
+    end.extend(&0x0605_4b50u32.to_le_bytes());
+    end.extend(&[0; 4]);
+    for _ in 0..2 {
+        end.extend(&(count.min(0xFFFF) as u16).to_le_bytes());
+    }
+    end.extend(&(size.min(ZIP64_LIMIT) as u32).to_le_bytes());
+    end.extend(&(offset.min(ZIP64_LIMIT) as u32).to_le_bytes());
+    end.extend(&0u16.to_le_bytes());
+    end
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Step {
+    Header(usize),
+    Data(usize),
+    Trailer(usize),
+    Done,
+}
+
+struct Writer {
+    format: Format,
+    members: Vec<Member>,
+    step: Step,
+    file: Option<File>,
+    remaining: u64,
+    crc: crc32fast::Hasher,
+    written: u64,
+    /// CRC and local header offset of the zip entries written
+    entries: Vec<(u32, u64)>,
+}
+
+impl Writer {
+    async fn next(&mut self) -> Option<io::Result<Bytes>> {
+        let chunk = loop {
+            match self.step {
+                Step::Header(index) if index == self.members.len() => {
+                    self.step = Step::Done;
+                    break Ok(self.end());
+                }
+                Step::Header(index) => {
+                    let member = &self.members[index];
+                    self.file = match File::open(&member.path).await {
+                        Ok(file) => Some(file),
+                        Err(e) => break Err(e),
+                    };
+                    self.remaining = member.size;
+                    self.crc = crc32fast::Hasher::new();
+                    self.step = Step::Data(index);
+                    break Ok(match self.format {
+                        Format::Tar => tar_header(member),
+                        Format::Zip => {
+                            self.entries.push((0, self.written));
+                            local_header(member)
+                        }
+                    });
+                }
+                Step::Data(index) if self.remaining == 0 => {
+                    self.file = None;
+                    self.step = Step::Trailer(index);
+                }
+                Step::Data(_) => break self.read().await,
+                Step::Trailer(index) => {
+                    self.step = Step::Header(index + 1);
+                    let member = &self.members[index];
+                    let trailer = match self.format {
+                        Format::Tar => vec![0; (padded(member.size) - member.size) as usize],
+                        Format::Zip => {
+                            let crc = std::mem::take(&mut self.crc).finalize();
+                            self.entries[index].0 = crc;
+                            descriptor(member, crc)
+                        }
+                    };
+                    if !trailer.is_empty() {
+                        break Ok(trailer);
+                    }
+                }
+                Step::Done => return None,
+            }
+        };
+        Some(chunk.map(|chunk| {
+            self.written += chunk.len() as u64;
+            Bytes::from(chunk)
+        }))
+    }
+
+    async fn read(&mut self) -> io::Result<Vec<u8>> {
+        let file = self.file.as_mut().unwrap();
+        let mut buffer = vec![0; CHUNK_SIZE.min(self.remaining) as usize];
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "A file got shorter while it was exported",
+            ));
+        }
+        buffer.truncate(read);
+        self.crc.update(&buffer);
+        self.remaining -= read as u64;
+        Ok(buffer)
+    }
+
+    fn end(&self) -> Vec<u8> {
+        match self.format {
+            Format::Tar => vec![0; 2 * BLOCK as usize],
+            Format::Zip => {
+                let mut directory = Vec::new();
+                for (member, (crc, offset)) in self.members.iter().zip(&self.entries) {
+                    directory.extend(central_header(member, *crc, *offset));
+                }
+                let size = directory.len() as u64;
+                directory.extend(end_records(self.members.len(), self.written, size));
+                directory
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+    use futures::StreamExt;
+
+    fn album(dir: &Path) -> PathBuf {
+        let album = dir.join("Album");
+        fs::create_dir_all(album.join("Scans")).unwrap();
+        fs::write(album.join("01 - Intro.flac"), vec![7u8; 1000]).unwrap();
+        fs::write(album.join("Scans/cover.jpg"), vec![1u8; 200_000]).unwrap();
+        fs::write(album.join(format!("{}.txt", "n".repeat(120))), "notes").unwrap();
+        album
+    }
+
+    async fn collect(export: Export) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut stream = export.stream();
+        while let Some(chunk) = stream.next().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        bytes
+    }
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([bytes[at], bytes[at + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+    }
+
+    #[tokio::test]
+    async fn zip_of_a_folder() {
+        let dir = scratch("zip");
+        let export = Export::new(&album(&dir), Format::Zip).unwrap();
+        let len = export.len();
+        let bytes = collect(export).await;
+        assert_eq!(bytes.len() as u64, len);
+
+        // The end record points at a central directory of three entries
+        let end = &bytes[bytes.len() - 22..];
+        assert_eq!(u32_at(end, 0), 0x0605_4b50);
+        assert_eq!(u16_at(end, 10), 3);
+        let directory = u32_at(end, 16) as usize;
+        assert_eq!(u32_at(&bytes, directory), 0x0201_4b50);
+        let name_len = u16_at(&bytes, directory + 28) as usize;
+        assert_eq!(
+            &bytes[directory + 46..directory + 46 + name_len],
+            b"Album/01 - Intro.flac"
+        );
+        assert_eq!(
+            u32_at(&bytes, directory + 16),
+            crc32fast::hash(&[7u8; 1000])
+        );
+        assert_eq!(u32_at(&bytes, directory + 20), 1000);
+
+        // Stored data follows the first local header
+        assert_eq!(u32_at(&bytes, 0), 0x0403_4b50);
+        let data = 30 + name_len;
+        assert_eq!(&bytes[data..data + 1000], &[7u8; 1000][..]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn tar_of_a_folder() {
+        let dir = scratch("tar");
+        let export = Export::new(&album(&dir), Format::Tar).unwrap();
+        let len = export.len();
+        let bytes = collect(export).await;
+        assert_eq!(bytes.len() as u64, len);
+        assert_eq!(bytes.len() % 512, 0);
+
+        assert_eq!(&bytes[..21], b"Album/01 - Intro.flac");
+        assert_eq!(&bytes[124..135], b"00000001750");
+        assert_eq!(&bytes[257..265], b"ustar  \0");
+        // The checksum counts its own field as spaces
+        let mut header = bytes[..512].to_vec();
+        header[148..156].copy_from_slice(b"        ");
+        let sum: u32 = header.iter().map(|b| *b as u32).sum();
+        assert_eq!(&bytes[148..154], format!("{:06o}", sum).as_bytes());
+        assert_eq!(&bytes[512..1512], &[7u8; 1000][..]);
+
+        // The long name comes in its own entry, after the scan as names sort bytewise
+        let long = 1024 + 512 + 512 + 200_192;
+        assert_eq!(&bytes[long..long + 13], b"././@LongLink");
+        assert_eq!(bytes[long + 156], b'L');
+        assert!(bytes[long + 512..].starts_with(format!("Album/{}", "n".repeat(120)).as_bytes()));
+        assert!(bytes[bytes.len() - 1024..].iter().all(|b| *b == 0));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn download_names() {
+        assert_eq!(
+            attachment("Some \"Album\" é.zip"),
+            "attachment; filename=\"Some _Album_ _.zip\"; filename*=UTF-8''Some%20%22Album%22%20%C3%A9.zip"
+        );
+    }
+
+    #[test]
+    fn zip64_when_needed() {
+        let member = Member {
+            path: PathBuf::new(),
+            name: "big.mkv".to_string(),
+            size: 5 << 30,
+            modified: UNIX_EPOCH,
+            mode: 0o644,
+        };
+        assert!(member.zip64());
+        assert_eq!(local_header(&member).len() as u64, local_len(&member));
+        assert_eq!(descriptor(&member, 1).len() as u64, descriptor_len(&member));
+        let far = 6 << 30;
+        assert_eq!(
+            central_header(&member, 1, far).len() as u64,
+            central_len(&member, far)
+        );
+        assert_eq!(central_len(&member, far), 46 + 7 + 28);
+        assert_eq!(end_records(1, far, 81).len() as u64, end_len(1, far, 81));
+        assert_eq!(end_len(1, 100, 81), 22);
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    fs, io,
+    io::SeekFrom,
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+};
+
+use bytes::Bytes;
+use futures::{stream, Stream};
+use serde::Serialize;
+use tokio::{fs::File, io::AsyncReadExt};
+
+/// Bytes read from disk per chunk of a streamed file.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub type FileStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>>>>;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub name: String,
+    /// What to pass back to list or stream this entry
+    pub path: String,
+    pub dir: bool,
+    pub size: u64,
+    pub modified: Option<String>,
+    pub mime: Option<String>,
+}
+
+pub fn mime_type(path: &Path) -> String {
+    mime_guess::from_path(path)
+        .first_or_octet_stream()
+        .essence_str()
+        .to_string()
+}
+
+pub fn entry(path: &Path, shown: &Path) -> io::Result<Entry> {
+    let metadata = fs::metadata(path)?;
+    Ok(Entry {
+        name: path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        path: shown.to_string_lossy().into_owned(),
+        dir: metadata.is_dir(),
+        size: metadata.len(),
+        modified: metadata
+            .modified()
+            .ok()
+            .map(|t| chrono::DateTime::<chrono::Local>::from(t).to_rfc3339()),
+        mime: Some(path)
+            .filter(|_| !metadata.is_dir())
+            .map(mime_type),
+    })
+}
+
+/// The entries of `dir`, folders first, or the file itself. `shown` is how the client
+/// addresses `path`, the entries' paths are built on it. Hidden files are left out.
+pub fn list(path: &Path, shown: &Path) -> io::Result<Vec<Entry>> {
+    if !path.is_dir() {
+        return Ok(vec![entry(path, shown)?]);
+    }
+    let mut entries = Vec::new();
+    for dir_entry in fs::read_dir(path)? {
+        let dir_entry = dir_entry?;
+        let name = dir_entry.file_name();
+        if name.to_string_lossy().starts_with('.') {
+            continue;
+        }
+        // Broken links and files removed meanwhile are skipped
+        if let Ok(entry) = entry(&dir_entry.path(), &shown.join(&name)) {
+            entries.push(entry);
+        }
+    }
+    entries.sort_by(|a, b| {
+        b.dir
+            .cmp(&a.dir)
+            .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()))
+    });
+    Ok(entries)
+}
+
+/// `relative` below `base`, None when it would leave it through `..`, an absolute path
+/// or a symlink pointing outside.
+pub fn resolve(base: &Path, relative: &str) -> Option<PathBuf> {
+    let relative = Path::new(relative.trim_start_matches('/'));
+    if relative
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return None;
+    }
+    let base = base.canonicalize().ok()?;
+    let path = base.join(relative).canonicalize().ok()?;
+    Some(path).filter(|p| p.starts_with(&base))
+}
+
+/// Resolves an absolute path that must lie within one of `roots`, following symlinks.
+pub fn resolve_in_roots(path: &str, roots: &[String]) -> Option<PathBuf> {
+    roots.iter().find_map(|root| {
+        let relative = Path::new(path).strip_prefix(root).ok()?;
+        resolve(Path::new(root), relative.to_str()?)
+    })
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Range {
+    /// No usable range asked for, the whole file is sent
+    Full,
+    /// First and last byte, both included
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Reads a `Range` header for a file of `len` bytes. Only single byte ranges are
+/// honoured, anything else gets the whole file as HTTP allows.
+pub fn parse_range(header: Option<&str>, len: u64) -> Range {
+    let spec = match header.and_then(|h| h.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Range::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(parts) => parts,
+        None => return Range::Full,
+    };
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // "bytes=-500" is the last 500 bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 || len == 0 {
+                return Range::Unsatisfiable;
+            }
+            (len.saturating_sub(suffix), len - 1)
+        }
+        (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        _ => return Range::Full,
+    };
+    if start >= len {
+        Range::Unsatisfiable
+    } else {
+        Range::Partial(start, end)
+    }
+}
+
+/// Streams `len` bytes of a file starting at `start`.
+pub async fn read_range(path: &Path, start: u64, len: u64) -> io::Result<FileStream> {
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+    Ok(Box::pin(stream::unfold(
+        (file, len),
+        |(mut file, remaining)| async move {
+            if remaining == 0 {
+                return None;
+            }
+            let mut buffer = vec![0; CHUNK_SIZE.min(remaining as usize)];
+            match file.read(&mut buffer).await {
+                Ok(0) => None,
+                Ok(read) => {
+                    buffer.truncate(read);
+                    Some((Ok(Bytes::from(buffer)), (file, remaining - read as u64)))
+                }
+                Err(e) => Some((Err(e), (file, 0))),
+            }
+        },
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+    use futures::StreamExt;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range(None, 100), Range::Full);
+        assert_eq!(parse_range(Some("bytes=0-9"), 100), Range::Partial(0, 9));
+        assert_eq!(parse_range(Some("bytes=90-"), 100), Range::Partial(90, 99));
+        assert_eq!(parse_range(Some("bytes=-10"), 100), Range::Partial(90, 99));
+        assert_eq!(parse_range(Some("bytes=-500"), 100), Range::Partial(0, 99));
+        assert_eq!(parse_range(Some("bytes=50-500"), 100), Range::Partial(50, 99));
+        assert_eq!(parse_range(Some("bytes=100-"), 100), Range::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=-0"), 100), Range::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=0-1,5-6"), 100), Range::Full);
+        assert_eq!(parse_range(Some("bytes=9-1"), 100), Range::Full);
+        assert_eq!(parse_range(Some("items=0-1"), 100), Range::Full);
+    }
+
+    #[test]
+    fn traversal_is_refused() {
+        let dir = scratch("resolve");
+        let base = dir.join("Torrent");
+        fs::create_dir_all(base.join("Subs")).unwrap();
+        fs::write(base.join("movie.mkv"), "video").unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+
+        assert_eq!(resolve(&base, ""), Some(base.clone()));
+        assert_eq!(resolve(&base, "movie.mkv"), Some(base.join("movie.mkv")));
+        assert_eq!(resolve(&base, "/Subs"), Some(base.join("Subs")));
+        assert_eq!(resolve(&base, "../secret.txt"), None);
+        assert_eq!(resolve(&base, "Subs/../../secret.txt"), None);
+        assert_eq!(resolve(&base, "missing.mkv"), None);
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.join("secret.txt"), base.join("link")).unwrap();
+            assert_eq!(resolve(&base, "link"), None);
+        }
+
+        let roots = vec![base.to_string_lossy().into_owned()];
+        let movie = base.join("movie.mkv");
+        assert_eq!(resolve_in_roots(movie.to_str().unwrap(), &roots), Some(movie));
+        let secret = dir.join("secret.txt");
+        assert_eq!(resolve_in_roots(secret.to_str().unwrap(), &roots), None);
+        let escape = format!("{}/../secret.txt", base.display());
+        assert_eq!(resolve_in_roots(&escape, &roots), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lists_folders_first() {
+        let dir = scratch("list");
+        fs::create_dir_all(dir.join("Subs")).unwrap();
+        fs::write(dir.join("b.mkv"), "video").unwrap();
+        fs::write(dir.join("A.srt"), "subs").unwrap();
+        fs::write(dir.join(".hidden"), "").unwrap();
+
+        let entries = list(&dir, Path::new("Show")).unwrap();
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["Subs", "A.srt", "b.mkv"]);
+        assert!(entries[0].dir);
+        assert_eq!(entries[0].mime, None);
+        assert_eq!(entries[2].path, "Show/b.mkv");
+        assert_eq!(entries[2].size, 5);
+        assert_eq!(entries[2].mime.as_deref(), Some("video/x-matroska"));
+
+        let single = list(&dir.join("b.mkv"), Path::new("b.mkv")).unwrap();
+        assert_eq!(single.len(), 1);
+        assert!(!single[0].dir);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn streams_ranges() {
+        let dir = scratch("stream");
+        let path = dir.join("data.bin");
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &data).unwrap();
+
+        let mut read = Vec::new();
+        let mut stream = read_range(&path, 1000, 150_000).await.unwrap();
+        while let Some(chunk) = stream.next().await {
+            read.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(read, &data[1000..151_000]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,10 +1,43 @@
-use crate::torrent::{client::Client, search::Searcher};
+use crate::{
+    config::ServerConfig,
+    disk,
+    error::{AppError, AppResult},
+    export::{self, Export, Format},
+    files::{self, FileStream, Range},
+    library::{self, Library},
+    paths::within_roots,
+    torrent::{
+        batch::{self, ActionResult, BatchAction, Target},
+        category,
+        client::{AddOptions, AddedTorrent, Client, Torrent, TorrentDetail},
+        events::EventBus,
+        extract::Extraction,
+        feed::Feed,
+        guard::{DiskGuard, SizeHint},
+        metainfo::Metainfo,
+        pipeline::{Job, Pipeline},
+        policy::PolicyEngine,
+        release::MediaKind,
+        search::{SearchResult, Searcher},
+        schedule::{Schedule, Scheduler},
+        session::{QueueMove, QueueSettings, SessionSettings, SessionStats},
+        stream,
+    },
+};
+use actix_http::{body::SizedStream, ws};
+use actix_multipart::Multipart;
 use actix_web::{
-    delete, get, post,
-    web::{Data, Json, Path, Query},
-    HttpResponse, Responder,
+    delete,
+    dev::{HttpResponseBuilder, RequestHead},
+    get,
+    http::header::{ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_RANGE, CONTENT_TYPE, RANGE},
+    patch, post, put,
+    web::{Data, Json, Path, Payload, Query},
+    HttpRequest, HttpResponse, Responder, ResponseError,
 };
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
+use std::path::{Path as StdPath, PathBuf};
 use transmission_rpc::types::{Nothing, Result, RpcResponse};
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -12,17 +45,199 @@ struct QueryParams {
     terms: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+struct ListParams {
+    category: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BatchRequest {
+    action: BatchAction,
+    targets: Vec<Target>,
+    /// Infohashes confirming remove-with-data
+    #[serde(default)]
+    confirm: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct OrganizeParams {
+    to: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct FileParams {
+    #[serde(default)]
+    path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ExportParams {
+    #[serde(default)]
+    format: Format,
+    /// Folder or file below the torrent's folder, all of it by default
+    #[serde(default)]
+    path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LibraryParams {
+    #[serde(default)]
+    q: String,
+    kind: Option<MediaKind>,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, Debug)]
+struct LibraryPage {
+    total: usize,
+    items: Vec<library::Item>,
+    #[serde(flatten)]
+    status: library::Status,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct KindParams {
+    kind: Option<MediaKind>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct MatchParams {
+    name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SequentialObj {
+    enabled: bool,
+    /// File to raise the priority of, as in `/torrent/files/{id}`
+    path: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct SequentialState {
+    sequential: bool,
+    priority_file: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RemoveParams {
+    #[serde(default)]
+    delete_data: bool,
+    confirm: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LocationObj {
+    location: String,
+    #[serde(default = "default_move", rename = "move")]
+    move_data: bool,
+}
+
+fn default_move() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct QueueObj {
+    #[serde(rename = "move")]
+    direction: QueueMove,
+}
+
+#[derive(Serialize, Debug)]
+struct DiskStats {
+    path: String,
+    free_bytes: Option<u64>,
+    total_bytes: Option<u64>,
+    error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct Stats {
+    #[serde(flatten)]
+    session: SessionStats,
+    disks: Vec<DiskStats>,
+}
+
+#[derive(Serialize, Debug)]
+struct DetailState {
+    #[serde(flatten)]
+    detail: TorrentDetail,
+    post_processing: Option<Job>,
+    extraction: Option<Vec<Extraction>>,
+}
+
+#[derive(Serialize, Debug)]
+struct ScheduleState {
+    #[serde(flatten)]
+    schedule: Schedule,
+    active_profile: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RunParams {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct SuggestedResult {
+    #[serde(flatten)]
+    pub result: SearchResult,
+    pub category: Option<String>,
+    /// What of it is already in the library
+    pub in_library: Vec<library::Item>,
+}
+
+const MAX_TORRENT_FILE_SIZE: usize = 10 * 1024 * 1024;
+
 #[derive(Serialize, Deserialize, Debug)]
 struct TorrentObj {
-    pub url: String,
+    pub url: Option<String>,
+    pub metainfo: Option<String>,
+    /// Size from the search result, checked against the free space
+    pub size: Option<SizeHint>,
+    #[serde(flatten)]
+    pub options: AddOptions,
+}
+
+#[derive(Serialize, Debug)]
+struct UploadedTorrent {
+    pub added: AddedTorrent,
+    pub torrent: Metainfo,
+}
+
+struct Upload {
+    bytes: Vec<u8>,
+    options: AddOptions,
+}
+
+#[derive(Serialize, Debug)]
+struct TorrentPreview {
+    pub magnet: String,
+    pub torrent: Metainfo,
 }
 
 #[get("/torrent/search")]
-async fn search_torrents(Query(params): Query<QueryParams>, searcher: Data<Searcher>) -> impl Responder {
+async fn search_torrents(
+    Query(params): Query<QueryParams>,
+    searcher: Data<Searcher>,
+    config: Data<ServerConfig>,
+    library: Data<Library>,
+) -> impl Responder {
     info!("Searching for {}!", params.terms);
 
     if params.terms.len() > 1 {
-        let search_result = searcher.search(params.terms.trim());
+        let search_result: Vec<SuggestedResult> = searcher
+            .search(params.terms.trim())
+            .into_iter()
+            .map(|result| SuggestedResult {
+                category: category::suggest(&config.categories, &result.name)
+                    .map(|c| c.name.clone()),
+                in_library: library.matches(&result.name),
+                result,
+            })
+            .collect();
         HttpResponse::Ok().json(search_result)
     } else {
         HttpResponse::InternalServerError().body("Length > 2")
@@ -30,9 +245,26 @@ async fn search_torrents(Query(params): Query<QueryParams>, searcher: Data<Searc
 }
 
 #[get("/torrent/list")]
-async fn list_torrents(client: Data<Client>) -> impl Responder {
+async fn list_torrents(
+    Query(params): Query<ListParams>,
+    client: Data<Client>,
+    config: Data<ServerConfig>,
+) -> impl Responder {
     info!("Listing torrents!");
-    let torrent_list = client.list_torrents().await;
+
+    let filter = match params.category.as_deref() {
+        Some(name) => match category::find(&config.categories, name) {
+            Ok(c) => Some(c),
+            Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+        },
+        None => None,
+    };
+
+    let torrent_list = categorized_torrents(&client, &config).await.map(|list| {
+        list.into_iter()
+            .filter(|t| filter.is_none_or(|c| c.matches(t)))
+            .collect::<Vec<_>>()
+    });
 
     match torrent_list {
         Ok(list) => HttpResponse::Ok().json(list),
@@ -40,15 +272,167 @@ async fn list_torrents(client: Data<Client>) -> impl Responder {
     }
 }
 
+async fn categorized_torrents(client: &Client, config: &ServerConfig) -> Result<Vec<Torrent>> {
+    let mut list = client.list_torrents().await?;
+    for t in list.iter_mut() {
+        t.category = category::categorize(&config.categories, t).map(|c| c.name.clone());
+    }
+    Ok(list)
+}
+
+#[get("/torrent/feed")]
+async fn torrent_feed(
+    req: HttpRequest,
+    payload: Payload,
+    feed: Data<Feed>,
+    events: Data<EventBus>,
+    client: Data<Client>,
+) -> HttpResponse {
+    let mut response = match ws::handshake(req.head()) {
+        Ok(response) => response,
+        Err(e) => return e.error_response(),
+    };
+    info!("Torrent feed connected!");
+
+    let (snapshot, updates) = feed.subscribe(&events, &client).await;
+    response.streaming(feed.into_inner().socket(snapshot, updates, payload))
+}
+
+#[get("/events")]
+async fn recent_events(events: Data<EventBus>) -> impl Responder {
+    HttpResponse::Ok().json(events.recent())
+}
+
+#[post("/torrent/add", guard = "is_multipart")]
+async fn upload_torrent(
+    mut payload: Multipart,
+    client: Data<Client>,
+    config: Data<ServerConfig>,
+    guard: Data<DiskGuard>,
+) -> impl Responder {
+    info!("Uploading torrent file!");
+
+    let mut upload = match read_upload(&mut payload).await {
+        Ok(upload) => upload,
+        Err(e) => {
+            error!("Torrent upload error! {}", e);
+            return HttpResponse::BadRequest().body(e.to_string());
+        }
+    };
+
+    if let Err(e) = resolve_options(&mut upload.options, &config) {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+
+    add_metainfo(upload.bytes, &upload.options, &client, &guard).await
+}
+
 #[post("/torrent/add")]
-async fn add_torrent(torrent: Json<TorrentObj>, client: Data<Client>) -> impl Responder {
-    info!("Adding torrent! {}", torrent.url);
-    let result = client.add_torrent(torrent.url.as_str()).await;
+async fn add_torrent(
+    torrent: Json<TorrentObj>,
+    client: Data<Client>,
+    config: Data<ServerConfig>,
+    guard: Data<DiskGuard>,
+) -> impl Responder {
+    let mut torrent = torrent.into_inner();
+
+    if let Err(e) = resolve_options(&mut torrent.options, &config) {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+
+    if let Some(encoded) = torrent.metainfo {
+        return match decode_metainfo(&encoded) {
+            Ok(bytes) => add_metainfo(bytes, &torrent.options, &client, &guard).await,
+            Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+        };
+    }
+
+    let url = match torrent.url {
+        Some(url) => url,
+        None => return HttpResponse::BadRequest().body("Missing url or metainfo"),
+    };
+
+    let size = torrent.size.as_ref().and_then(SizeHint::bytes);
+    if let Err(e) = check_space(&torrent.options, size, &client, &guard).await {
+        return e;
+    }
+
+    info!("Adding torrent! {} {:?}", url, torrent.options);
+    let result = client.add_torrent(url.as_str(), &torrent.options).await;
+
+    match result {
+        Ok(added) => {
+            info!("Torrent added! {:?}", added);
+            HttpResponse::Ok().json(added)
+        }
+        Err(e) => {
+            error!("Torrent add error! {}", e);
+            HttpResponse::InternalServerError().body(e.to_string())
+        }
+    }
+}
+
+fn resolve_options(options: &mut AddOptions, config: &ServerConfig) -> AppResult<()> {
+    if let Some(name) = options.category.clone() {
+        category::find(&config.categories, &name)?.apply(options);
+    }
+    options.validate(&config.allowed_dirs())
+}
+
+async fn check_space(
+    options: &AddOptions,
+    size: Option<u64>,
+    client: &Client,
+    guard: &DiskGuard,
+) -> std::result::Result<(), HttpResponse> {
+    let dir = options.download_dir.as_deref();
+    guard.check_add(client, dir, size).await.map_err(|e| {
+        warn!("Torrent refused! {}", e);
+        HttpResponse::InsufficientStorage().body(e.to_string())
+    })
+}
+
+async fn add_metainfo(
+    bytes: Vec<u8>,
+    options: &AddOptions,
+    client: &Client,
+    guard: &DiskGuard,
+) -> HttpResponse {
+    let metainfo = match Metainfo::from_bytes(&bytes) {
+        Ok(m) => m,
+        Err(e) => {
+            error!("Invalid torrent file! {}", e);
+            return HttpResponse::BadRequest().body(e.to_string());
+        }
+    };
+
+    match client.has_torrent(&metainfo.info_hash).await {
+        Ok(true) => {
+            info!("Torrent already added! {}", metainfo.info_hash);
+            return HttpResponse::Conflict()
+                .body(format!("Torrent already added: {}", metainfo.info_hash));
+        }
+        Ok(false) => {}
+        Err(e) => warn!("Could not check for duplicate torrent! {}", e),
+    }
+
+    if let Err(e) = check_space(options, Some(metainfo.total_size), client, guard).await {
+        return e;
+    }
+
+    info!(
+        "Adding torrent file! {} ({}) {:?}",
+        metainfo.name, metainfo.info_hash, options
+    );
+    let result = client.add_metainfo(&bytes, options).await;
 
     match result {
-        Ok(r) => {
-            info!("Torrent added! {:?}", r);
-            HttpResponse::Ok().body(r.result)
+        Ok(added) => {
+            info!("Torrent added! {:?}", added);
+            HttpResponse::Ok().json(UploadedTorrent {
+                added,
+                torrent: metainfo,
+            })
         }
         Err(e) => {
             error!("Torrent add error! {}", e);
@@ -57,6 +441,86 @@ async fn add_torrent(torrent: Json<TorrentObj>, client: Data<Client>) -> impl Re
     }
 }
 
+#[post("/torrent/preview", guard = "is_multipart")]
+async fn upload_preview(mut payload: Multipart) -> impl Responder {
+    match read_upload(&mut payload).await {
+        Ok(upload) => preview(&upload.bytes),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+#[post("/torrent/preview")]
+async fn preview_torrent(torrent: Json<TorrentObj>) -> impl Responder {
+    let encoded = match torrent.metainfo.as_deref() {
+        Some(encoded) => encoded,
+        None => return HttpResponse::BadRequest().body("Missing metainfo"),
+    };
+
+    match decode_metainfo(encoded) {
+        Ok(bytes) => preview(&bytes),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+fn preview(bytes: &[u8]) -> HttpResponse {
+    match Metainfo::from_bytes(bytes) {
+        Ok(torrent) => HttpResponse::Ok().json(TorrentPreview {
+            magnet: torrent.magnet(),
+            torrent,
+        }),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+fn decode_metainfo(encoded: &str) -> AppResult<Vec<u8>> {
+    base64::decode(encoded.trim())
+        .map_err(|e| AppError::ValidationError(format!("Invalid metainfo encoding: {}", e)))
+}
+
+async fn read_upload(payload: &mut Multipart) -> AppResult<Upload> {
+    let mut file = None;
+    let mut options = AddOptions::default();
+
+    while let Some(field) = payload.next().await {
+        let mut field = field.map_err(|e| AppError::ValidationError(e.to_string()))?;
+        let disposition = field.content_disposition();
+        let is_file = disposition
+            .as_ref()
+            .is_some_and(|cd| cd.get_filename().is_some());
+        let is_options = disposition
+            .as_ref()
+            .is_some_and(|cd| cd.get_name() == Some("options"));
+
+        let mut bytes = Vec::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk.map_err(|e| AppError::ValidationError(e.to_string()))?;
+            if bytes.len() + chunk.len() > MAX_TORRENT_FILE_SIZE {
+                return Err(AppError::ValidationError("Torrent file too large".into()));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+
+        if is_file && file.is_none() {
+            file = Some(bytes);
+        } else if is_options {
+            options = serde_json::from_slice(&bytes)
+                .map_err(|e| AppError::ValidationError(format!("Invalid options: {}", e)))?;
+        }
+    }
+
+    match file {
+        Some(bytes) => Ok(Upload { bytes, options }),
+        None => Err(AppError::ValidationError("No torrent file uploaded".into())),
+    }
+}
+
+fn is_multipart(head: &RequestHead) -> bool {
+    head.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("multipart/form-data"))
+}
+
 #[post("/torrent/resume/{id}")]
 async fn resume_torrent(Path(id): Path<i64>, client: Data<Client>) -> impl Responder {
     info!("Resuming torrent! {}", id);
@@ -73,15 +537,645 @@ async fn pause_torrent(Path(id): Path<i64>, client: Data<Client>) -> impl Respon
     process(result)
 }
 
+/// Where the files of a torrent are, its folder or the single file.
+async fn torrent_base(client: &Client, id: i64) -> std::result::Result<PathBuf, HttpResponse> {
+    match client.torrent_detail(id).await {
+        Ok(Some(detail)) => {
+            let torrent = detail.torrent;
+            Ok(PathBuf::from(torrent.download_dir).join(torrent.name))
+        }
+        Ok(None) => Err(HttpResponse::NotFound().body(format!("No torrent with id {}", id))),
+        Err(e) => Err(HttpResponse::InternalServerError().body(e.to_string())),
+    }
+}
+
+#[get("/torrent/files/{id}")]
+async fn torrent_files(
+    Path(id): Path<i64>,
+    Query(params): Query<FileParams>,
+    client: Data<Client>,
+) -> impl Responder {
+    let base = match torrent_base(&client, id).await {
+        Ok(base) => base,
+        Err(response) => return response,
+    };
+    match files::resolve(&base, &params.path) {
+        Some(path) => list_files(&path, params.path.trim_start_matches('/')),
+        None => HttpResponse::NotFound().body(format!("No file {}", params.path)),
+    }
+}
+
+#[get("/torrent/stream/{id}")]
+async fn stream_torrent_file(
+    req: HttpRequest,
+    Path(id): Path<i64>,
+    Query(params): Query<FileParams>,
+    client: Data<Client>,
+) -> HttpResponse {
+    let map = match client.piece_map(id).await {
+        Ok(Some(map)) => map,
+        Ok(None) => return HttpResponse::NotFound().body(format!("No torrent with id {}", id)),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    let index = match map.file(&params.path) {
+        Some(index) => index,
+        None => return HttpResponse::NotFound().body(format!("No file {}", params.path)),
+    };
+
+    if map.files[index].complete() {
+        let base = PathBuf::from(&map.download_dir).join(&map.name);
+        return match files::resolve(&base, &params.path) {
+            Some(path) => send_file(&req, &path).await,
+            None => HttpResponse::NotFound().body(format!("No file {}", params.path)),
+        };
+    }
+
+    // Still downloading, the stream waits for the pieces it gets to
+    let file = &map.files[index];
+    let (response, start, end) = match ranged(&req, file.length) {
+        Ok(ranged) => ranged,
+        Err(response) => return response,
+    };
+    let mime = files::mime_type(StdPath::new(&file.name));
+    let body = stream::download_stream(client.into_inner(), id, map, index, start, end - start);
+    sized(response, mime, end - start, body)
+}
+
+#[get("/torrent/export/{id}")]
+async fn export_torrent(
+    Path(id): Path<i64>,
+    Query(params): Query<ExportParams>,
+    client: Data<Client>,
+) -> HttpResponse {
+    let map = match client.piece_map(id).await {
+        Ok(Some(map)) => map,
+        Ok(None) => return HttpResponse::NotFound().body(format!("No torrent with id {}", id)),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    if !map.files.iter().all(|file| file.complete()) {
+        return HttpResponse::Conflict().body("The torrent is still downloading");
+    }
+    let base = PathBuf::from(&map.download_dir).join(&map.name);
+    let path = match files::resolve(&base, &params.path) {
+        Some(path) => path,
+        None => return HttpResponse::NotFound().body(format!("No file {}", params.path)),
+    };
+    info!("Exporting torrent {} as {:?}! {}", id, params.format, path.display());
+
+    let archive = match Export::new(&path, params.format) {
+        Ok(archive) if archive.is_empty() => {
+            return HttpResponse::NotFound().body(format!("No files in {}", params.path))
+        }
+        Ok(archive) => archive,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    let name = format!(
+        "{}.{}",
+        path.file_name().unwrap_or_default().to_string_lossy(),
+        params.format.extension()
+    );
+    HttpResponse::Ok()
+        .content_type(params.format.mime())
+        .header(CONTENT_DISPOSITION, export::attachment(&name))
+        .body(SizedStream::new(
+            archive.len(),
+            archive
+                .stream()
+                .map(|chunk| chunk.map_err(actix_web::Error::from)),
+        ))
+}
+
+#[post("/torrent/sequential/{id}")]
+async fn set_sequential(
+    Path(id): Path<i64>,
+    body: Json<SequentialObj>,
+    client: Data<Client>,
+) -> impl Responder {
+    info!(
+        "Setting sequential download of torrent {}! {}",
+        id, body.enabled
+    );
+
+    let version = match client.rpc_version().await {
+        Ok(version) => version,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    let sequential = version >= stream::SEQUENTIAL_RPC_VERSION;
+    if sequential {
+        if let Err(e) = client.set_sequential(id, body.enabled).await {
+            return HttpResponse::InternalServerError().body(e.to_string());
+        }
+    } else if body.path.is_none() {
+        return HttpResponse::NotImplemented().body(format!(
+            "Sequential download needs Transmission 4.1 or later, RPC version {} found",
+            version
+        ));
+    }
+
+    // Transmission has no piece priorities, the file being watched gets priority instead
+    if let Some(path) = &body.path {
+        let index = match client.piece_map(id).await {
+            Ok(Some(map)) => map.file(path),
+            Ok(None) => return HttpResponse::NotFound().body(format!("No torrent with id {}", id)),
+            Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+        };
+        let index = match index {
+            Some(index) => index,
+            None => return HttpResponse::NotFound().body(format!("No file {}", path)),
+        };
+        if let Err(e) = client.set_file_priority(id, &[index], body.enabled).await {
+            return HttpResponse::InternalServerError().body(e.to_string());
+        }
+    }
+    HttpResponse::Ok().json(SequentialState {
+        sequential: sequential && body.enabled,
+        priority_file: body.path.clone().filter(|_| body.enabled),
+    })
+}
+
+const LIBRARY_PAGE: usize = 100;
+
+#[get("/library")]
+async fn library_items(
+    Query(params): Query<LibraryParams>,
+    library: Data<Library>,
+) -> impl Responder {
+    let items = library.search(&params.q, params.kind);
+    HttpResponse::Ok().json(LibraryPage {
+        total: items.len(),
+        items: items
+            .into_iter()
+            .skip(params.offset)
+            .take(params.limit.unwrap_or(LIBRARY_PAGE))
+            .collect(),
+        status: library.status(),
+    })
+}
+
+#[get("/library/titles")]
+async fn library_titles(
+    Query(params): Query<KindParams>,
+    library: Data<Library>,
+) -> impl Responder {
+    HttpResponse::Ok().json(library.titles(params.kind))
+}
+
+#[get("/library/match")]
+async fn library_match(
+    Query(params): Query<MatchParams>,
+    library: Data<Library>,
+) -> impl Responder {
+    HttpResponse::Ok().json(library.matches(&params.name))
+}
+
+#[post("/library/rescan")]
+async fn rescan_library(library: Data<Library>) -> impl Responder {
+    info!("Rescanning the library!");
+    let task = library.clone();
+    std::thread::spawn(move || task.scan());
+    HttpResponse::Accepted().json(library.status())
+}
+
+#[get("/library/files")]
+async fn library_files(
+    Query(params): Query<FileParams>,
+    config: Data<ServerConfig>,
+) -> impl Responder {
+    if params.path.is_empty() {
+        let roots: Vec<files::Entry> = config
+            .library_roots
+            .iter()
+            .filter_map(|root| files::entry(StdPath::new(root), StdPath::new(root)).ok())
+            .collect();
+        return HttpResponse::Ok().json(roots);
+    }
+    match files::resolve_in_roots(&params.path, &config.library_roots) {
+        Some(path) => list_files(&path, &params.path),
+        None => HttpResponse::NotFound().body(format!("No file {}", params.path)),
+    }
+}
+
+#[get("/library/stream")]
+async fn stream_library_file(
+    req: HttpRequest,
+    Query(params): Query<FileParams>,
+    config: Data<ServerConfig>,
+) -> HttpResponse {
+    match files::resolve_in_roots(&params.path, &config.library_roots) {
+        Some(path) => send_file(&req, &path).await,
+        None => HttpResponse::NotFound().body(format!("No file {}", params.path)),
+    }
+}
+
+fn list_files(path: &StdPath, shown: &str) -> HttpResponse {
+    match files::list(path, StdPath::new(shown)) {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Sends a file, or the byte range the client asked for so players can seek.
+async fn send_file(req: &HttpRequest, path: &StdPath) -> HttpResponse {
+    let len = match std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        _ => return HttpResponse::NotFound().body(format!("No file {}", path.display())),
+    };
+    let (response, start, end) = match ranged(req, len) {
+        Ok(ranged) => ranged,
+        Err(response) => return response,
+    };
+
+    match files::read_range(path, start, end - start).await {
+        Ok(stream) => sized(response, files::mime_type(path), end - start, stream),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// The response for the `Range` of a request to `len` bytes, with the bytes to send.
+fn ranged(
+    req: &HttpRequest,
+    len: u64,
+) -> std::result::Result<(HttpResponseBuilder, u64, u64), HttpResponse> {
+    let header = req.headers().get(RANGE).and_then(|v| v.to_str().ok());
+    match files::parse_range(header, len) {
+        Range::Full => Ok((HttpResponse::Ok(), 0, len)),
+        Range::Partial(start, last) => {
+            let mut response = HttpResponse::PartialContent();
+            response.header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, last, len));
+            Ok((response, start, last + 1))
+        }
+        Range::Unsatisfiable => Err(HttpResponse::RangeNotSatisfiable()
+            .header(CONTENT_RANGE, format!("bytes */{}", len))
+            .finish()),
+    }
+}
+
+fn sized(
+    mut response: HttpResponseBuilder,
+    mime: String,
+    len: u64,
+    body: FileStream,
+) -> HttpResponse {
+    response
+        .header(ACCEPT_RANGES, "bytes")
+        .content_type(mime)
+        .body(SizedStream::new(
+            len,
+            body.map(|chunk| chunk.map_err(actix_web::Error::from)),
+        ))
+}
+
+#[get("/torrent/detail/{id}")]
+async fn torrent_detail(
+    Path(id): Path<i64>,
+    client: Data<Client>,
+    pipeline: Data<Pipeline>,
+) -> impl Responder {
+    match client.torrent_detail(id).await {
+        Ok(Some(detail)) => HttpResponse::Ok().json(DetailState {
+            detail,
+            post_processing: pipeline.job(id),
+            extraction: pipeline.extractor().get(id),
+        }),
+        Ok(None) => HttpResponse::NotFound().body(format!("No torrent with id {}", id)),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[get("/pipeline")]
+async fn pipeline_jobs(pipeline: Data<Pipeline>) -> impl Responder {
+    HttpResponse::Ok().json(pipeline.jobs())
+}
+
+#[get("/extractions")]
+async fn extractions(pipeline: Data<Pipeline>) -> impl Responder {
+    HttpResponse::Ok().json(pipeline.extractor().all())
+}
+
+#[post("/torrent/pipeline/{id}/retry")]
+async fn retry_pipeline(
+    Path(id): Path<i64>,
+    pipeline: Data<Pipeline>,
+    client: Data<Client>,
+) -> impl Responder {
+    info!("Retrying post-processing of torrent! {}", id);
+    match pipeline.job(id) {
+        None => return HttpResponse::NotFound().body(format!("No post-processing for {}", id)),
+        Some(job) if job.running() => {
+            return HttpResponse::Conflict().body("Post-processing is still running")
+        }
+        Some(_) => {}
+    }
+    if !pipeline.retry(id) {
+        return HttpResponse::Conflict().body("No failed steps to retry");
+    }
+
+    let (task, task_client) = (pipeline.clone(), client.clone());
+    actix_web::rt::spawn(async move { task.run(&task_client, id).await });
+    HttpResponse::Accepted().json(pipeline.job(id))
+}
+
+#[get("/torrent/organize/{id}")]
+async fn preview_organize(
+    Path(id): Path<i64>,
+    Query(params): Query<OrganizeParams>,
+    client: Data<Client>,
+    pipeline: Data<Pipeline>,
+) -> impl Responder {
+    let torrent = match client.torrent_detail(id).await {
+        Ok(Some(detail)) => detail.torrent,
+        Ok(None) => return HttpResponse::NotFound().body(format!("No torrent with id {}", id)),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    match pipeline.preview(&torrent, params.to).await {
+        Ok(placements) => HttpResponse::Ok().json(placements),
+        Err(e @ AppError::ValidationError(_)) => HttpResponse::BadRequest().body(e.to_string()),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[post("/torrent/verify/{id}")]
+async fn verify_torrent(Path(id): Path<i64>, client: Data<Client>) -> impl Responder {
+    info!("Verifying torrent! {}", id);
+    let result = client.verify_torrents(vec![id]).await;
+
+    process(result)
+}
+
+#[post("/torrent/reannounce/{id}")]
+async fn reannounce_torrent(Path(id): Path<i64>, client: Data<Client>) -> impl Responder {
+    info!("Reannouncing torrent! {}", id);
+    let result = client.reannounce_torrents(vec![id]).await;
+
+    process(result)
+}
+
+#[post("/torrent/queue/{id}")]
+async fn queue_torrent(
+    Path(id): Path<i64>,
+    body: Json<QueueObj>,
+    client: Data<Client>,
+) -> impl Responder {
+    info!("Moving torrent {} in queue! {:?}", id, body.direction);
+
+    match client.queue_move(vec![id], body.direction).await {
+        Ok(_) => HttpResponse::Ok().json("ok"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[get("/session")]
+async fn session_settings(client: Data<Client>) -> impl Responder {
+    match client.session_settings().await {
+        Ok(settings) => HttpResponse::Ok().json(settings),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[patch("/session")]
+async fn update_session_settings(
+    settings: Json<SessionSettings>,
+    client: Data<Client>,
+    config: Data<ServerConfig>,
+) -> impl Responder {
+    info!("Updating session settings! {:?}", settings);
+
+    if let Err(e) = settings.validate(&config.allowed_dirs()) {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+
+    match client.set_session_settings(&settings).await {
+        Ok(_) => HttpResponse::Ok().json("ok"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[get("/stats")]
+async fn stats(client: Data<Client>, config: Data<ServerConfig>) -> impl Responder {
+    let session = match client.session_stats().await {
+        Ok(session) => session,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    let mut dirs = config.allowed_dirs();
+    dirs.sort();
+    dirs.dedup();
+
+    let mut disks = Vec::new();
+    for path in dirs {
+        disks.push(disk_stats(&client, path).await);
+    }
+
+    HttpResponse::Ok().json(Stats { session, disks })
+}
+
+// Transmission knows the free space even when it runs on another host;
+// the total size, and the free space if the daemon cannot tell, come from statvfs.
+async fn disk_stats(client: &Client, path: String) -> DiskStats {
+    let local = disk::space(&path);
+    let remote = client.free_space(&path).await;
+
+    if let Err(e) = &remote {
+        warn!("Transmission free space failed for {}! {}", path, e);
+    }
+
+    let error = match (&remote, &local) {
+        (Err(_), Err(e)) => Some(e.to_string()),
+        _ => None,
+    };
+    let local = local.ok();
+
+    DiskStats {
+        free_bytes: remote.ok().or_else(|| local.map(|s| s.free)),
+        total_bytes: local.map(|s| s.total),
+        error,
+        path,
+    }
+}
+
+#[get("/schedule")]
+async fn get_schedule(scheduler: Data<Scheduler>) -> impl Responder {
+    HttpResponse::Ok().json(ScheduleState {
+        schedule: scheduler.schedule(),
+        active_profile: scheduler.active(),
+    })
+}
+
+#[put("/schedule")]
+async fn update_schedule(
+    schedule: Json<Schedule>,
+    scheduler: Data<Scheduler>,
+    client: Data<Client>,
+) -> impl Responder {
+    info!("Updating speed schedule! {:?}", schedule);
+
+    match scheduler.update(schedule.into_inner()) {
+        Ok(_) => {
+            scheduler.tick(&client).await;
+            HttpResponse::Ok().json(ScheduleState {
+                schedule: scheduler.schedule(),
+                active_profile: scheduler.active(),
+            })
+        }
+        Err(e @ AppError::ValidationError(_)) => HttpResponse::BadRequest().body(e.to_string()),
+        Err(e) => {
+            error!("Could not save schedule! {}", e);
+            HttpResponse::InternalServerError().body(e.to_string())
+        }
+    }
+}
+
+#[get("/policies")]
+async fn get_policies(engine: Data<PolicyEngine>) -> impl Responder {
+    HttpResponse::Ok().json(&engine.config)
+}
+
+#[post("/policies/run")]
+async fn run_policies(
+    Query(params): Query<RunParams>,
+    engine: Data<PolicyEngine>,
+    client: Data<Client>,
+) -> impl Responder {
+    info!("Running seeding policies! dry run: {}", params.dry_run);
+
+    match engine.run(&client, params.dry_run).await {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[get("/policies/audit")]
+async fn policy_audit(engine: Data<PolicyEngine>) -> impl Responder {
+    HttpResponse::Ok().json(engine.audit())
+}
+
+#[get("/session/queue")]
+async fn queue_settings(client: Data<Client>) -> impl Responder {
+    match client.queue_settings().await {
+        Ok(settings) => HttpResponse::Ok().json(settings),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[patch("/session/queue")]
+async fn update_queue_settings(
+    settings: Json<QueueSettings>,
+    client: Data<Client>,
+) -> impl Responder {
+    info!("Updating queue settings! {:?}", settings);
+
+    if let Err(e) = settings.validate() {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+
+    match client.set_queue_settings(&settings).await {
+        Ok(_) => HttpResponse::Ok().json("ok"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
 #[delete("/torrent/remove/{id}")]
-async fn del_torrent(Path(id): Path<i64>, client: Data<Client>) -> impl Responder {
-    info!("Deleting torrent {}!", id);
-    let result = client.remove_torrent(id).await;
+async fn del_torrent(
+    Path(id): Path<i64>,
+    Query(params): Query<RemoveParams>,
+    client: Data<Client>,
+    config: Data<ServerConfig>,
+) -> impl Responder {
+    info!("Deleting torrent {}! delete data: {}", id, params.delete_data);
+
+    if params.delete_data {
+        let torrent = match client.list_torrents().await {
+            Ok(list) => list.into_iter().find(|t| t.id == id),
+            Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+        };
+        let torrent = match torrent {
+            Some(t) => t,
+            None => return HttpResponse::NotFound().body(format!("No torrent with id {}", id)),
+        };
+        let confirm: Vec<String> = params.confirm.into_iter().collect();
+        if let Err(e) = config.delete_policy.check(&[&torrent], &confirm) {
+            return HttpResponse::Forbidden().body(e.to_string());
+        }
+    }
+
+    let result = client.remove_torrent(id, params.delete_data).await;
+
+    process(result)
+}
+
+#[post("/torrent/actions")]
+async fn batch_action(
+    request: Json<BatchRequest>,
+    client: Data<Client>,
+    config: Data<ServerConfig>,
+) -> impl Responder {
+    let request = request.into_inner();
+    info!("Running {:?} on {:?}!", request.action, request.targets);
+
+    let torrents = match categorized_torrents(&client, &config).await {
+        Ok(list) => list,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    let selection = match batch::select(&torrents, &request.targets) {
+        Ok(selection) => selection,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+
+    if request.action == BatchAction::RemoveWithData {
+        if let Err(e) = config.delete_policy.check(&selection.torrents, &request.confirm) {
+            return HttpResponse::Forbidden().body(e.to_string());
+        }
+    }
+
+    let error = if selection.torrents.is_empty() {
+        None
+    } else {
+        match client.batch_action(request.action, selection.ids()).await {
+            Ok(_) => None,
+            Err(e) => {
+                error!("Batch action error! {}", e);
+                Some(e.to_string())
+            }
+        }
+    };
+
+    let results: Vec<ActionResult> = selection
+        .torrents
+        .iter()
+        .map(|t| ActionResult::done(t, error.clone()))
+        .chain(selection.unmatched.iter().map(ActionResult::unmatched))
+        .collect();
+
+    match error {
+        None => HttpResponse::Ok().json(results),
+        Some(_) => HttpResponse::InternalServerError().json(results),
+    }
+}
+
+#[post("/torrent/location/{id}")]
+async fn set_location(
+    Path(id): Path<i64>,
+    body: Json<LocationObj>,
+    client: Data<Client>,
+    config: Data<ServerConfig>,
+) -> impl Responder {
+    info!(
+        "Setting torrent {} location to {}! move: {}",
+        id, body.location, body.move_data
+    );
+
+    if !within_roots(&body.location, &config.library_roots) {
+        return HttpResponse::BadRequest()
+            .body(format!("Location is outside the library roots: {}", body.location));
+    }
+
+    let result = client.set_location(id, &body.location, body.move_data).await;
 
     process(result)
 }
 
-fn process(result: Result<RpcResponse<Nothing>>) -> impl Responder {
+fn process(result: Result<RpcResponse<Nothing>>) -> HttpResponse {
     match result {
         Ok(_) => HttpResponse::Ok().json("ok"),
         Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
@@ -1,3 +1,10 @@
 pub mod config;
+pub mod disk;
 pub mod error;
+pub mod export;
+pub mod files;
+pub mod library;
+pub mod paths;
+#[cfg(test)]
+mod testing;
 pub mod torrent;
\ No newline at end of file
@@ -0,0 +1,489 @@
+#[cfg(target_os = "linux")]
+pub mod watch;
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+use log::{error, info};
+use serde::Serialize;
+
+use crate::torrent::{
+    organizer::{is_sample, VIDEO_EXTENSIONS},
+    release::{MediaKind, Release},
+};
+
+const AUDIO_EXTENSIONS: [&str; 8] = ["flac", "mp3", "m4a", "aac", "ogg", "opus", "wav", "ape"];
+
+/// How often the library is scanned again when changes cannot be watched.
+const RESCAN_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// A media file of the library with what its name tells about it.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Item {
+    /// Absolute, as taken by `/library/stream`
+    pub path: String,
+    pub name: String,
+    pub size: u64,
+    pub modified: Option<String>,
+    #[serde(flatten)]
+    pub release: Release,
+}
+
+/// Everything of one movie, show or album in the library.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Title {
+    pub title: String,
+    pub year: Option<u16>,
+    pub kind: MediaKind,
+    pub files: usize,
+    pub size: u64,
+    pub seasons: Vec<u32>,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Status {
+    pub scanning: bool,
+    /// When the last full scan finished
+    pub scanned: Option<String>,
+    pub files: usize,
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+fn folder_release(path: &Path) -> Option<Release> {
+    path.file_name()
+        .map(|name| Release::parse(&name.to_string_lossy()))
+}
+
+/// What the name of a media file and the folders above it tell, None for other files.
+fn describe(path: &Path) -> Option<Release> {
+    let ext = extension(path);
+    let parent = path.parent().unwrap_or(path);
+    if AUDIO_EXTENSIONS.contains(&ext.as_str()) {
+        // Tracks are named after the album folder, discs one level deeper
+        let folder = Some(parent)
+            .filter(|p| {
+                !p.file_name()
+                    .is_some_and(|n| n.to_string_lossy().to_lowercase().starts_with("cd"))
+            })
+            .or_else(|| parent.parent())?;
+        let mut release = folder_release(folder)?;
+        release.kind = MediaKind::Music;
+        release.season = None;
+        release.episode = None;
+        release.last_episode = None;
+        return Some(release);
+    }
+    if !VIDEO_EXTENSIONS.contains(&ext.as_str()) || is_sample(path) {
+        return None;
+    }
+
+    let mut release = Release::parse(&path.file_stem()?.to_string_lossy());
+    // Organized files are named by title and episode only, their folders hold the rest
+    for folder in path.ancestors().skip(1).take(2).filter_map(folder_release) {
+        if release.kind == MediaKind::Unknown || release.title.is_empty() {
+            release.title = folder.title.clone();
+            release.kind = folder.kind;
+        }
+        release.year = release.year.or(folder.year);
+        release.season = release.season.or(folder.season);
+        release.resolution = release.resolution.or(folder.resolution);
+    }
+    if release.season.is_some() {
+        release.kind = MediaKind::Tv;
+    } else if !matches!(release.kind, MediaKind::Tv | MediaKind::Movie) {
+        release.kind = MediaKind::Movie;
+    }
+    release.title = release.title.trim_end_matches([' ', '-']).to_string();
+    Some(release)
+}
+
+/// Titles compared without case and punctuation, "Mr. Robot" is "mr robot".
+fn key(title: &str) -> String {
+    title
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn episodes(release: &Release) -> Option<(u32, u32)> {
+    let first = release.episode?;
+    Some((first, release.last_episode.unwrap_or(first)))
+}
+
+/// Whether `item` is (part of) what a release named like `wanted` holds.
+fn covers(item: &Release, wanted: &Release) -> bool {
+    if key(&item.title) != key(&wanted.title) {
+        return false;
+    }
+    if wanted.season.is_some() {
+        return item.season == wanted.season
+            && match (episodes(item), episodes(wanted)) {
+                (Some((first, last)), Some((from, to))) => first <= to && from <= last,
+                _ => true,
+            };
+    }
+    wanted.year.is_none() || item.year.is_none() || item.year == wanted.year
+}
+
+fn item(path: &Path) -> Option<Item> {
+    let release = describe(path)?;
+    let metadata = fs::metadata(path).ok().filter(|m| m.is_file())?;
+    Some(Item {
+        path: path.to_string_lossy().into_owned(),
+        name: path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        size: metadata.len(),
+        modified: metadata
+            .modified()
+            .ok()
+            .map(|t| chrono::DateTime::<chrono::Local>::from(t).to_rfc3339()),
+        release,
+    })
+}
+
+/// Hidden folders are skipped, extractions are staged in them.
+fn hidden(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|n| n.to_string_lossy().starts_with('.'))
+}
+
+fn walk(path: &Path, found: &mut BTreeMap<PathBuf, Item>) -> io::Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let path = entry.path();
+        if hidden(&path) {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            if let Err(e) = walk(&path, found) {
+                error!("Could not index {}: {}", path.display(), e);
+            }
+        } else if let Some(item) = item(&path) {
+            found.insert(path, item);
+        }
+    }
+    Ok(())
+}
+
+// Replaces what is indexed below `path` with `found`
+fn replace(items: &mut BTreeMap<PathBuf, Item>, path: &Path, found: BTreeMap<PathBuf, Item>) {
+    items.retain(|p, _| !p.starts_with(path));
+    items.extend(found);
+}
+
+type Update = (PathBuf, BTreeMap<PathBuf, Item>);
+
+/// An index of the media files below the library roots.
+pub struct Library {
+    dirs: Vec<PathBuf>,
+    items: Mutex<BTreeMap<PathBuf, Item>>,
+    // Updates made while a scan runs, applied again on top of its result. Only locked while
+    // holding `items`.
+    scan_updates: Mutex<Option<Vec<Update>>>,
+    status: Mutex<Status>,
+}
+
+impl Library {
+    pub fn new(dirs: &[String]) -> Self {
+        Self {
+            dirs: dirs.iter().map(PathBuf::from).collect(),
+            items: Mutex::new(BTreeMap::new()),
+            scan_updates: Mutex::new(None),
+            status: Mutex::new(Status::default()),
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        let mut status = self.status.lock().unwrap().clone();
+        status.files = self.items.lock().unwrap().len();
+        status
+    }
+
+    /// Indexes all roots again, unless a scan is running already. Changes indexed by `update`
+    /// meanwhile are kept.
+    pub fn scan(&self) {
+        {
+            let mut status = self.status.lock().unwrap();
+            if status.scanning {
+                return;
+            }
+            status.scanning = true;
+        }
+        {
+            let _items = self.items.lock().unwrap();
+            *self.scan_updates.lock().unwrap() = Some(Vec::new());
+        }
+        let mut found = BTreeMap::new();
+        for dir in &self.dirs {
+            if let Err(e) = walk(dir, &mut found) {
+                error!("Could not index {}: {}", dir.display(), e);
+            }
+        }
+        info!("Library scanned, {} media files", found.len());
+        let mut items = self.items.lock().unwrap();
+        for (path, update) in self.scan_updates.lock().unwrap().take().unwrap_or_default() {
+            replace(&mut found, &path, update);
+        }
+        *items = found;
+        drop(items);
+
+        let mut status = self.status.lock().unwrap();
+        status.scanning = false;
+        status.scanned = Some(chrono::Local::now().to_rfc3339());
+    }
+
+    /// Indexes `path` again after it changed: a file, a folder or something removed.
+    pub fn update(&self, path: &Path) {
+        let mut found = BTreeMap::new();
+        if path.is_dir() && !path.ancestors().any(hidden) {
+            if let Err(e) = walk(path, &mut found) {
+                error!("Could not index {}: {}", path.display(), e);
+            }
+        } else if let Some(item) = item(path).filter(|_| !path.ancestors().any(hidden)) {
+            found.insert(path.to_path_buf(), item);
+        }
+        let mut items = self.items.lock().unwrap();
+        if let Some(updates) = self.scan_updates.lock().unwrap().as_mut() {
+            updates.push((path.to_path_buf(), found.clone()));
+        }
+        replace(&mut items, path, found);
+    }
+
+    /// Items whose title or file name contain all `terms`, of `kind` if given.
+    pub fn search(&self, terms: &str, kind: Option<MediaKind>) -> Vec<Item> {
+        let terms: Vec<String> = key(terms).split(' ').map(String::from).collect();
+        self.items
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|item| kind.is_none_or(|kind| item.release.kind == kind))
+            .filter(|item| {
+                let text = format!("{} {}", key(&item.release.title), key(&item.name));
+                terms.iter().all(|term| text.contains(term.as_str()))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// The movies, shows and albums in the library, by title.
+    pub fn titles(&self, kind: Option<MediaKind>) -> Vec<Title> {
+        let mut titles: BTreeMap<(String, Option<u16>, MediaKind), Title> = BTreeMap::new();
+        for item in self.items.lock().unwrap().values() {
+            let release = &item.release;
+            if kind.is_some_and(|kind| release.kind != kind) {
+                continue;
+            }
+            // Episodes carry the year of their show only now and then
+            let year = release.year.filter(|_| release.kind != MediaKind::Tv);
+            let title = titles
+                .entry((key(&release.title), year, release.kind))
+                .or_insert_with(|| Title {
+                    title: release.title.clone(),
+                    year,
+                    kind: release.kind,
+                    files: 0,
+                    size: 0,
+                    seasons: Vec::new(),
+                });
+            title.files += 1;
+            title.size += item.size;
+            if let Some(season) = release.season.filter(|s| !title.seasons.contains(s)) {
+                title.seasons.push(season);
+                title.seasons.sort_unstable();
+            }
+        }
+        titles.into_values().collect()
+    }
+
+    /// The items a release called `name` would bring, so what is already there.
+    pub fn matches(&self, name: &str) -> Vec<Item> {
+        let wanted = Release::parse(name);
+        if key(&wanted.title).is_empty() {
+            return Vec::new();
+        }
+        self.items
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|item| covers(&item.release, &wanted))
+            .cloned()
+            .collect()
+    }
+
+    /// Scans the library and keeps it up to date, following changes through inotify
+    /// where it can and rescanning every few minutes otherwise. Does not return.
+    pub fn watch(&self) {
+        #[cfg(target_os = "linux")]
+        if let Err(e) = self.follow() {
+            error!("Could not watch the library, rescanning it instead: {}", e);
+        }
+        loop {
+            self.scan();
+            thread::sleep(RESCAN_INTERVAL);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn follow(&self) -> io::Result<()> {
+        let mut watcher = watch::Watcher::new()?;
+        // Watching starts before the scan so nothing happening during it is missed
+        for dir in self.dirs.iter().filter(|d| d.is_dir()) {
+            watcher.add(dir)?;
+        }
+        self.scan();
+        loop {
+            for change in watcher.wait()? {
+                match change {
+                    watch::Change::Path(path) => self.update(&path),
+                    watch::Change::Overflow => self.scan(),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{scratch, write};
+    use std::sync::Arc;
+
+    #[test]
+    fn describes_media_files() {
+        let movie = describe(Path::new("/lib/Movies/Arrival (2016)/Arrival (2016).mkv")).unwrap();
+        assert_eq!(
+            (movie.title.as_str(), movie.year, movie.kind),
+            ("Arrival", Some(2016), MediaKind::Movie)
+        );
+
+        let episode = describe(Path::new("/lib/TV/Show/Season 02/Show - S02E03.mkv")).unwrap();
+        assert_eq!(episode.title, "Show");
+        assert_eq!((episode.season, episode.episode), (Some(2), Some(3)));
+        assert_eq!(episode.kind, MediaKind::Tv);
+
+        let download = describe(Path::new(
+            "/dl/Some.Movie.2019.1080p.BluRay.x264/smv-1080p.mkv",
+        ))
+        .unwrap();
+        assert_eq!(download.title, "Some Movie");
+        assert_eq!(download.year, Some(2019));
+        assert_eq!(download.resolution.as_deref(), Some("1080p"));
+
+        let track = describe(Path::new(
+            "/lib/Music/Artist - Album (2001) [FLAC]/CD1/01.flac",
+        ))
+        .unwrap();
+        assert_eq!(
+            (track.title.as_str(), track.kind),
+            ("Artist - Album", MediaKind::Music)
+        );
+
+        assert_eq!(describe(Path::new("/lib/Movies/Arrival/sample.mkv")), None);
+        assert_eq!(describe(Path::new("/lib/Movies/Arrival/poster.jpg")), None);
+    }
+
+    #[test]
+    fn scans_searches_and_matches() {
+        let dir = scratch("scan");
+        write(&dir.join("Movies/Arrival (2016)/Arrival (2016).mkv"), "media");
+        write(&dir.join("TV/Mr. Robot/Season 01/Mr. Robot - S01E01.mkv"), "media");
+        write(&dir.join("TV/Mr. Robot/Season 01/Mr. Robot - S01E02.mkv"), "media");
+        write(&dir.join(".extracting-x/Hidden.2020.mkv"), "media");
+        let library = Library::new(&[dir.to_string_lossy().into_owned()]);
+        library.scan();
+        assert_eq!(library.status().files, 3);
+        assert!(library.status().scanned.is_some());
+
+        assert_eq!(library.search("mr robot", None).len(), 2);
+        assert_eq!(library.search("arrival", Some(MediaKind::Tv)).len(), 0);
+        assert_eq!(
+            library.search("", Some(MediaKind::Movie))[0].name,
+            "Arrival (2016).mkv"
+        );
+
+        assert_eq!(library.matches("Arrival.2016.2160p.WEB-DL.x265").len(), 1);
+        assert!(library.matches("Arrival.1999.1080p.BluRay").is_empty());
+        assert_eq!(library.matches("Mr.Robot.S01.1080p.WEB").len(), 2);
+        assert_eq!(library.matches("Mr Robot S01E02 720p HDTV").len(), 1);
+        assert!(library.matches("Mr.Robot.S01E03.720p").is_empty());
+        assert!(library.matches("Mr.Robot.S02E01.720p").is_empty());
+
+        let titles = library.titles(None);
+        assert_eq!(titles.len(), 2);
+        let show = titles.iter().find(|t| t.kind == MediaKind::Tv).unwrap();
+        assert_eq!(
+            (show.title.as_str(), show.files, show.size),
+            ("Mr Robot", 2, 10)
+        );
+        assert_eq!(show.seasons, vec![1]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn updates_changed_paths() {
+        let dir = scratch("update");
+        let show = dir.join("TV/Show/Season 01");
+        write(&show.join("Show - S01E01.mkv"), "media");
+        let library = Library::new(&[dir.to_string_lossy().into_owned()]);
+        library.scan();
+
+        write(&show.join("Show - S01E02.mkv"), "media");
+        library.update(&show.join("Show - S01E02.mkv"));
+        assert_eq!(library.status().files, 2);
+
+        fs::remove_dir_all(&show).unwrap();
+        library.update(&dir.join("TV/Show"));
+        assert_eq!(library.status().files, 0);
+
+        write(&dir.join("Movies/Arrival (2016)/Arrival (2016).mkv"), "media");
+        library.update(&dir.join("Movies"));
+        assert_eq!(library.matches("Arrival 2016").len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_updates_made_during_a_scan() {
+        let dir = scratch("rescan");
+        for i in 0..2000 {
+            write(&dir.join(format!("Movies/Movie {} (2000).mkv", i)), "media");
+        }
+        let library = Arc::new(Library::new(&[dir.to_string_lossy().into_owned()]));
+        let scan = {
+            let library = library.clone();
+            thread::spawn(move || library.scan())
+        };
+        while !library.status().scanning && !scan.is_finished() {
+            thread::yield_now();
+        }
+
+        let arrival = dir.join("Arrival (2016).mkv");
+        write(&arrival, "media");
+        library.update(&arrival);
+        fs::remove_file(dir.join("Movies/Movie 0 (2000).mkv")).unwrap();
+        library.update(&dir.join("Movies/Movie 0 (2000).mkv"));
+        scan.join().unwrap();
+
+        assert_eq!(library.matches("Arrival 2016").len(), 1);
+        assert!(library.matches("Movie 0 2000").is_empty());
+        assert_eq!(library.status().files, 2000);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
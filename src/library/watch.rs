@@ -0,0 +1,167 @@
+use std::{
+    collections::HashMap,
+    ffi::{CString, OsStr},
+    fs, io,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+};
+
+use log::error;
+
+const MASK: u32 = libc::IN_CREATE
+    | libc::IN_CLOSE_WRITE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_DELETE
+    | libc::IN_DELETE_SELF
+    | libc::IN_ONLYDIR;
+
+const EVENT_SIZE: usize = std::mem::size_of::<libc::inotify_event>();
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// A file or folder was created, written, moved or removed
+    Path(PathBuf),
+    /// The kernel dropped events, only a full scan catches up
+    Overflow,
+}
+
+/// Changes below folders, through inotify. Every folder needs its own watch, folders
+/// created later are watched as they appear.
+pub struct Watcher {
+    fd: i32,
+    dirs: HashMap<i32, PathBuf>,
+}
+
+impl Watcher {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd,
+            dirs: HashMap::new(),
+        })
+    }
+
+    /// Watches `dir` and the folders below it, hidden ones aside. Watching a folder again,
+    /// as after it was moved, updates its path. Only fails if `dir` itself cannot be watched,
+    /// folders below it that cannot be read or watched, as past the watch limit, are logged
+    /// and skipped.
+    pub fn add(&mut self, dir: &Path) -> io::Result<()> {
+        let c_path = CString::new(dir.as_os_str().to_os_string().into_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd, c_path.as_ptr(), MASK) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.dirs.insert(wd, dir.to_path_buf());
+
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Could not watch the folders in {}: {}", dir.display(), e);
+                return Ok(());
+            }
+        };
+        for entry in entries.flatten() {
+            let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+            if is_dir && !entry.file_name().as_bytes().starts_with(b".") {
+                let path = entry.path();
+                if let Err(e) = self.add(&path) {
+                    error!("Could not watch {}: {}", path.display(), e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for the next changes.
+    pub fn wait(&mut self) -> io::Result<Vec<Change>> {
+        let mut buffer = vec![0u8; 64 * 1024];
+        let read = unsafe { libc::read(self.fd, buffer.as_mut_ptr() as *mut _, buffer.len()) };
+        if read < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::Interrupted => Ok(Vec::new()),
+                _ => Err(e),
+            };
+        }
+
+        let mut changes = Vec::new();
+        let mut offset = 0;
+        while offset + EVENT_SIZE <= read as usize {
+            let event: libc::inotify_event =
+                unsafe { std::ptr::read_unaligned(buffer.as_ptr().add(offset) as *const _) };
+            let name = &buffer[offset + EVENT_SIZE..offset + EVENT_SIZE + event.len as usize];
+            let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+            offset += EVENT_SIZE + event.len as usize;
+
+            if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                changes.push(Change::Overflow);
+                continue;
+            }
+            if event.mask & libc::IN_IGNORED != 0 {
+                self.dirs.remove(&event.wd);
+                continue;
+            }
+            let dir = match self.dirs.get(&event.wd) {
+                Some(dir) => dir,
+                None => continue,
+            };
+            let path = if name.is_empty() {
+                dir.clone()
+            } else {
+                dir.join(OsStr::from_bytes(name))
+            };
+            let created = libc::IN_CREATE | libc::IN_MOVED_TO;
+            if event.mask & libc::IN_ISDIR != 0
+                && event.mask & created != 0
+                && !name.starts_with(b".")
+            {
+                if let Err(e) = self.add(&path) {
+                    error!("Could not watch {}: {}", path.display(), e);
+                }
+            }
+            let change = Change::Path(path);
+            if !changes.contains(&change) {
+                changes.push(change);
+            }
+        }
+        Ok(changes)
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_new_folders() {
+        let dir = std::env::temp_dir().join(format!("watch-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut watcher = Watcher::new().unwrap();
+        watcher.add(&dir).unwrap();
+
+        fs::create_dir(dir.join("Movies")).unwrap();
+        assert_eq!(
+            watcher.wait().unwrap(),
+            vec![Change::Path(dir.join("Movies"))]
+        );
+
+        // The new folder is watched too
+        fs::write(dir.join("Movies/movie.mkv"), "video").unwrap();
+        let file = Change::Path(dir.join("Movies/movie.mkv"));
+        assert_eq!(watcher.wait().unwrap()[0], file);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
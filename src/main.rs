@@ -1,23 +1,42 @@
 
+use std::{path::Path, sync::Arc, time::Duration};
+
 use actix_web::{web, App, HttpResponse, HttpServer};
 use dotenv::dotenv;
-use tokio::net::UdpSocket;
+use tokio::{net::UdpSocket, sync::broadcast::RecvError};
 
 
 use torrent::{
     client::Client, 
     downloader::TorrentDownloader, 
+    events::{self, EventBus},
+    feed::Feed,
+    guard::DiskGuard,
+    notifier::Notifications,
+    pipeline::Pipeline,
+    policy::PolicyEngine,
+    rpc::Rpc,
+    schedule::Scheduler,
     search::Searcher, 
-    source::l337xto::L337xTo
+    source::l337xto::L337xTo,
+    webhook::Webhooks,
 };
+use library::Library;
 use transmission_rpc::TransClient;
 
 #[macro_use]
 extern crate log;
 
 mod config;
+mod disk;
 mod error;
+mod export;
+mod files;
 mod handlers;
+mod library;
+mod paths;
+#[cfg(test)]
+mod testing;
 mod torrent;
 
 #[actix_web::main]
@@ -51,17 +70,155 @@ async fn main() -> std::io::Result<()> {
 
     let server_config = config::ServerConfig::from_env();
     let server_addr = server_config.server_addr.clone();
+
+    let scheduler = web::Data::new(Scheduler::load(
+        Path::new(&server_config.data_dir).join("schedule.json"),
+    ));
+    // The schedule follows the clock and the seeding policies need ratios and seeding times the
+    // event bus does not list, so both keep their own timers
+    let schedule_task = scheduler.clone();
+    let schedule_client = client(&server_config);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            schedule_task.tick(&schedule_client).await;
+        }
+    });
     
+    let policies = web::Data::new(PolicyEngine::new(&server_config));
+    if !policies.config.rules.is_empty() {
+        let policy_task = policies.clone();
+        let policy_client = client(&server_config);
+        let every = Duration::from_secs(policies.config.interval_minutes.max(1) * 60);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            loop {
+                interval.tick().await;
+                if let Err(e) = policy_task.run(&policy_client, false).await {
+                    error!("Seeding policies failed: {}", e);
+                }
+            }
+        });
+    }
+
+    let feed = Arc::new(Feed::default());
+    let events = web::Data::new(EventBus::new(
+        server_config.categories.clone(),
+        vec![feed.clone()],
+    ));
+    let feed = web::Data::from(feed);
+    let events_task = events.clone();
+    let events_client = client(&server_config);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(events::POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            events_task.poll(&events_client).await;
+        }
+    });
+    // Free space shrinks without any torrent event, so the guard checks it on a timer against
+    // the bus's latest list
+    let guard = web::Data::new(DiskGuard::new(&server_config));
+    if guard.watching() {
+        let guard_task = guard.clone();
+        let guard_events = events.clone();
+        let guard_client = client(&server_config);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                if let Some(torrents) = guard_events.torrents() {
+                    guard_task.watch(&guard_client, &torrents).await;
+                }
+            }
+        });
+    }
+    let webhooks = Webhooks::new(server_config.webhooks.clone());
+    if !webhooks.is_empty() {
+        let receiver = events.subscribe();
+        tokio::spawn(async move { webhooks.run(receiver).await });
+    }
+    let notifications = Notifications::new(server_config.notifications.clone());
+    if !notifications.is_empty() {
+        let receiver = events.subscribe();
+        tokio::spawn(async move { notifications.run(receiver).await });
+    }
+    let pipeline = web::Data::new(Pipeline::load(
+        Path::new(&server_config.data_dir).join("pipeline.json"),
+        &server_config,
+    ));
+    let pipeline_task = pipeline.clone().into_inner();
+    let pipeline_client = Arc::new(client(&server_config));
+    let receiver = events.subscribe();
+    tokio::spawn(async move { pipeline_task.listen(pipeline_client, receiver).await });
+    let library = web::Data::new(Library::new(&server_config.library_roots));
+    let library_task = library.clone();
+    std::thread::spawn(move || library_task.watch());
+    let mut event_log = events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match event_log.recv().await {
+                Ok(event) => info!("Torrent {:?}: {}", event.event, event.torrent.name),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
     HttpServer::new(move || {
         App::new()
+            .data(server_config.clone())
             .data(client(&server_config))
             .data(searcher())
+            .app_data(scheduler.clone())
+            .app_data(policies.clone())
+            .app_data(guard.clone())
+            .app_data(feed.clone())
+            .app_data(events.clone())
+            .app_data(pipeline.clone())
+            .app_data(library.clone())
             .service(handlers::search_torrents)
             .service(handlers::list_torrents)
+            .service(handlers::torrent_feed)
+            .service(handlers::recent_events)
+            .service(handlers::upload_torrent)
             .service(handlers::add_torrent)
+            .service(handlers::upload_preview)
+            .service(handlers::preview_torrent)
             .service(handlers::resume_torrent)
             .service(handlers::pause_torrent)
+            .service(handlers::torrent_detail)
+            .service(handlers::pipeline_jobs)
+            .service(handlers::retry_pipeline)
+            .service(handlers::extractions)
+            .service(handlers::torrent_files)
+            .service(handlers::stream_torrent_file)
+            .service(handlers::set_sequential)
+            .service(handlers::export_torrent)
+            .service(handlers::library_items)
+            .service(handlers::library_titles)
+            .service(handlers::library_match)
+            .service(handlers::rescan_library)
+            .service(handlers::library_files)
+            .service(handlers::stream_library_file)
+            .service(handlers::preview_organize)
+            .service(handlers::verify_torrent)
+            .service(handlers::reannounce_torrent)
+            .service(handlers::queue_torrent)
+            .service(handlers::stats)
+            .service(handlers::get_schedule)
+            .service(handlers::update_schedule)
+            .service(handlers::get_policies)
+            .service(handlers::run_policies)
+            .service(handlers::policy_audit)
+            .service(handlers::session_settings)
+            .service(handlers::update_session_settings)
+            .service(handlers::queue_settings)
+            .service(handlers::update_queue_settings)
             .service(handlers::del_torrent)
+            .service(handlers::batch_action)
+            .service(handlers::set_location)
             .default_service(web::route().to(HttpResponse::NotFound))
     })
     .bind(server_addr)?
@@ -75,5 +232,5 @@ pub fn searcher() -> Searcher {
 
 pub fn client(config: &config::ServerConfig) -> Client {
     let client = TransClient::new(&config.transmission_url);
-    Client::new(client)
+    Client::new(client, Rpc::new(&config.transmission_url))
 }
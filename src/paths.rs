@@ -0,0 +1,41 @@
+use std::path::{Component, Path};
+
+/// Whether `path` is an absolute path equal to or inside one of `roots`, without `..` tricks.
+pub fn within_roots(path: &str, roots: &[String]) -> bool {
+    let path = Path::new(path);
+    path.is_absolute()
+        && !path.components().any(|c| c == Component::ParentDir)
+        && roots.iter().any(|root| path.starts_with(root))
+}
+
+pub fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_within_roots() {
+        let roots = vec!["/mnt/usb/Movies".to_string(), "/mnt/usb/TV/".to_string()];
+
+        assert!(within_roots("/mnt/usb/Movies", &roots));
+        assert!(within_roots("/mnt/usb/TV/Show/Season 01", &roots));
+        assert!(!within_roots("/mnt/usb/MoviesExtra", &roots));
+        assert!(!within_roots("/mnt/usb/Movies/../../etc", &roots));
+        assert!(!within_roots("Movies", &roots));
+        assert!(!within_roots("/mnt/usb/Movies", &[]));
+    }
+
+    #[test]
+    fn split_comma_list() {
+        assert_eq!(split_list(" /a, /b ,,"), vec!["/a", "/b"]);
+        assert!(split_list("").is_empty());
+    }
+}
@@ -0,0 +1,121 @@
+//! Local stand-in servers for tests of code that talks to other services.
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use crate::torrent::client::Torrent;
+
+/// An empty temporary folder for a test, canonical so it compares equal to resolved paths.
+pub fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("remote-media-pi-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir.canonicalize().unwrap()
+}
+
+/// Writes `text` to `path`, creating the folders above it.
+pub fn write(path: &Path, text: &str) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, text).unwrap();
+}
+
+/// A downloading torrent to build test torrents from, as in
+/// `Torrent { id: 2, ..testing::torrent() }`.
+pub fn torrent() -> Torrent {
+    Torrent {
+        id: 1,
+        name: "Test".to_string(),
+        hash: "1111111111111111111111111111111111111111".to_string(),
+        status: "downloading".to_string(),
+        size: 1,
+        progress: 0,
+        queue_position: 0,
+        stalled: false,
+        error: None,
+        labels: vec![],
+        download_dir: "/downloads".to_string(),
+        category: None,
+    }
+}
+
+pub struct Request {
+    /// Method and path, as in "POST /hook"
+    pub target: String,
+    /// Lowercased "name: value" lines
+    pub headers: Vec<String>,
+    pub body: String,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let prefix = format!("{}:", name.to_lowercase());
+        self.headers
+            .iter()
+            .find_map(|h| h.strip_prefix(&prefix))
+            .map(str::trim)
+    }
+}
+
+/// Answers one HTTP request per `(status, body)`, recording what it got.
+/// Returns the server's base url without a trailing slash.
+pub fn http_server<B: Into<String>>(
+    responses: Vec<(u16, B)>,
+) -> (String, Arc<Mutex<Vec<Request>>>) {
+    let responses: Vec<(u16, String)> = responses
+        .into_iter()
+        .map(|(status, body)| (status, body.into()))
+        .collect();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let log = received.clone();
+
+    thread::spawn(move || {
+        for (status, body) in responses {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+
+            let mut target = String::new();
+            reader.read_line(&mut target).unwrap();
+            let target = target.rsplitn(2, ' ').last().unwrap_or("").to_string();
+
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                headers.push(line.trim().to_lowercase());
+            }
+            let mut request = Request {
+                target,
+                headers,
+                body: String::new(),
+            };
+            let length = request
+                .header("content-length")
+                .map(|l| l.parse().unwrap())
+                .unwrap_or(0);
+            let mut raw = vec![0; length];
+            reader.read_exact(&mut raw).unwrap();
+            request.body = String::from_utf8(raw).unwrap();
+            log.lock().unwrap().push(request);
+
+            let response = format!(
+                "HTTP/1.1 {} Status\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+        }
+    });
+    (url, received)
+}
@@ -1,4 +1,21 @@
+pub mod batch;
+pub mod category;
 pub mod client;
+pub mod events;
+pub mod extract;
+pub mod feed;
+pub mod guard;
+pub mod metainfo;
+pub mod notifier;
+pub mod organizer;
+pub mod pipeline;
+pub mod policy;
+pub mod release;
+pub mod rpc;
+pub mod schedule;
 pub mod search;
+pub mod session;
 pub mod source;
+pub mod stream;
+pub mod webhook;
 pub mod downloader;
@@ -0,0 +1,272 @@
+use serde::{Deserialize, Serialize};
+
+use super::client::Torrent;
+use crate::error::{AppError, AppResult};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BatchAction {
+    Start,
+    Stop,
+    StartNow,
+    Verify,
+    Reannounce,
+    Remove,
+    RemoveWithData,
+}
+
+/// A torrent id, an infohash, `all` or a filter expression such as
+/// `status:paused label:tv`, where every condition has to match.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Target {
+    Id(i64),
+    Expr(String),
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ActionResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<Target>,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ActionResult {
+    pub fn done(torrent: &Torrent, error: Option<String>) -> Self {
+        Self {
+            id: Some(torrent.id),
+            name: Some(torrent.name.clone()),
+            target: None,
+            ok: error.is_none(),
+            error,
+        }
+    }
+
+    pub fn unmatched(target: &Target) -> Self {
+        Self {
+            id: None,
+            name: None,
+            target: Some(target.clone()),
+            ok: false,
+            error: Some("No matching torrent".into()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Selection<'a> {
+    pub torrents: Vec<&'a Torrent>,
+    pub unmatched: Vec<Target>,
+}
+
+impl Selection<'_> {
+    pub fn ids(&self) -> Vec<i64> {
+        self.torrents.iter().map(|t| t.id).collect()
+    }
+}
+
+enum Selector {
+    Id(i64),
+    Hash(String),
+    All,
+    Filter(Vec<Condition>),
+}
+
+enum Condition {
+    Status(String),
+    Category(String),
+    Label(String),
+    Name(String),
+}
+
+impl Condition {
+    fn matches(&self, torrent: &Torrent) -> bool {
+        match self {
+            Condition::Status(status) => torrent.status.eq_ignore_ascii_case(status),
+            Condition::Category(name) => torrent
+                .category
+                .as_ref()
+                .is_some_and(|c| c.eq_ignore_ascii_case(name)),
+            Condition::Label(label) => torrent.labels.iter().any(|l| l == label),
+            Condition::Name(part) => torrent.name.to_lowercase().contains(&part.to_lowercase()),
+        }
+    }
+}
+
+impl Selector {
+    fn parse(target: &Target) -> AppResult<Self> {
+        let expr = match target {
+            Target::Id(id) => return Ok(Selector::Id(*id)),
+            Target::Expr(expr) => expr.trim(),
+        };
+
+        if expr.eq_ignore_ascii_case("all") {
+            return Ok(Selector::All);
+        }
+        if is_infohash(expr) {
+            return Ok(Selector::Hash(expr.to_lowercase()));
+        }
+        if let Ok(id) = expr.parse() {
+            return Ok(Selector::Id(id));
+        }
+
+        let conditions = expr
+            .split_whitespace()
+            .map(|part| {
+                let (key, value) = part
+                    .split_once(':')
+                    .filter(|(_, v)| !v.is_empty())
+                    .ok_or_else(|| invalid(part))?;
+                let value = value.to_string();
+                match key {
+                    "status" => Ok(Condition::Status(value)),
+                    "category" => Ok(Condition::Category(value)),
+                    "label" => Ok(Condition::Label(value)),
+                    "name" => Ok(Condition::Name(value)),
+                    _ => Err(invalid(part)),
+                }
+            })
+            .collect::<AppResult<Vec<_>>>()?;
+
+        if conditions.is_empty() {
+            return Err(invalid(expr));
+        }
+        Ok(Selector::Filter(conditions))
+    }
+
+    fn matches(&self, torrent: &Torrent) -> bool {
+        match self {
+            Selector::Id(id) => torrent.id == *id,
+            Selector::Hash(hash) => torrent.hash.eq_ignore_ascii_case(hash),
+            Selector::All => true,
+            Selector::Filter(conditions) => conditions.iter().all(|c| c.matches(torrent)),
+        }
+    }
+
+    // Ids and hashes name a single torrent, so finding nothing is worth reporting
+    fn is_exact(&self) -> bool {
+        matches!(self, Selector::Id(_) | Selector::Hash(_))
+    }
+}
+
+/// Resolves the targets against the current torrent list, keeping list order
+/// and dropping duplicates when several targets match the same torrent.
+pub fn select<'a>(torrents: &'a [Torrent], targets: &[Target]) -> AppResult<Selection<'a>> {
+    if targets.is_empty() {
+        return Err(AppError::ValidationError("No targets given".into()));
+    }
+
+    let selectors = targets
+        .iter()
+        .map(Selector::parse)
+        .collect::<AppResult<Vec<_>>>()?;
+
+    let unmatched = targets
+        .iter()
+        .zip(&selectors)
+        .filter(|(_, s)| s.is_exact() && !torrents.iter().any(|t| s.matches(t)))
+        .map(|(target, _)| target.clone())
+        .collect();
+
+    let torrents = torrents
+        .iter()
+        .filter(|t| selectors.iter().any(|s| s.matches(t)))
+        .collect();
+
+    Ok(Selection {
+        torrents,
+        unmatched,
+    })
+}
+
+fn is_infohash(text: &str) -> bool {
+    (text.len() == 40 || text.len() == 64) && text.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn invalid(expr: &str) -> AppError {
+    AppError::ValidationError(format!("Invalid target: {}", expr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn torrent(id: i64, name: &str, status: &str, labels: Vec<&str>) -> Torrent {
+        Torrent {
+            id,
+            name: name.to_string(),
+            hash: format!("{:040x}", id),
+            status: status.to_string(),
+            labels: labels.into_iter().map(String::from).collect(),
+            ..testing::torrent()
+        }
+    }
+
+    fn torrents() -> Vec<Torrent> {
+        let mut torrents = vec![
+            torrent(1, "Some Movie", "paused", vec!["video"]),
+            torrent(2, "Some Show S01", "downloading", vec!["tv", "video"]),
+            torrent(3, "Linux ISO", "paused", vec![]),
+        ];
+        torrents[1].category = Some("tv".to_string());
+        torrents
+    }
+
+    fn ids(targets: Vec<Target>) -> Vec<i64> {
+        select(&torrents(), &targets).unwrap().ids()
+    }
+
+    fn expr(text: &str) -> Target {
+        Target::Expr(text.to_string())
+    }
+
+    #[test]
+    fn select_by_id_and_hash() {
+        let hash = format!("{:040X}", 3);
+        assert_eq!(ids(vec![Target::Id(2), expr(&hash)]), vec![2, 3]);
+        assert_eq!(ids(vec![expr("1"), Target::Id(1)]), vec![1]);
+    }
+
+    #[test]
+    fn select_all_and_filters() {
+        assert_eq!(ids(vec![expr("all")]), vec![1, 2, 3]);
+        assert_eq!(ids(vec![expr("status:paused")]), vec![1, 3]);
+        assert_eq!(ids(vec![expr("status:paused label:video")]), vec![1]);
+        assert_eq!(ids(vec![expr("category:TV")]), vec![2]);
+        assert_eq!(ids(vec![expr("name:linux")]), vec![3]);
+        assert!(ids(vec![expr("label:music")]).is_empty());
+    }
+
+    #[test]
+    fn reports_unmatched_targets() {
+        let torrents = torrents();
+        let targets = vec![Target::Id(9), expr(&"f".repeat(40)), expr("label:none")];
+        let selection = select(&torrents, &targets).unwrap();
+        assert!(selection.torrents.is_empty());
+        assert_eq!(selection.unmatched, targets[..2].to_vec());
+    }
+
+    #[test]
+    fn rejects_invalid_targets() {
+        let torrents = torrents();
+        assert!(select(&torrents, &[]).is_err());
+        assert!(select(&torrents, &[expr("size:big")]).is_err());
+        assert!(select(&torrents, &[expr("status:")]).is_err());
+        assert!(select(&torrents, &[expr("  ")]).is_err());
+    }
+
+    #[test]
+    fn action_names() {
+        let action: BatchAction = serde_json::from_str("\"remove-with-data\"").unwrap();
+        assert_eq!(action, BatchAction::RemoveWithData);
+        let action: BatchAction = serde_json::from_str("\"start-now\"").unwrap();
+        assert_eq!(action, BatchAction::StartNow);
+    }
+}
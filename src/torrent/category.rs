@@ -0,0 +1,218 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    client::{AddOptions, Torrent},
+    organizer::OrganizeMode,
+    release::{MediaKind, Release},
+};
+use crate::error::{AppError, AppResult};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum PostAction {
+    Move { to: String },
+    Copy { to: String },
+    Hardlink { to: String },
+    /// Unpacks the archive sets found in the files next to them, or below `to`
+    Extract {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        to: Option<String>,
+    },
+    /// Deletes files and folders matching the patterns ("*" wildcards), or samples,
+    /// .nfo, .txt, .exe and shortcut files when none are given
+    DeleteJunk {
+        #[serde(default)]
+        patterns: Vec<String>,
+    },
+    Command { run: String },
+    /// Places videos and subtitles into Movies/ and TV/ folders below `to`
+    Organize {
+        to: String,
+        #[serde(default)]
+        mode: OrganizeMode,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Category {
+    pub name: String,
+    pub download_dir: String,
+    #[serde(default)]
+    pub labels: Vec<String>,
+    pub seed_ratio_limit: Option<f64>,
+    #[serde(default)]
+    pub on_complete: Vec<PostAction>,
+    /// Release kind this category is suggested for in search results
+    pub media: Option<MediaKind>,
+}
+
+impl Category {
+    /// Fills the add options from the category, keeping anything set explicitly.
+    pub fn apply(&self, options: &mut AddOptions) {
+        if options.download_dir.is_none() {
+            options.download_dir = Some(self.download_dir.clone());
+        }
+        if options.seed_ratio_limit.is_none() {
+            options.seed_ratio_limit = self.seed_ratio_limit;
+        }
+
+        let labels = options.labels.get_or_insert_with(Vec::new);
+        for label in std::iter::once(&self.name).chain(&self.labels) {
+            if !labels.contains(label) {
+                labels.push(label.clone());
+            }
+        }
+    }
+
+    /// Names must not be empty and download dirs must be absolute and below `/`: `matches`
+    /// compares path prefixes, so an empty dir or `/` would claim every torrent.
+    pub fn validate(&self) -> AppResult<()> {
+        if self.name.trim().is_empty() {
+            return Err(AppError::ValidationError("Categories need a name".to_string()));
+        }
+        let dir = Path::new(&self.download_dir);
+        if !dir.is_absolute() || dir.parent().is_none() {
+            return Err(AppError::ValidationError(format!(
+                "Download dir of category {} must be an absolute path below /",
+                self.name
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, torrent: &Torrent) -> bool {
+        torrent.labels.contains(&self.name)
+            || Path::new(&torrent.download_dir).starts_with(&self.download_dir)
+    }
+}
+
+pub fn find<'a>(categories: &'a [Category], name: &str) -> AppResult<&'a Category> {
+    categories
+        .iter()
+        .find(|c| c.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| AppError::ValidationError(format!("Unknown category: {}", name)))
+}
+
+pub fn categorize<'a>(categories: &'a [Category], torrent: &Torrent) -> Option<&'a Category> {
+    categories
+        .iter()
+        .find(|c| torrent.labels.contains(&c.name))
+        .or_else(|| categories.iter().find(|c| c.matches(torrent)))
+}
+
+pub fn suggest<'a>(categories: &'a [Category], name: &str) -> Option<&'a Category> {
+    let kind = Release::parse(name).kind;
+    categories.iter().find(|c| c.media == Some(kind))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn categories() -> Vec<Category> {
+        vec![
+            Category {
+                name: "movies".to_string(),
+                download_dir: "/mnt/usb/Movies".to_string(),
+                labels: vec!["video".to_string()],
+                seed_ratio_limit: Some(1.5),
+                on_complete: vec![],
+                media: Some(MediaKind::Movie),
+            },
+            Category {
+                name: "tv".to_string(),
+                download_dir: "/mnt/usb/TV".to_string(),
+                labels: vec![],
+                seed_ratio_limit: None,
+                on_complete: vec![PostAction::Command {
+                    run: "notify-tv".to_string(),
+                }],
+                media: Some(MediaKind::Tv),
+            },
+        ]
+    }
+
+    fn torrent(labels: Vec<&str>, download_dir: &str) -> Torrent {
+        Torrent {
+            labels: labels.into_iter().map(String::from).collect(),
+            download_dir: download_dir.to_string(),
+            ..testing::torrent()
+        }
+    }
+
+    #[test]
+    fn apply_fills_defaults() {
+        let categories = categories();
+        let mut options = AddOptions {
+            labels: Some(vec!["mine".to_string()]),
+            ..AddOptions::default()
+        };
+        find(&categories, "Movies").unwrap().apply(&mut options);
+
+        assert_eq!(options.download_dir.as_deref(), Some("/mnt/usb/Movies"));
+        assert_eq!(options.seed_ratio_limit, Some(1.5));
+        assert_eq!(
+            options.labels,
+            Some(vec![
+                "mine".to_string(),
+                "movies".to_string(),
+                "video".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn apply_keeps_explicit_options() {
+        let categories = categories();
+        let mut options = AddOptions {
+            download_dir: Some("/mnt/usb/TV/Kids".to_string()),
+            ..AddOptions::default()
+        };
+        find(&categories, "tv").unwrap().apply(&mut options);
+        assert_eq!(options.download_dir.as_deref(), Some("/mnt/usb/TV/Kids"));
+    }
+
+    #[test]
+    fn validate_rejects_catch_all_dirs() {
+        let mut category = categories().remove(0);
+        assert!(category.validate().is_ok());
+        for dir in ["", "/", "Movies", "./Movies"] {
+            category.download_dir = dir.to_string();
+            assert!(category.validate().is_err(), "{:?}", dir);
+        }
+        category.download_dir = "/mnt/usb/Movies".to_string();
+        category.name = " ".to_string();
+        assert!(category.validate().is_err());
+    }
+
+    #[test]
+    fn unknown_category() {
+        assert!(find(&categories(), "games").is_err());
+    }
+
+    #[test]
+    fn categorize_by_label_or_dir() {
+        let categories = categories();
+        let by_label = torrent(vec!["tv"], "/downloads");
+        let by_dir = torrent(vec![], "/mnt/usb/Movies/Film");
+        let none = torrent(vec![], "/downloads");
+
+        assert_eq!(categorize(&categories, &by_label).unwrap().name, "tv");
+        assert_eq!(categorize(&categories, &by_dir).unwrap().name, "movies");
+        assert!(categorize(&categories, &none).is_none());
+    }
+
+    #[test]
+    fn suggest_from_release_name() {
+        let categories = categories();
+        assert_eq!(suggest(&categories, "Show.S01E01.720p").unwrap().name, "tv");
+        assert_eq!(
+            suggest(&categories, "Film.2020.1080p.WEBRip").unwrap().name,
+            "movies"
+        );
+        assert!(suggest(&categories, "Artist - Album [FLAC]").is_none());
+    }
+}
@@ -1,90 +1,548 @@
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use transmission_rpc::{
-    types::{
-        Id, Nothing, Result, RpcResponse, TorrentAction, TorrentAddArgs, TorrentAdded,
-        TorrentGetField,
-    },
+    types::{Id, Nothing, Result, RpcResponse, TorrentAction, TorrentGetField},
     TransClient,
 };
 
+use super::{
+    batch::BatchAction,
+    rpc::Rpc,
+    session::{self, QueueMove, QueueSettings, SessionSettings, SessionStats},
+    stream::PieceMap,
+};
+use crate::{
+    error::{AppError, AppResult},
+    paths::within_roots,
+};
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Torrent {
     pub id: i64,
     pub name: String,
+    #[serde(default)]
+    pub hash: String,
     pub status: String,
     pub size: i64,
     pub progress: i32,
+    #[serde(default)]
+    pub queue_position: i64,
+    /// Active but not moving any data
+    #[serde(default)]
+    pub stalled: bool,
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub labels: Vec<String>,
+    #[serde(default)]
+    pub download_dir: String,
+    #[serde(default)]
+    pub category: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TorrentList<T> {
+    torrents: Vec<T>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct RpcTorrent {
+    id: i64,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    hash_string: String,
+    status: Option<i64>,
+    is_finished: Option<bool>,
+    is_stalled: Option<bool>,
+    #[serde(default)]
+    total_size: i64,
+    #[serde(default)]
+    percent_done: f64,
+    #[serde(default)]
+    queue_position: i64,
+    error: Option<i64>,
+    #[serde(default)]
+    error_string: String,
+    #[serde(default)]
+    labels: Vec<String>,
+    #[serde(default)]
+    download_dir: String,
+    // Only requested for the detail view
+    #[serde(default)]
+    recheck_progress: f64,
+    #[serde(default)]
+    rate_download: i64,
+    #[serde(default)]
+    rate_upload: i64,
+    #[serde(default)]
+    eta: i64,
+    #[serde(default)]
+    upload_ratio: f64,
+    #[serde(default)]
+    peers_connected: i64,
+    #[serde(default)]
+    tracker_stats: Vec<TrackerStat>,
+    // Transmission 4.1 names new fields in snake case
+    #[serde(default, rename = "sequential_download")]
+    sequential_download: bool,
+    // Only requested for the policy engine
+    #[serde(default)]
+    seconds_seeding: i64,
+    #[serde(default)]
+    activity_date: i64,
+    #[serde(default)]
+    is_private: bool,
+}
+
+impl RpcTorrent {
+    fn to_torrent(&self) -> Torrent {
+        let status = match self.status {
+            Some(0) | Some(1) | Some(2) => "paused",
+            Some(3) | Some(4) => "downloading",
+            Some(5) | Some(6) => "done",
+            _ => {
+                if self.is_finished == Some(true) {
+                    "done"
+                } else if self.error.is_some() {
+                    "error"
+                } else if self.is_stalled == Some(true) {
+                    "paused"
+                } else {
+                    "downloading"
+                }
+            }
+        };
+
+        Torrent {
+            id: self.id,
+            name: self.name.clone(),
+            hash: self.hash_string.clone(),
+            size: self.total_size,
+            status: status.into(),
+            progress: (self.percent_done * 100.0) as i32,
+            queue_position: self.queue_position,
+            stalled: self.is_stalled == Some(true),
+            error: match self.error {
+                Some(code) if code != 0 => Some(if self.error_string.is_empty() {
+                    format!("Error {}", code)
+                } else {
+                    self.error_string.clone()
+                }),
+                _ => None,
+            },
+            labels: self.labels.clone(),
+            download_dir: self.download_dir.clone(),
+            category: None,
+        }
+    }
+
+    // Transmission's own state names, which tell a hash check apart from a paused torrent
+    fn state(&self) -> &'static str {
+        match self.status {
+            Some(0) => "stopped",
+            Some(1) => "check-wait",
+            Some(2) => "checking",
+            Some(3) => "download-wait",
+            Some(4) => "downloading",
+            Some(5) => "seed-wait",
+            Some(6) => "seeding",
+            _ => "unknown",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct TrackerStat {
+    pub host: String,
+    #[serde(default)]
+    pub last_announce_succeeded: bool,
+    #[serde(default)]
+    pub last_announce_result: String,
+    #[serde(default)]
+    pub next_announce_time: i64,
+    #[serde(default)]
+    pub seeder_count: i64,
+    #[serde(default)]
+    pub leecher_count: i64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TorrentDetail {
+    #[serde(flatten)]
+    pub torrent: Torrent,
+    pub state: String,
+    /// Hash check progress in percent while the torrent is being verified
+    pub verify_progress: Option<i32>,
+    pub rate_download: i64,
+    pub rate_upload: i64,
+    pub eta: Option<i64>,
+    pub upload_ratio: f64,
+    pub peers_connected: i64,
+    pub trackers: Vec<TrackerStat>,
+    /// Whether pieces are downloaded in order
+    pub sequential: bool,
+}
+
+impl From<RpcTorrent> for TorrentDetail {
+    fn from(t: RpcTorrent) -> Self {
+        let state = t.state();
+        Self {
+            torrent: t.to_torrent(),
+            state: state.into(),
+            verify_progress: match state {
+                "checking" => Some((t.recheck_progress * 100.0) as i32),
+                "check-wait" => Some(0),
+                _ => None,
+            },
+            rate_download: t.rate_download,
+            rate_upload: t.rate_upload,
+            eta: Some(t.eta).filter(|eta| *eta >= 0),
+            upload_ratio: t.upload_ratio,
+            peers_connected: t.peers_connected,
+            trackers: t.tracker_stats,
+            sequential: t.sequential_download,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SeedingTorrent {
+    #[serde(flatten)]
+    pub torrent: Torrent,
+    pub ratio: f64,
+    pub seeding_secs: i64,
+    pub idle_secs: i64,
+    pub private: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AddOptions {
+    pub download_dir: Option<String>,
+    pub paused: Option<bool>,
+    pub labels: Option<Vec<String>>,
+    pub peer_limit: Option<i64>,
+    pub bandwidth_priority: Option<i64>,
+    pub files_unwanted: Option<Vec<i64>>,
+    pub seed_ratio_limit: Option<f64>,
+    pub category: Option<String>,
+}
+
+impl AddOptions {
+    pub fn validate(&self, download_dirs: &[String]) -> AppResult<()> {
+        if let Some(dir) = &self.download_dir {
+            if !within_roots(dir, download_dirs) {
+                return Err(invalid(format!("Download dir not allowed: {}", dir)));
+            }
+        }
+        if let Some(labels) = &self.labels {
+            if labels
+                .iter()
+                .any(|l| l.trim().is_empty() || l.contains(','))
+            {
+                return Err(invalid(
+                    "Labels must be non-empty and without commas".into(),
+                ));
+            }
+        }
+        if matches!(self.peer_limit, Some(limit) if limit < 1) {
+            return Err(invalid("Peer limit must be positive".into()));
+        }
+        if matches!(self.bandwidth_priority, Some(p) if !(-1..=1).contains(&p)) {
+            return Err(invalid("Bandwidth priority must be -1, 0 or 1".into()));
+        }
+        if matches!(self.seed_ratio_limit, Some(ratio) if ratio.is_nan() || ratio < 0.0) {
+            return Err(invalid("Seed ratio limit must not be negative".into()));
+        }
+        if let Some(files) = &self.files_unwanted {
+            if files.iter().any(|f| *f < 0) {
+                return Err(invalid("File indexes must not be negative".into()));
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&self, args: &mut Value) {
+        let fields = [
+            ("download-dir", json!(self.download_dir)),
+            ("paused", json!(self.paused)),
+            ("labels", json!(self.labels)),
+            ("peer-limit", json!(self.peer_limit)),
+            ("bandwidthPriority", json!(self.bandwidth_priority)),
+            ("files-unwanted", json!(self.files_unwanted)),
+        ];
+        for (key, value) in fields.iter().filter(|(_, v)| !v.is_null()) {
+            args[*key] = value.clone();
+        }
+    }
+}
+
+fn invalid(msg: String) -> AppError {
+    AppError::ValidationError(msg)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AddedTorrent {
+    pub id: i64,
+    pub name: String,
+    #[serde(rename(deserialize = "hashString"))]
+    pub hash: String,
+    #[serde(default)]
+    pub duplicate: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct AddReply {
+    #[serde(rename = "torrent-added")]
+    added: Option<AddedTorrent>,
+    #[serde(rename = "torrent-duplicate")]
+    duplicate: Option<AddedTorrent>,
+}
+
+#[derive(Deserialize, Debug)]
+struct FreeSpace {
+    #[serde(rename = "size-bytes")]
+    size_bytes: u64,
 }
 
 pub struct Client {
     client: TransClient,
+    rpc: Rpc,
 }
 
 impl Client {
-    pub fn new(client: TransClient) -> Self {
-        Self { client }
+    pub fn new(client: TransClient, rpc: Rpc) -> Self {
+        Self { client, rpc }
     }
 
     pub async fn list_torrents(&self) -> Result<Vec<Torrent>> {
+        let torrents = self.get_torrents(None, &[]).await?;
+        Ok(torrents.iter().map(RpcTorrent::to_torrent).collect())
+    }
+
+    pub async fn torrent_detail(&self, id: i64) -> Result<Option<TorrentDetail>> {
+        let extra = [
+            "recheckProgress",
+            "rateDownload",
+            "rateUpload",
+            "eta",
+            "uploadRatio",
+            "peersConnected",
+            "trackerStats",
+            "sequential_download",
+        ];
+        let torrents = self.get_torrents(Some(id), &extra).await?;
+        Ok(torrents.into_iter().next().map(TorrentDetail::from))
+    }
+
+    /// Torrents with the seeding figures the policy engine works on.
+    pub async fn seeding_torrents(&self) -> Result<Vec<SeedingTorrent>> {
+        let extra = ["uploadRatio", "secondsSeeding", "activityDate", "isPrivate"];
+        let now = chrono::Utc::now().timestamp();
+        let torrents = self.get_torrents(None, &extra).await?;
+        Ok(torrents
+            .iter()
+            .map(|t| SeedingTorrent {
+                torrent: t.to_torrent(),
+                ratio: t.upload_ratio,
+                seeding_secs: t.seconds_seeding,
+                idle_secs: (now - t.activity_date).max(0),
+                private: t.is_private,
+            })
+            .collect())
+    }
+
+    async fn get_torrents(&self, id: Option<i64>, extra: &[&str]) -> Result<Vec<RpcTorrent>> {
+        let mut fields = vec![
+            "id",
+            "name",
+            "hashString",
+            "status",
+            "isFinished",
+            "isStalled",
+            "totalSize",
+            "percentDone",
+            "queuePosition",
+            "error",
+            "errorString",
+            "labels",
+            "downloadDir",
+        ];
+        fields.extend_from_slice(extra);
+
+        let mut args = json!({ "fields": fields });
+        if let Some(id) = id {
+            args["ids"] = json!([id]);
+        }
+        let res: TorrentList<RpcTorrent> = self.rpc.call("torrent-get", args).await?;
+        Ok(res.torrents)
+    }
+
+    pub async fn add_torrent(&self, url: &str, options: &AddOptions) -> Result<AddedTorrent> {
+        self.add(json!({ "filename": url }), options).await
+    }
+
+    pub async fn add_metainfo(
+        &self,
+        metainfo: &[u8],
+        options: &AddOptions,
+    ) -> Result<AddedTorrent> {
+        let args = json!({ "metainfo": base64::encode(metainfo) });
+        self.add(args, options).await
+    }
+
+    async fn add(&self, mut args: Value, options: &AddOptions) -> Result<AddedTorrent> {
+        options.apply(&mut args);
+        let reply: AddReply = self.rpc.call("torrent-add", args).await?;
+
+        let added = match (reply.added, reply.duplicate) {
+            (Some(added), _) => added,
+            (None, Some(duplicate)) => AddedTorrent {
+                duplicate: true,
+                ..duplicate
+            },
+            (None, None) => return Err("torrent-add returned no torrent".into()),
+        };
+
+        if let (Some(ratio), false) = (options.seed_ratio_limit, added.duplicate) {
+            self.set_seed_ratio(added.id, ratio).await?;
+        }
+        Ok(added)
+    }
+
+    /// Downloaded pieces and file layout of a torrent, for streaming it while it downloads.
+    pub async fn piece_map(&self, id: i64) -> Result<Option<PieceMap>> {
+        let args = json!({ "ids": [id], "fields": PieceMap::FIELDS });
+        let res: TorrentList<PieceMap> = self.rpc.call("torrent-get", args).await?;
+        Ok(res.torrents.into_iter().next())
+    }
+
+    pub async fn rpc_version(&self) -> Result<i64> {
+        let reply: Value = self
+            .rpc
+            .call("session-get", json!({ "fields": ["rpc-version"] }))
+            .await?;
+        Ok(reply["rpc-version"].as_i64().unwrap_or(0))
+    }
+
+    /// Downloads the pieces in order, only Transmission 4.1 and later know about it.
+    pub async fn set_sequential(&self, id: i64, enabled: bool) -> Result<()> {
+        let args = json!({ "ids": [id], "sequential_download": enabled });
+        let _: Value = self.rpc.call("torrent-set", args).await?;
+        Ok(())
+    }
+
+    /// Sets the priority of some files of a torrent, by index.
+    pub async fn set_file_priority(&self, id: i64, files: &[usize], high: bool) -> Result<()> {
+        let key = if high { "priority-high" } else { "priority-normal" };
+        let mut args = json!({ "ids": [id] });
+        args[key] = json!(files);
+        let _: Value = self.rpc.call("torrent-set", args).await?;
+        Ok(())
+    }
+
+    pub async fn set_seed_ratio(&self, id: i64, ratio: f64) -> Result<()> {
+        let args = json!({ "ids": [id], "seedRatioLimit": ratio, "seedRatioMode": 1 });
+        let _: Value = self.rpc.call("torrent-set", args).await?;
+        Ok(())
+    }
+
+    pub async fn has_torrent(&self, hash: &str) -> Result<bool> {
         let res = self
             .client
             .torrent_get(
-                Some(vec![
-                    TorrentGetField::Id,
-                    TorrentGetField::Name,
-                    TorrentGetField::Status,
-                    TorrentGetField::Isfinished,
-                    TorrentGetField::Isstalled,
-                    TorrentGetField::Totalsize,
-                    TorrentGetField::Percentdone,
-                    TorrentGetField::Error,
-                ]),
-                None,
+                Some(vec![TorrentGetField::Id]),
+                Some(vec![Id::Hash(hash.into())]),
             )
             .await?;
-        let torrents = res.arguments.torrents;
-        Ok(torrents
-            .iter()
-            .map(|t| {
-                let status = match t.status {
-                    Some(0) | Some(1) | Some(2) => "paused",
-                    Some(3) | Some(4) => "downloading",
-                    Some(5) | Some(6) => "done",
-                    _ => {
-                        if t.is_finished.is_some() && t.is_finished.unwrap() {
-                            "done"
-                        } else if t.error.is_some() {
-                            "error"
-                        } else if t.is_stalled.is_some() && t.is_stalled.unwrap() {
-                            "paused"
-                        } else {
-                            "downloading"
-                        }
-                    }
-                };
-
-                Torrent {
-                    id: t.id.unwrap(),
-                    name: t.name.clone().unwrap(),
-                    size: t.total_size.unwrap(),
-                    status: status.into(),
-                    progress: (t.percent_done.unwrap() * 100.0) as i32,
-                }
-            })
-            .collect())
+        Ok(!res.arguments.torrents.is_empty())
+    }
+
+    pub async fn remove_torrent(
+        &self,
+        id: i64,
+        delete_data: bool,
+    ) -> Result<RpcResponse<Nothing>> {
+        self.client.torrent_remove(vec![Id::Id(id)], delete_data).await
     }
 
-    pub async fn add_torrent(&self, url: &str) -> Result<RpcResponse<TorrentAdded>> {
-        let add = TorrentAddArgs {
-            filename: Some(url.into()),
-            ..TorrentAddArgs::default()
+    /// Runs one action over all ids in a single RPC call.
+    pub async fn batch_action(
+        &self,
+        action: BatchAction,
+        ids: Vec<i64>,
+    ) -> Result<RpcResponse<Nothing>> {
+        let ids = ids.into_iter().map(Id::Id).collect();
+        let action = match action {
+            BatchAction::Remove => return self.client.torrent_remove(ids, false).await,
+            BatchAction::RemoveWithData => return self.client.torrent_remove(ids, true).await,
+            BatchAction::Start => TorrentAction::Start,
+            BatchAction::Stop => TorrentAction::Stop,
+            BatchAction::StartNow => TorrentAction::StartNow,
+            BatchAction::Verify => TorrentAction::Verify,
+            BatchAction::Reannounce => TorrentAction::Reannounce,
         };
+        self.client.torrent_action(action, ids).await
+    }
 
-        self.client.torrent_add(add).await
+    /// Points the torrent at `location`, moving the data there first when `move_data` is set.
+    pub async fn set_location(
+        &self,
+        id: i64,
+        location: &str,
+        move_data: bool,
+    ) -> Result<RpcResponse<Nothing>> {
+        self.client
+            .torrent_set_location(vec![Id::Id(id)], location.to_string(), Some(move_data))
+            .await
     }
 
-    pub async fn remove_torrent(&self, id: i64) -> Result<RpcResponse<Nothing>> {
-        self.client.torrent_remove(vec![Id::Id(id)], true).await
+    /// Starts a hash check of the downloaded data.
+    pub async fn verify_torrents(&self, ids: Vec<i64>) -> Result<RpcResponse<Nothing>> {
+        self.batch_action(BatchAction::Verify, ids).await
+    }
+
+    /// Asks the trackers for more peers right away.
+    pub async fn reannounce_torrents(&self, ids: Vec<i64>) -> Result<RpcResponse<Nothing>> {
+        self.batch_action(BatchAction::Reannounce, ids).await
+    }
+
+    pub async fn queue_move(&self, ids: Vec<i64>, direction: QueueMove) -> Result<()> {
+        let _: Value = self.rpc.call(direction.method(), json!({ "ids": ids })).await?;
+        Ok(())
+    }
+
+    pub async fn queue_settings(&self) -> Result<QueueSettings> {
+        let args = json!({ "fields": QueueSettings::FIELDS });
+        let reply: Value = self.rpc.call("session-get", args).await?;
+        Ok(session::from_rpc(reply)?)
+    }
+
+    pub async fn set_queue_settings(&self, settings: &QueueSettings) -> Result<()> {
+        let _: Value = self.rpc.call("session-set", session::to_rpc(settings)?).await?;
+        Ok(())
+    }
+
+    pub async fn session_settings(&self) -> Result<SessionSettings> {
+        let reply: Value = self.rpc.call("session-get", json!({})).await?;
+        Ok(session::from_rpc(reply)?)
+    }
+
+    pub async fn set_session_settings(&self, settings: &SessionSettings) -> Result<()> {
+        let _: Value = self.rpc.call("session-set", session::to_rpc(settings)?).await?;
+        Ok(())
+    }
+
+    pub async fn session_stats(&self) -> Result<SessionStats> {
+        Ok(self.rpc.call("session-stats", json!({})).await?)
+    }
+
+    /// Free bytes in `path` as seen by the Transmission daemon.
+    pub async fn free_space(&self, path: &str) -> Result<u64> {
+        let reply: FreeSpace = self.rpc.call("free-space", json!({ "path": path })).await?;
+        Ok(reply.size_bytes)
     }
 
     pub async fn pause_torrent(&self, id: i64) -> Result<RpcResponse<Nothing>> {
@@ -109,9 +567,16 @@ mod tests {
         let torrent = Torrent {
             id: 1,
             name: "Test Movie".to_string(),
+            hash: "0123456789abcdef0123456789abcdef01234567".to_string(),
             status: "downloading".to_string(),
             size: 1000000000, // 1GB
             progress: 50,
+            queue_position: 0,
+            stalled: false,
+            error: None,
+            labels: vec![],
+            download_dir: "/downloads".to_string(),
+            category: None,
         };
 
         assert_eq!(torrent.id, 1);
@@ -126,9 +591,16 @@ mod tests {
         let torrent = Torrent {
             id: 1,
             name: "Test Movie".to_string(),
+            hash: "0123456789abcdef0123456789abcdef01234567".to_string(),
             status: "downloading".to_string(),
             size: 1000000000,
             progress: 50,
+            queue_position: 0,
+            stalled: false,
+            error: None,
+            labels: vec!["movies".to_string()],
+            download_dir: "/downloads".to_string(),
+            category: Some("movies".to_string()),
         };
 
         let cloned = torrent.clone();
@@ -144,9 +616,16 @@ mod tests {
         let torrent = Torrent {
             id: 1,
             name: "Test Movie".to_string(),
+            hash: "0123456789abcdef0123456789abcdef01234567".to_string(),
             status: "downloading".to_string(),
             size: 1000000000,
             progress: 50,
+            queue_position: 0,
+            stalled: false,
+            error: None,
+            labels: vec!["movies".to_string()],
+            download_dir: "/downloads".to_string(),
+            category: Some("movies".to_string()),
         };
 
         // Test that the torrent can be serialized to JSON
@@ -164,6 +643,90 @@ mod tests {
         assert_eq!(torrent.status, deserialized_torrent.status);
         assert_eq!(torrent.size, deserialized_torrent.size);
         assert_eq!(torrent.progress, deserialized_torrent.progress);
+        assert_eq!(torrent.labels, deserialized_torrent.labels);
+        assert_eq!(torrent.category, deserialized_torrent.category);
+    }
+
+    #[test]
+    fn test_torrent_detail_verify_progress() {
+        let rpc: RpcTorrent = serde_json::from_value(json!({
+            "id": 3,
+            "name": "Test Movie",
+            "hashString": "0123456789abcdef0123456789abcdef01234567",
+            "status": 2,
+            "percentDone": 0.5,
+            "recheckProgress": 0.25,
+            "eta": -1,
+            "errorString": "",
+            "trackerStats": [{ "host": "tracker.example.org:443", "lastAnnounceSucceeded": true }]
+        }))
+        .unwrap();
+
+        let detail = TorrentDetail::from(rpc);
+        assert_eq!(detail.torrent.status, "paused");
+        assert_eq!(detail.state, "checking");
+        assert_eq!(detail.verify_progress, Some(25));
+        assert_eq!(detail.eta, None);
+        assert_eq!(detail.torrent.error, None);
+        assert!(detail.trackers[0].last_announce_succeeded);
+    }
+
+    #[test]
+    fn test_add_options_validation() {
+        let dirs = vec!["/mnt/usb/Movies".to_string(), "/mnt/usb/TV".to_string()];
+
+        let options = AddOptions {
+            download_dir: Some("/mnt/usb/TV/Show".to_string()),
+            paused: Some(true),
+            labels: Some(vec!["tv".to_string()]),
+            peer_limit: Some(50),
+            bandwidth_priority: Some(1),
+            files_unwanted: Some(vec![0, 2]),
+            seed_ratio_limit: Some(2.0),
+            category: None,
+        };
+        assert!(options.validate(&dirs).is_ok());
+        assert!(AddOptions::default().validate(&[]).is_ok());
+
+        let outside = AddOptions {
+            download_dir: Some("/home/pi".to_string()),
+            ..AddOptions::default()
+        };
+        assert!(outside.validate(&dirs).is_err());
+
+        let bad_label = AddOptions {
+            labels: Some(vec!["a,b".to_string()]),
+            ..AddOptions::default()
+        };
+        assert!(bad_label.validate(&dirs).is_err());
+
+        let bad_priority = AddOptions {
+            bandwidth_priority: Some(5),
+            ..AddOptions::default()
+        };
+        assert!(bad_priority.validate(&dirs).is_err());
+    }
+
+    #[test]
+    fn test_add_options_apply() {
+        let options = AddOptions {
+            download_dir: Some("/mnt/usb/Movies".to_string()),
+            labels: Some(vec!["movies".to_string()]),
+            seed_ratio_limit: Some(2.0),
+            category: Some("movies".to_string()),
+            ..AddOptions::default()
+        };
+        let mut args = json!({ "filename": "magnet:?xt=urn:btih:abc" });
+        options.apply(&mut args);
+
+        assert_eq!(
+            args,
+            json!({
+                "filename": "magnet:?xt=urn:btih:abc",
+                "download-dir": "/mnt/usb/Movies",
+                "labels": ["movies"],
+            })
+        );
     }
 
     // Note: The Client struct methods require an actual TransClient which needs 
@@ -178,8 +741,9 @@ mod tests {
         // or a mocked transmission client
         
         use transmission_rpc::TransClient;
-        let trans_client = TransClient::new("http://localhost:9091/transmission/rpc");
-        let _client = Client::new(trans_client);
+        let url = "http://localhost:9091/transmission/rpc";
+        let trans_client = TransClient::new(url);
+        let _client = Client::new(trans_client, Rpc::new(url));
         
         // Just verify the client was created - we can't test the methods without
         // a running transmission daemon or proper mocking
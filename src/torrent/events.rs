@@ -0,0 +1,370 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::sync::{self, broadcast};
+
+use super::{
+    category::{self, Category},
+    client::{Client, Torrent},
+};
+
+/// How often the backend is polled while a listener wants live updates.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How often it is polled otherwise, which bounds how late events are.
+pub const EVENT_INTERVAL: Duration = Duration::from_secs(10);
+
+const RECENT_EVENTS: usize = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Added,
+    Started,
+    Paused,
+    Completed,
+    Errored,
+    Removed,
+    Stalled,
+}
+
+impl EventKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::Added => "added",
+            EventKind::Started => "started",
+            EventKind::Paused => "paused",
+            EventKind::Completed => "completed",
+            EventKind::Errored => "errored",
+            EventKind::Removed => "removed",
+            EventKind::Stalled => "stalled",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TorrentEvent {
+    pub event: EventKind,
+    /// The torrent as it was seen when the event was detected, the last known state if removed
+    pub torrent: Torrent,
+    pub time: String,
+}
+
+impl TorrentEvent {
+    fn new(event: EventKind, torrent: &Torrent) -> Self {
+        Self {
+            event,
+            torrent: torrent.clone(),
+            time: chrono::Local::now().to_rfc3339(),
+        }
+    }
+
+    /// Values for "{{field}}" placeholders in templates: the torrent's fields, `event` and `time`.
+    pub fn placeholders(&self) -> Map<String, Value> {
+        let mut fields = match serde_json::to_value(&self.torrent) {
+            Ok(Value::Object(map)) => map,
+            _ => Map::new(),
+        };
+        fields.insert("event".into(), self.event.name().into());
+        fields.insert("time".into(), self.time.clone().into());
+        fields
+    }
+}
+
+/// Replaces every known "{{field}}" in `text`, unknown ones are left alone.
+pub fn fill(text: &str, fields: &Map<String, Value>) -> String {
+    let mut text = text.to_string();
+    for (name, value) in fields {
+        let replacement = match value {
+            Value::String(s) => s.clone(),
+            Value::Null => String::new(),
+            other => other.to_string(),
+        };
+        text = text.replace(&format!("{{{{{}}}}}", name), &replacement);
+    }
+    text
+}
+
+/// Lifecycle transitions between two snapshots of the torrent list.
+pub fn detect(old: &[Torrent], new: &[Torrent]) -> Vec<TorrentEvent> {
+    let mut events = Vec::new();
+
+    for torrent in new {
+        let previous = match old.iter().find(|t| t.id == torrent.id) {
+            Some(previous) => previous,
+            None => {
+                events.push(TorrentEvent::new(EventKind::Added, torrent));
+                continue;
+            }
+        };
+
+        let was_paused = previous.status == "paused";
+        let is_paused = torrent.status == "paused";
+        if was_paused && !is_paused {
+            events.push(TorrentEvent::new(EventKind::Started, torrent));
+        }
+        if !was_paused && is_paused {
+            events.push(TorrentEvent::new(EventKind::Paused, torrent));
+        }
+        if previous.progress < 100 && torrent.progress >= 100 {
+            events.push(TorrentEvent::new(EventKind::Completed, torrent));
+        }
+        if previous.error.is_none() && torrent.error.is_some() {
+            events.push(TorrentEvent::new(EventKind::Errored, torrent));
+        }
+        if !previous.stalled && torrent.stalled {
+            events.push(TorrentEvent::new(EventKind::Stalled, torrent));
+        }
+    }
+
+    for torrent in old {
+        if !new.iter().any(|t| t.id == torrent.id) {
+            events.push(TorrentEvent::new(EventKind::Removed, torrent));
+        }
+    }
+    events
+}
+
+/// Gets every snapshot as soon as it is taken, along with the events found in it.
+/// Called under the bus lock, so listeners see snapshots in order and must not block.
+pub trait Listener: Send + Sync {
+    /// Whether snapshots are needed every `POLL_INTERVAL` right now
+    fn live(&self) -> bool;
+    fn update(&self, torrents: &[Torrent], events: &[TorrentEvent]);
+}
+
+struct BusState {
+    torrents: Option<Vec<Torrent>>,
+    updated: Option<Instant>,
+    recent: VecDeque<TorrentEvent>,
+}
+
+/// Polls the torrent list in one place and publishes what happened to it,
+/// so features react to events instead of polling the backend themselves.
+pub struct EventBus {
+    categories: Vec<Category>,
+    listeners: Vec<Arc<dyn Listener>>,
+    state: Mutex<BusState>,
+    // Held from listing to publishing, so a slow list cannot overwrite a newer one
+    refreshing: sync::Mutex<()>,
+    sender: broadcast::Sender<TorrentEvent>,
+}
+
+impl EventBus {
+    pub fn new(categories: Vec<Category>, listeners: Vec<Arc<dyn Listener>>) -> Self {
+        let (sender, _) = broadcast::channel(64);
+        Self {
+            categories,
+            listeners,
+            state: Mutex::new(BusState {
+                torrents: None,
+                updated: None,
+                recent: VecDeque::new(),
+            }),
+            refreshing: sync::Mutex::new(()),
+            sender,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<TorrentEvent> {
+        self.sender.subscribe()
+    }
+
+    /// The latest events, oldest first.
+    pub fn recent(&self) -> Vec<TorrentEvent> {
+        self.state.lock().unwrap().recent.iter().cloned().collect()
+    }
+
+    /// The latest snapshot, none before the first one was taken.
+    pub fn torrents(&self) -> Option<Vec<Torrent>> {
+        self.state.lock().unwrap().torrents.clone()
+    }
+
+    /// Whether the last snapshot is recent enough to serve as live data.
+    pub fn fresh(&self) -> bool {
+        matches!(
+            self.state.lock().unwrap().updated,
+            Some(updated) if updated.elapsed() < POLL_INTERVAL
+        )
+    }
+
+    /// Called every `POLL_INTERVAL`, only asks the backend that often while a listener is live.
+    pub async fn poll(&self, client: &Client) {
+        let due = !matches!(
+            self.state.lock().unwrap().updated,
+            Some(updated) if updated.elapsed() < EVENT_INTERVAL
+        );
+        if due || self.listeners.iter().any(|l| l.live()) {
+            self.refresh(client).await;
+        }
+    }
+
+    pub async fn refresh(&self, client: &Client) {
+        let _refreshing = self.refreshing.lock().await;
+        let mut torrents = match client.list_torrents().await {
+            Ok(list) => list,
+            Err(e) => {
+                error!("Could not list torrents for events: {}", e);
+                return;
+            }
+        };
+        for t in torrents.iter_mut() {
+            t.category = category::categorize(&self.categories, t).map(|c| c.name.clone());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        // The first snapshot is only a baseline, everything in it would look added
+        let events = match &state.torrents {
+            Some(old) => detect(old, &torrents),
+            None => Vec::new(),
+        };
+        for listener in &self.listeners {
+            listener.update(&torrents, &events);
+        }
+        for event in events {
+            state.recent.push_back(event.clone());
+            if state.recent.len() > RECENT_EVENTS {
+                state.recent.pop_front();
+            }
+            let _ = self.sender.send(event);
+        }
+        state.torrents = Some(torrents);
+        state.updated = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{self, http_server},
+        torrent::rpc::Rpc,
+    };
+    use serde_json::json;
+    use transmission_rpc::TransClient;
+
+    fn torrent(id: i64, status: &str, progress: i32) -> Torrent {
+        Torrent {
+            id,
+            name: format!("Torrent {}", id),
+            hash: format!("{:040x}", id),
+            status: status.to_string(),
+            progress,
+            ..testing::torrent()
+        }
+    }
+
+    struct Recorder(Mutex<Vec<(Vec<i32>, Vec<TorrentEvent>)>>);
+
+    impl Listener for Recorder {
+        fn live(&self) -> bool {
+            false
+        }
+
+        fn update(&self, torrents: &[Torrent], events: &[TorrentEvent]) {
+            let progress = torrents.iter().map(|t| t.progress).collect();
+            self.0.lock().unwrap().push((progress, events.to_vec()));
+        }
+    }
+
+    #[tokio::test]
+    async fn refreshes_apply_in_order() {
+        let reply = |percent: f64| {
+            let torrents = json!([{ "id": 1, "name": "Movie", "percentDone": percent }]);
+            let reply = json!({ "result": "success", "arguments": { "torrents": torrents } });
+            reply.to_string()
+        };
+        let (url, _) = http_server(vec![(200, reply(0.2)), (200, reply(0.5)), (200, reply(1.0))]);
+        let client = Client::new(TransClient::new(&url), Rpc::new(&url));
+        let recorder = Arc::new(Recorder(Mutex::new(Vec::new())));
+        let bus = EventBus::new(vec![], vec![recorder.clone()]);
+
+        // Each refresh lists only after the one before it was published
+        futures::join!(bus.refresh(&client), bus.refresh(&client), bus.refresh(&client));
+
+        let updates = recorder.0.lock().unwrap();
+        let progress: Vec<_> = updates.iter().map(|(p, _)| p.clone()).collect();
+        assert_eq!(progress, vec![vec![20], vec![50], vec![100]]);
+        assert_eq!(kinds(&updates[2].1), vec![(1, EventKind::Completed)]);
+        assert_eq!(bus.torrents().unwrap()[0].progress, 100);
+    }
+
+    fn kinds(events: &[TorrentEvent]) -> Vec<(i64, EventKind)> {
+        events.iter().map(|e| (e.torrent.id, e.event)).collect()
+    }
+
+    #[test]
+    fn detect_transitions() {
+        let mut errored = torrent(4, "downloading", 30);
+        errored.error = Some("Tracker gave HTTP 404".to_string());
+        let mut stalled = torrent(5, "downloading", 60);
+        stalled.stalled = true;
+
+        let old = vec![
+            torrent(1, "paused", 10),
+            torrent(2, "downloading", 99),
+            torrent(3, "downloading", 50),
+            torrent(4, "downloading", 30),
+            torrent(5, "downloading", 60),
+            torrent(6, "done", 100),
+        ];
+        let new = vec![
+            torrent(1, "downloading", 10),
+            torrent(2, "done", 100),
+            torrent(3, "paused", 50),
+            errored,
+            stalled,
+            torrent(7, "downloading", 0),
+        ];
+
+        assert_eq!(
+            kinds(&detect(&old, &new)),
+            vec![
+                (1, EventKind::Started),
+                (2, EventKind::Completed),
+                (3, EventKind::Paused),
+                (4, EventKind::Errored),
+                (5, EventKind::Stalled),
+                (7, EventKind::Added),
+                (6, EventKind::Removed),
+            ]
+        );
+    }
+
+    #[test]
+    fn detect_nothing_when_unchanged() {
+        let list = vec![torrent(1, "downloading", 10), torrent(2, "done", 100)];
+        assert!(detect(&list, &list).is_empty());
+
+        // Progress alone is not a lifecycle event
+        let later = vec![torrent(1, "downloading", 20), torrent(2, "done", 100)];
+        assert!(detect(&list, &later).is_empty());
+    }
+
+    #[test]
+    fn events_serialize_lowercase() {
+        let event = TorrentEvent::new(EventKind::Completed, &torrent(1, "done", 100));
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["event"], "completed");
+        assert_eq!(json["torrent"]["id"], 1);
+
+        let kind: EventKind = serde_json::from_str("\"stalled\"").unwrap();
+        assert_eq!(kind, EventKind::Stalled);
+        assert_eq!(json["event"], EventKind::Completed.name());
+    }
+
+    #[test]
+    fn fill_placeholders() {
+        let event = TorrentEvent::new(EventKind::Errored, &torrent(3, "paused", 40));
+        let text = fill(
+            "{{name}} {{event}} at {{progress}}%, {{category}}{{nope}}",
+            &event.placeholders(),
+        );
+        assert_eq!(text, "Torrent 3 errored at 40%, {{nope}}");
+    }
+}
@@ -0,0 +1,596 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Mutex,
+    time::Duration,
+};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use super::pipeline::{blocking, last_line, StepState};
+use crate::error::{AppError, AppResult};
+
+/// How often the size of a running extraction is measured.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Files are unpacked here first and moved next to the archive once the tool succeeded.
+const STAGING_PREFIX: &str = ".extracting-";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveKind {
+    Rar,
+    Zip,
+    SevenZip,
+}
+
+/// The volumes of one archive, the first one is what the tools are given.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveSet {
+    pub kind: ArchiveKind,
+    pub volumes: Vec<PathBuf>,
+    /// Whether the volume the tools start from is there
+    pub complete: bool,
+}
+
+impl ArchiveSet {
+    pub fn first(&self) -> &Path {
+        &self.volumes[0]
+    }
+}
+
+// The set a file belongs to and its position in it, "movie.rar" comes before "movie.r00"
+fn volume(path: &Path) -> Option<(ArchiveKind, String, u32)> {
+    let name = path.file_name()?.to_string_lossy().to_lowercase();
+    let (stem, ext) = name.rsplit_once('.')?;
+    let number = |digits: &str| -> Option<u32> {
+        Some(digits)
+            .filter(|d| !d.is_empty() && d.chars().all(|c| c.is_ascii_digit()))?
+            .parse()
+            .ok()
+    };
+
+    match ext {
+        "rar" => match stem
+            .rsplit_once(".part")
+            .and_then(|(s, n)| Some((s, number(n)?)))
+        {
+            // "name.part01.rar" style sets start at part 1
+            Some((set, part)) => Some((ArchiveKind::Rar, set.to_string(), part)),
+            None => Some((ArchiveKind::Rar, stem.to_string(), 1)),
+        },
+        "zip" => Some((ArchiveKind::Zip, stem.to_string(), 1)),
+        "7z" => Some((ArchiveKind::SevenZip, stem.to_string(), 1)),
+        _ if ext.len() != 3 || !ext.is_ascii() => None,
+        _ => {
+            if let Some(set) = stem.strip_suffix(".7z") {
+                return Some((ArchiveKind::SevenZip, set.to_string(), number(ext)?));
+            }
+            let (kind, digits) = match ext.split_at(1) {
+                ("r", digits) | ("s", digits) => (ArchiveKind::Rar, digits),
+                ("z", digits) => (ArchiveKind::Zip, digits),
+                _ => return None,
+            };
+            // Old style rar sets go .r00 to .r99, then .s00
+            let offset = if ext.starts_with('s') { 100 } else { 0 };
+            number(digits)
+                .filter(|_| digits.len() == 2)
+                .map(|n| (kind, stem.to_string(), n + offset + 2))
+        }
+    }
+}
+
+fn is_staging(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|n| n.to_string_lossy().starts_with(STAGING_PREFIX))
+}
+
+fn collect(path: &Path, found: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        found.push(path.to_path_buf());
+        return Ok(());
+    }
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if !is_staging(&path) {
+            collect(&path, found)?;
+        }
+    }
+    Ok(())
+}
+
+/// The archive sets below `path`, grouped by folder, name and kind.
+pub fn archive_sets(path: &Path) -> io::Result<Vec<ArchiveSet>> {
+    let mut files = Vec::new();
+    collect(path, &mut files)?;
+
+    let mut sets: BTreeMap<(PathBuf, String, ArchiveKind), Vec<(u32, PathBuf)>> = BTreeMap::new();
+    for file in files {
+        if let Some((kind, name, part)) = volume(&file) {
+            let dir = file.parent().map(Path::to_path_buf).unwrap_or_default();
+            sets.entry((dir, name, kind))
+                .or_default()
+                .push((part, file));
+        }
+    }
+
+    Ok(sets
+        .into_iter()
+        .map(|((_, _, kind), mut volumes)| {
+            volumes.sort();
+            ArchiveSet {
+                kind,
+                complete: volumes[0].0 == 1,
+                volumes: volumes.into_iter().map(|(_, path)| path).collect(),
+            }
+        })
+        .collect())
+}
+
+/// The extraction of one archive set of a torrent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Extraction {
+    pub archive: String,
+    pub volumes: usize,
+    /// Size of all volumes
+    pub size: u64,
+    /// Bytes unpacked so far
+    pub extracted: u64,
+    /// Folder the files are unpacked into
+    pub target: String,
+    pub state: StepState,
+    pub error: Option<String>,
+    /// Unpacked files, deleted again when the torrent is removed
+    pub files: Vec<String>,
+}
+
+/// Unpacks archive sets with `unrar`, `unzip` and `7z`, keeping track of what was written
+/// so it can be cleaned up with the torrent.
+pub struct Extractor {
+    path: PathBuf,
+    torrents: Mutex<HashMap<i64, Vec<Extraction>>>,
+}
+
+impl Extractor {
+    pub fn load(path: PathBuf) -> Self {
+        let mut torrents: HashMap<i64, Vec<Extraction>> = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                error!("Invalid extraction file {}: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        for extraction in torrents.values_mut().flatten() {
+            if extraction.state == StepState::Running {
+                extraction.state = StepState::Failed;
+                extraction.error = Some("Interrupted by a restart".to_string());
+            }
+        }
+        Self {
+            path,
+            torrents: Mutex::new(torrents),
+        }
+    }
+
+    pub fn all(&self) -> BTreeMap<i64, Vec<Extraction>> {
+        self.torrents
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, list)| (*id, list.clone()))
+            .collect()
+    }
+
+    pub fn get(&self, id: i64) -> Option<Vec<Extraction>> {
+        self.torrents.lock().unwrap().get(&id).cloned()
+    }
+
+    fn save(&self, torrents: &HashMap<i64, Vec<Extraction>>) {
+        let saved = self
+            .path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| {
+                let json = serde_json::to_string_pretty(torrents).unwrap_or_default();
+                fs::write(&self.path, json)
+            });
+        if let Err(e) = saved {
+            error!("Could not save {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn update<F: FnOnce(&mut Extraction)>(&self, id: i64, archive: &str, save: bool, f: F) {
+        let mut torrents = self.torrents.lock().unwrap();
+        if let Some(extraction) = torrents
+            .get_mut(&id)
+            .and_then(|list| list.iter_mut().find(|e| e.archive == archive))
+        {
+            f(extraction);
+        }
+        if save {
+            self.save(&torrents);
+        }
+    }
+
+    /// Unpacks the archive sets below `source` next to the archives, or below `target`
+    /// keeping their folders. Sets unpacked before are skipped, so a retry only redoes
+    /// the failed ones. Returns how many sets were unpacked.
+    pub async fn extract(&self, id: i64, source: &Path, target: Option<&Path>) -> AppResult<usize> {
+        let root = source.to_path_buf();
+        let sets = blocking(move || archive_sets(&root)).await?;
+
+        let mut pending = Vec::new();
+        {
+            let mut torrents = self.torrents.lock().unwrap();
+            let previous = torrents.remove(&id).unwrap_or_default();
+            let mut list = Vec::new();
+            for set in &sets {
+                let archive = set.first().to_string_lossy().into_owned();
+                if let Some(done) = previous
+                    .iter()
+                    .find(|e| e.archive == archive && e.state == StepState::Done)
+                {
+                    list.push(done.clone());
+                    continue;
+                }
+                let dir = set.first().parent().unwrap_or(source);
+                let into = match target {
+                    Some(target) => target.join(dir.strip_prefix(source).unwrap_or(Path::new(""))),
+                    None => dir.to_path_buf(),
+                };
+                list.push(Extraction {
+                    archive,
+                    volumes: set.volumes.len(),
+                    size: set
+                        .volumes
+                        .iter()
+                        .filter_map(|v| v.metadata().ok())
+                        .map(|m| m.len())
+                        .sum(),
+                    extracted: 0,
+                    target: into.to_string_lossy().into_owned(),
+                    state: StepState::Pending,
+                    error: None,
+                    files: Vec::new(),
+                });
+                pending.push((set.clone(), into));
+            }
+            torrents.insert(id, list);
+            self.save(&torrents);
+        }
+
+        let mut failed = 0;
+        for (set, into) in &pending {
+            let archive = set.first().to_string_lossy().into_owned();
+            self.update(id, &archive, true, |e| e.state = StepState::Running);
+            let result = if set.complete {
+                self.unpack(id, set, into).await
+            } else {
+                Err(AppError::ValidationError(format!(
+                    "The first volume of {} is missing",
+                    archive
+                )))
+            };
+            match result {
+                Ok(files) => {
+                    info!("Extracted {} files from {}", files.len(), archive);
+                    self.update(id, &archive, true, |e| {
+                        e.state = StepState::Done;
+                        e.files = files;
+                    });
+                }
+                Err(e) => {
+                    error!("Could not extract {}: {}", archive, e);
+                    failed += 1;
+                    self.update(id, &archive, true, |ex| {
+                        ex.state = StepState::Failed;
+                        ex.error = Some(e.to_string());
+                    });
+                }
+            }
+        }
+
+        if failed > 0 {
+            return Err(AppError::ValidationError(format!(
+                "{} of {} archives could not be extracted",
+                failed,
+                pending.len()
+            )));
+        }
+        Ok(pending.len())
+    }
+
+    async fn unpack(&self, id: i64, set: &ArchiveSet, into: &Path) -> AppResult<Vec<String>> {
+        let archive = set.first().to_string_lossy().into_owned();
+        let name = set
+            .first()
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let staging = into.join(format!("{}{}", STAGING_PREFIX, name));
+        let dir = staging.clone();
+        blocking(move || {
+            if dir.exists() {
+                fs::remove_dir_all(&dir)?;
+            }
+            fs::create_dir_all(&dir)
+        })
+        .await?;
+
+        let mut command = tool(set.kind, set.first(), &staging);
+        let child = command
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| {
+                AppError::IoError(io::Error::new(
+                    e.kind(),
+                    format!("Could not run the extractor for {}: {}", archive, e),
+                ))
+            })?;
+        let output = child.wait_with_output();
+        tokio::pin!(output);
+        let output = loop {
+            match tokio::time::timeout(PROGRESS_INTERVAL, &mut output).await {
+                Ok(output) => break output,
+                Err(_) => {
+                    let dir = staging.clone();
+                    let written = blocking(move || dir_size(&dir)).await.unwrap_or(0);
+                    self.update(id, &archive, false, |e| e.extracted = written);
+                }
+            }
+        };
+
+        let (dir, into) = (staging.clone(), into.to_path_buf());
+        let result = match output {
+            Ok(output) if output.status.success() => {
+                blocking(move || {
+                    let size = dir_size(&dir)?;
+                    let files = settle(&dir, &into)?;
+                    fs::remove_dir_all(&dir)?;
+                    Ok((size, files))
+                })
+                .await
+            }
+            Ok(output) => Err(AppError::ValidationError(format!(
+                "{} failed: {}",
+                output.status,
+                last_line(&output.stderr)
+            ))),
+            Err(e) => Err(AppError::IoError(e)),
+        };
+        if result.is_err() {
+            let _ = fs::remove_dir_all(&staging);
+        }
+        let (size, files) = result?;
+        self.update(id, &archive, false, |e| e.extracted = size);
+        Ok(files
+            .iter()
+            .map(|f| f.to_string_lossy().into_owned())
+            .collect())
+    }
+
+    /// Deletes what was unpacked for a torrent and folders left empty by it.
+    pub fn clean(&self, id: i64) -> usize {
+        let list = {
+            let mut torrents = self.torrents.lock().unwrap();
+            match torrents.remove(&id) {
+                Some(list) => {
+                    self.save(&torrents);
+                    list
+                }
+                None => return 0,
+            }
+        };
+
+        let mut removed = 0;
+        for extraction in &list {
+            let target = Path::new(&extraction.target);
+            for file in &extraction.files {
+                let file = Path::new(file);
+                match fs::remove_file(file) {
+                    Ok(_) => removed += 1,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => error!("Could not delete {}: {}", file.display(), e),
+                }
+                let mut dir = file.parent();
+                while let Some(d) = dir.filter(|d| d.starts_with(target)) {
+                    if fs::remove_dir(d).is_err() {
+                        break;
+                    }
+                    dir = d.parent();
+                }
+            }
+        }
+        removed
+    }
+}
+
+fn tool(kind: ArchiveKind, archive: &Path, into: &Path) -> Command {
+    match kind {
+        ArchiveKind::Zip => {
+            let mut command = Command::new("unzip");
+            command.arg("-o").arg("-q").arg(archive).arg("-d").arg(into);
+            command
+        }
+        ArchiveKind::SevenZip => {
+            let mut command = Command::new("7z");
+            command
+                .arg("x")
+                .arg("-y")
+                .arg(format!("-o{}", into.display()))
+                .arg(archive);
+            command
+        }
+        ArchiveKind::Rar => {
+            let mut command = Command::new("unrar");
+            command
+                .arg("x")
+                .arg("-o+")
+                .arg("-idq")
+                .arg(archive)
+                .arg(format!("{}/", into.display()));
+            command
+        }
+    }
+}
+
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut files = Vec::new();
+    collect(path, &mut files)?;
+    Ok(files
+        .iter()
+        .filter_map(|f| f.metadata().ok())
+        .map(|m| m.len())
+        .sum())
+}
+
+/// Moves what is in `staging` into `into` and returns the files it put there. Files that
+/// exist already are kept, they may be the torrent's own and still seeded, so only what
+/// was moved in is deleted again by `clean`.
+fn settle(staging: &Path, into: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    collect(staging, &mut files)?;
+    let mut settled = Vec::new();
+    for file in files.iter().filter(|f| f.is_file()) {
+        let relative = file.strip_prefix(staging).unwrap_or(file);
+        let target = into.join(relative);
+        if fs::symlink_metadata(&target).is_ok() {
+            info!("Keeping {}, it exists already", target.display());
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(file, &target)?;
+        settled.push(target);
+    }
+    Ok(settled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{scratch, write};
+
+    fn display(path: &Path) -> String {
+        path.to_string_lossy().into_owned()
+    }
+
+    fn names(set: &ArchiveSet) -> Vec<String> {
+        set.volumes
+            .iter()
+            .map(|v| v.file_name().unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn detects_sets() {
+        let dir = scratch("sets");
+        for name in &[
+            "movie.rar",
+            "movie.r01",
+            "movie.r00",
+            "show.part02.rar",
+            "show.part01.rar",
+            "album.zip",
+            "files.7z.002",
+            "files.7z.001",
+            "broken.part2.rar",
+            "movie.mkv",
+            "Subs/subs.zip",
+            ".extracting-old.rar/inner.rar",
+        ] {
+            write(&dir.join(name), "data");
+        }
+
+        let sets = archive_sets(&dir).unwrap();
+        let found: Vec<(Vec<String>, bool)> = sets.iter().map(|s| (names(s), s.complete)).collect();
+        let list = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            vec![
+                (list(&["album.zip"]), true),
+                (list(&["broken.part2.rar"]), false),
+                (list(&["files.7z.001", "files.7z.002"]), true),
+                (list(&["movie.rar", "movie.r00", "movie.r01"]), true),
+                (list(&["show.part01.rar", "show.part02.rar"]), true),
+                (list(&["subs.zip"]), true),
+            ]
+        );
+        assert_eq!(sets[3].kind, ArchiveKind::Rar);
+        assert_eq!(sets[2].kind, ArchiveKind::SevenZip);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn settles_and_cleans() {
+        let dir = scratch("settle");
+        let staging = dir.join(".extracting-movie.rar");
+        write(&staging.join("movie.mkv"), "data");
+        write(&staging.join("Extras/trailer.mkv"), "data");
+        write(&staging.join("movie.nfo"), "data");
+        // The torrent has the video unpacked as well, it is seeded and must stay
+        fs::write(dir.join("movie.mkv"), "seeded").unwrap();
+
+        let mut files = settle(&staging, &dir).unwrap();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![dir.join("Extras/trailer.mkv"), dir.join("movie.nfo")]
+        );
+        assert_eq!(fs::read_to_string(dir.join("movie.mkv")).unwrap(), "seeded");
+
+        let extractor = Extractor::load(dir.join("extractions.json"));
+        extractor.torrents.lock().unwrap().insert(
+            3,
+            vec![Extraction {
+                archive: display(&dir.join("movie.rar")),
+                volumes: 1,
+                size: 4,
+                extracted: 8,
+                target: display(&dir),
+                state: StepState::Done,
+                error: None,
+                files: files.iter().map(|f| display(f)).collect(),
+            }],
+        );
+        assert_eq!(extractor.clean(3), 2);
+        assert!(!dir.join("Extras").exists());
+        assert!(dir.join("movie.mkv").exists());
+        assert_eq!(extractor.clean(3), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_failures_per_archive() {
+        let dir = scratch("failures");
+        let source = dir.join("Some.Release");
+        write(&source.join("broken.zip"), "data");
+        write(&source.join("orphan.part3.rar"), "data");
+
+        let extractor = Extractor::load(dir.join("extractions.json"));
+        let result = extractor.extract(7, &source, None).await;
+        assert!(result.unwrap_err().to_string().contains("2 of 2"));
+
+        let list = extractor.get(7).unwrap();
+        assert_eq!(list.len(), 2);
+        assert!(list.iter().all(|e| e.state == StepState::Failed));
+        assert!(list[1].error.as_ref().unwrap().contains("first volume"));
+        // Nothing is left behind by a failed extraction
+        assert!(!source.join(".extracting-broken.zip").exists());
+
+        // The state survives a restart
+        let reloaded = Extractor::load(dir.join("extractions.json"));
+        assert_eq!(reloaded.get(7), Some(list));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,290 @@
+use std::sync::{Arc, Mutex};
+
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{Codec, Frame, Message};
+use actix_web::web::Payload;
+use bytes::{Bytes, BytesMut};
+use futures::{
+    future::ready,
+    stream::{self, LocalBoxStream, StreamExt},
+};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tokio::sync::{
+    broadcast::{self, RecvError},
+    mpsc,
+};
+
+use super::{
+    client::{Client, Torrent},
+    events::{EventBus, Listener, TorrentEvent},
+};
+
+#[derive(Serialize, Debug, Default)]
+pub struct ListDiff {
+    pub added: Vec<Torrent>,
+    pub removed: Vec<i64>,
+    /// The id and the fields that changed for every changed torrent
+    pub changed: Vec<Map<String, Value>>,
+}
+
+impl ListDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+pub fn diff(old: &[Torrent], new: &[Torrent]) -> ListDiff {
+    let mut result = ListDiff::default();
+
+    for torrent in new {
+        let previous = match old.iter().find(|t| t.id == torrent.id) {
+            Some(previous) => previous,
+            None => {
+                result.added.push(torrent.clone());
+                continue;
+            }
+        };
+
+        let (before, after) = match (fields(previous), fields(torrent)) {
+            (Some(before), Some(after)) => (before, after),
+            _ => continue,
+        };
+        let mut changed: Map<String, Value> = after
+            .into_iter()
+            .filter(|(key, value)| before.get(key) != Some(value))
+            .collect();
+        if !changed.is_empty() {
+            changed.insert("id".into(), torrent.id.into());
+            result.changed.push(changed);
+        }
+    }
+
+    result.removed = old
+        .iter()
+        .filter(|t| !new.iter().any(|n| n.id == t.id))
+        .map(|t| t.id)
+        .collect();
+    result
+}
+
+fn fields(torrent: &Torrent) -> Option<Map<String, Value>> {
+    match serde_json::to_value(torrent) {
+        Ok(Value::Object(map)) => Some(map),
+        _ => None,
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum FeedMessage<'a> {
+    Snapshot { torrents: &'a [Torrent] },
+    Diff(&'a ListDiff),
+    Event(&'a TorrentEvent),
+}
+
+fn to_json(message: &FeedMessage) -> String {
+    serde_json::to_string(message).unwrap_or_default()
+}
+
+/// Broadcasts what changed in the torrent list to every connected socket.
+/// Snapshots come from the event bus, so the backend sees one poller
+/// whatever the number of clients.
+pub struct Feed {
+    torrents: Mutex<Vec<Torrent>>,
+    sender: broadcast::Sender<String>,
+}
+
+impl Default for Feed {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(16);
+        Self {
+            torrents: Mutex::new(Vec::new()),
+            sender,
+        }
+    }
+}
+
+impl Listener for Feed {
+    fn live(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    fn update(&self, torrents: &[Torrent], events: &[TorrentEvent]) {
+        // Diffs are computed and sent under the lock so subscribers see them in order
+        let mut state = self.torrents.lock().unwrap();
+        let changes = diff(&state, torrents);
+        *state = torrents.to_vec();
+        if !self.live() {
+            return;
+        }
+        if !changes.is_empty() {
+            let _ = self.sender.send(to_json(&FeedMessage::Diff(&changes)));
+        }
+        for event in events {
+            let _ = self.sender.send(to_json(&FeedMessage::Event(event)));
+        }
+    }
+}
+
+impl Feed {
+    fn snapshot(&self) -> String {
+        to_json(&FeedMessage::Snapshot {
+            torrents: &self.torrents.lock().unwrap(),
+        })
+    }
+
+    /// The current list and a receiver for the diffs and events that follow it.
+    pub async fn subscribe(
+        &self,
+        events: &EventBus,
+        client: &Client,
+    ) -> (String, broadcast::Receiver<String>) {
+        if !events.fresh() {
+            events.refresh(client).await;
+        }
+
+        let state = self.torrents.lock().unwrap();
+        let snapshot = to_json(&FeedMessage::Snapshot { torrents: &state });
+        (snapshot, self.sender.subscribe())
+    }
+
+    /// WebSocket frames for one client: the snapshot, then every diff and event. Pings are
+    /// answered and the stream ends once the client closes the socket.
+    pub fn socket(
+        self: Arc<Self>,
+        snapshot: String,
+        updates: broadcast::Receiver<String>,
+        mut payload: Payload,
+    ) -> LocalBoxStream<'static, Result<Bytes, actix_web::Error>> {
+        let (control, control_rx) = mpsc::unbounded_channel();
+        actix_web::rt::spawn(async move {
+            let mut codec = Codec::new();
+            let mut buf = BytesMut::new();
+            while let Some(Ok(chunk)) = payload.next().await {
+                buf.extend_from_slice(&chunk);
+                loop {
+                    match codec.decode(&mut buf) {
+                        Ok(Some(Frame::Ping(data))) => {
+                            let _ = control.send(Message::Pong(data));
+                        }
+                        Ok(Some(Frame::Close(reason))) => {
+                            let _ = control.send(Message::Close(reason));
+                            return;
+                        }
+                        Ok(Some(_)) => {}
+                        Ok(None) => break,
+                        Err(_) => {
+                            let _ = control.send(Message::Close(None));
+                            return;
+                        }
+                    }
+                }
+            }
+            let _ = control.send(Message::Close(None));
+        });
+
+        let feed = self;
+        let updates = stream::unfold(updates, move |mut updates| {
+            let feed = feed.clone();
+            async move {
+                let text = match updates.recv().await {
+                    Ok(text) => text,
+                    // Too far behind to apply the diffs, start over from the full list
+                    Err(RecvError::Lagged(_)) => feed.snapshot(),
+                    Err(RecvError::Closed) => return None,
+                };
+                Some((Message::Text(text), updates))
+            }
+        });
+
+        let mut codec = Codec::new();
+        stream::select(
+            stream::once(ready(Message::Text(snapshot))).chain(updates),
+            control_rx,
+        )
+        .scan(false, |closed, message| {
+            if *closed {
+                return ready(None);
+            }
+            *closed = matches!(message, Message::Close(_));
+            ready(Some(message))
+        })
+        .map(move |message| {
+            let mut buf = BytesMut::new();
+            codec.encode(message, &mut buf)?;
+            Ok(buf.freeze())
+        })
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn torrent(id: i64, status: &str, progress: i32) -> Torrent {
+        Torrent {
+            id,
+            name: format!("Torrent {}", id),
+            hash: format!("{:040x}", id),
+            status: status.to_string(),
+            progress,
+            ..testing::torrent()
+        }
+    }
+
+    #[test]
+    fn diff_lists() {
+        let old = vec![torrent(1, "downloading", 10), torrent(2, "paused", 0)];
+        let new = vec![torrent(1, "downloading", 25), torrent(3, "downloading", 0)];
+
+        let changes = diff(&old, &new);
+        assert_eq!(changes.added.len(), 1);
+        assert_eq!(changes.added[0].id, 3);
+        assert_eq!(changes.removed, vec![2]);
+        assert_eq!(changes.changed.len(), 1);
+        assert_eq!(
+            Value::Object(changes.changed[0].clone()),
+            serde_json::json!({ "id": 1, "progress": 25 })
+        );
+    }
+
+    #[test]
+    fn diff_unchanged() {
+        let list = vec![torrent(1, "done", 100)];
+        assert!(diff(&list, &list).is_empty());
+    }
+
+    #[test]
+    fn messages_are_tagged() {
+        let changes = diff(&[], &[torrent(1, "done", 100)]);
+        let json: Value = serde_json::from_str(&to_json(&FeedMessage::Diff(&changes))).unwrap();
+        assert_eq!(json["type"], "diff");
+        assert_eq!(json["added"][0]["id"], 1);
+
+        let json: Value =
+            serde_json::from_str(&to_json(&FeedMessage::Snapshot { torrents: &[] })).unwrap();
+        assert_eq!(json["type"], "snapshot");
+    }
+
+    #[test]
+    fn update_sends_diffs_and_events() {
+        let feed = Feed::default();
+        let mut updates = feed.sender.subscribe();
+        feed.update(&[torrent(1, "downloading", 10)], &[]);
+
+        let done = [torrent(1, "done", 100)];
+        let events = crate::torrent::events::detect(&[torrent(1, "downloading", 10)], &done);
+        feed.update(&done, &events);
+
+        let messages: Vec<Value> = std::iter::from_fn(|| updates.try_recv().ok())
+            .map(|text| serde_json::from_str(&text).unwrap())
+            .collect();
+        let types: Vec<&str> = messages.iter().map(|m| m["type"].as_str().unwrap()).collect();
+        assert_eq!(types, vec!["diff", "diff", "event"]);
+        assert_eq!(messages[2]["event"], "completed");
+        assert_eq!(feed.torrents.lock().unwrap()[0].status, "done");
+    }
+}
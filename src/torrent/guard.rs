@@ -0,0 +1,279 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    batch::BatchAction,
+    client::{Client, Torrent},
+};
+use crate::{
+    config::ServerConfig,
+    disk,
+    error::{AppError, AppResult},
+};
+
+/// Torrent size given with an add request, in bytes or as shown in search results ("1.5 GB").
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum SizeHint {
+    Bytes(u64),
+    Text(String),
+}
+
+impl SizeHint {
+    pub fn bytes(&self) -> Option<u64> {
+        match self {
+            SizeHint::Bytes(bytes) => Some(*bytes),
+            SizeHint::Text(text) => parse_size(text),
+        }
+    }
+}
+
+pub fn parse_size(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let number: f64 = text[..split].parse().ok()?;
+    let unit: u64 = match text[split..].trim().to_uppercase().as_str() {
+        "" | "B" => 1,
+        "KB" | "KIB" => 1 << 10,
+        "MB" | "MIB" => 1 << 20,
+        "GB" | "GIB" => 1 << 30,
+        "TB" | "TIB" => 1 << 40,
+        _ => return None,
+    };
+    Some((number * unit as f64) as u64)
+}
+
+fn gb(bytes: u64) -> String {
+    format!("{:.1} GB", bytes as f64 / (1u64 << 30) as f64)
+}
+
+/// Whether `size` more bytes fit in `free` while keeping `reserve` free.
+pub fn fits(free: u64, size: Option<u64>, reserve: u64) -> bool {
+    free >= size.unwrap_or(0).saturating_add(reserve)
+}
+
+/// Refuses adds that would fill the disk and pauses downloads while free space is low.
+pub struct DiskGuard {
+    reserve: u64,
+    pause_below: Option<u64>,
+    resume_above: u64,
+    path: PathBuf,
+    // Downloads paused by the guard and their directory, kept across restarts so they are
+    // still resumed
+    paused: Mutex<HashMap<i64, String>>,
+}
+
+impl DiskGuard {
+    pub fn new(config: &ServerConfig) -> Self {
+        let path = Path::new(&config.data_dir).join("disk-guard.json");
+        let paused = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                error!("Invalid disk guard file {}: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Self {
+            reserve: config.disk_reserve,
+            pause_below: config.disk_pause_below,
+            resume_above: config
+                .disk_resume_above
+                .or_else(|| config.disk_pause_below.map(|b| b.saturating_mul(2)))
+                .unwrap_or(0),
+            path,
+            paused: Mutex::new(paused),
+        }
+    }
+
+    fn save(&self, paused: &HashMap<i64, String>) {
+        let saved = self
+            .path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| {
+                let json = serde_json::to_string_pretty(paused).unwrap_or_default();
+                fs::write(&self.path, json)
+            });
+        if let Err(e) = saved {
+            error!("Could not save {}: {}", self.path.display(), e);
+        }
+    }
+
+    pub fn watching(&self) -> bool {
+        self.pause_below.is_some()
+    }
+
+    pub async fn check_add(
+        &self,
+        client: &Client,
+        dir: Option<&str>,
+        size: Option<u64>,
+    ) -> AppResult<()> {
+        let dir = match dir {
+            Some(dir) => dir.to_string(),
+            None => match client.session_settings().await {
+                Ok(session) => match session.download_dir {
+                    Some(dir) => dir,
+                    None => return Ok(()),
+                },
+                Err(e) => {
+                    warn!("Could not read the default download dir! {}", e);
+                    return Ok(());
+                }
+            },
+        };
+
+        let free = match disk::free_space(client, &dir).await {
+            Some(free) => free,
+            None => {
+                warn!("Free space of {} is unknown, adding anyway", dir);
+                return Ok(());
+            }
+        };
+
+        if fits(free, size, self.reserve) {
+            return Ok(());
+        }
+        let needed = match size {
+            Some(size) => format!("{} plus {} reserve", gb(size), gb(self.reserve)),
+            None => format!("{} reserve", gb(self.reserve)),
+        };
+        Err(AppError::InsufficientStorage(format!(
+            "Not enough space in {}: {} needed, {} free",
+            dir,
+            needed,
+            gb(free)
+        )))
+    }
+
+    /// Pauses the downloads in `torrents` whose directory ran low on space and resumes the ones
+    /// it paused once there is enough again.
+    pub async fn watch(&self, client: &Client, torrents: &[Torrent]) {
+        let pause_below = match self.pause_below {
+            Some(limit) => limit,
+            None => return,
+        };
+
+        let mut dirs: Vec<String> = torrents.iter().map(|t| t.download_dir.clone()).collect();
+        dirs.extend(self.paused.lock().unwrap().values().cloned());
+        dirs.sort();
+        dirs.dedup();
+
+        for dir in dirs {
+            let free = match disk::free_space(client, &dir).await {
+                Some(free) => free,
+                None => continue,
+            };
+
+            if free < pause_below {
+                let ids: Vec<i64> = torrents
+                    .iter()
+                    .filter(|t| t.download_dir == dir && t.status == "downloading")
+                    .map(|t| t.id)
+                    .collect();
+                if ids.is_empty() {
+                    continue;
+                }
+                info!(
+                    "Only {} free in {}, pausing downloads {:?}",
+                    gb(free),
+                    dir,
+                    ids
+                );
+                match client.batch_action(BatchAction::Stop, ids.clone()).await {
+                    Ok(_) => {
+                        let mut paused = self.paused.lock().unwrap();
+                        ids.into_iter().for_each(|id| {
+                            paused.insert(id, dir.clone());
+                        });
+                        self.save(&paused);
+                    }
+                    Err(e) => error!("Disk guard could not pause downloads: {}", e),
+                }
+            } else if free > self.resume_above {
+                let ids: Vec<i64> = self
+                    .paused
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|(id, d)| **d == dir && torrents.iter().any(|t| t.id == **id))
+                    .map(|(id, _)| *id)
+                    .collect();
+                if !ids.is_empty() {
+                    info!("{} free in {}, resuming downloads {:?}", gb(free), dir, ids);
+                    if let Err(e) = client.batch_action(BatchAction::Start, ids).await {
+                        error!("Disk guard could not resume downloads: {}", e);
+                        continue;
+                    }
+                }
+                let mut paused = self.paused.lock().unwrap();
+                let before = paused.len();
+                paused.retain(|_, d| *d != dir);
+                if paused.len() != before {
+                    self.save(&paused);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(parse_size("800 MB"), Some(800 << 20));
+        assert_eq!(parse_size("1.5 GB"), Some(3 << 29));
+        assert_eq!(parse_size("2GiB"), Some(2 << 30));
+        assert_eq!(parse_size("1024"), Some(1024));
+        assert_eq!(parse_size("a lot"), None);
+        assert_eq!(parse_size("3 parsecs"), None);
+
+        assert_eq!(SizeHint::Bytes(10).bytes(), Some(10));
+        let hint: SizeHint = serde_json::from_str("\"1 KB\"").unwrap();
+        assert_eq!(hint.bytes(), Some(1024));
+    }
+
+    #[test]
+    fn fits_with_reserve() {
+        let gb = 1u64 << 30;
+        assert!(fits(10 * gb, Some(8 * gb), gb));
+        assert!(!fits(10 * gb, Some(9 * gb + 1), gb));
+        assert!(fits(2 * gb, None, gb));
+        assert!(!fits(gb / 2, None, gb));
+    }
+
+    #[test]
+    fn paused_downloads_survive_restarts() {
+        let dir = std::env::temp_dir().join(format!("disk-guard-{}", std::process::id()));
+        let config = ServerConfig {
+            data_dir: dir.display().to_string(),
+            disk_pause_below: Some(1 << 30),
+            ..ServerConfig::default()
+        };
+        let guard = DiskGuard::new(&config);
+        {
+            let mut paused = guard.paused.lock().unwrap();
+            paused.insert(3, "/downloads".to_string());
+            guard.save(&paused);
+        }
+
+        let restarted = DiskGuard::new(&config);
+        assert_eq!(
+            *restarted.paused.lock().unwrap(),
+            HashMap::from([(3, "/downloads".to_string())])
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
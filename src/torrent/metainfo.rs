@@ -0,0 +1,542 @@
+pub mod bencode;
+
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::error::{AppError, AppResult};
+
+use self::bencode::Value;
+
+const V1_PIECE_HASH_LEN: usize = 20;
+const V2_MIN_PIECE_LENGTH: u64 = 16 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MetaVersion {
+    V1,
+    V2,
+    Hybrid,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MetaFile {
+    pub path: String,
+    pub length: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pieces_root: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Metainfo {
+    pub name: String,
+    pub version: MetaVersion,
+    /// SHA-1 infohash for v1 and hybrid torrents, truncated SHA-256 for v2-only ones.
+    pub info_hash: String,
+    pub info_hash_v2: Option<String>,
+    pub files: Vec<MetaFile>,
+    pub total_size: u64,
+    pub piece_length: u64,
+    pub piece_count: u64,
+    pub private: bool,
+    pub trackers: Vec<String>,
+}
+
+impl Metainfo {
+    pub fn from_bytes(data: &[u8]) -> AppResult<Self> {
+        let root = bencode::decode(data)?;
+        let info = root
+            .get("info")
+            .filter(|i| i.as_dict().is_some())
+            .ok_or_else(|| invalid("missing info dictionary"))?;
+        let raw_info =
+            bencode::raw_entry(data, "info")?.ok_or_else(|| invalid("missing info dictionary"))?;
+
+        // The name becomes the torrent's file or folder, so it gets the same checks as paths
+        let name = path_component(info.get("name").and_then(Value::as_str))
+            .map_err(|_| invalid("missing or invalid name"))?
+            .to_string();
+
+        let piece_length =
+            info.get("piece length")
+                .and_then(Value::as_int)
+                .filter(|l| *l > 0)
+                .ok_or_else(|| invalid("missing or invalid piece length"))? as u64;
+
+        let is_v2 = info.get("meta version").and_then(Value::as_int) == Some(2);
+        let is_v1 = info.get("pieces").is_some();
+        let version = match (is_v1, is_v2) {
+            (true, true) => MetaVersion::Hybrid,
+            (true, false) => MetaVersion::V1,
+            (false, true) => MetaVersion::V2,
+            (false, false) => return Err(invalid("neither v1 pieces nor v2 meta version")),
+        };
+
+        let (v1_files, v1_size) = if is_v1 {
+            v1_files(info, &name)?
+        } else {
+            (Vec::new(), 0)
+        };
+
+        let (files, piece_count) = if is_v2 {
+            if piece_length < V2_MIN_PIECE_LENGTH || !piece_length.is_power_of_two() {
+                return Err(invalid("v2 piece length must be a power of two >= 16 KiB"));
+            }
+            let tree = info
+                .get("file tree")
+                .ok_or_else(|| invalid("missing file tree"))?;
+            let mut files = Vec::new();
+            walk_file_tree(tree, &mut vec![name.clone()], &mut files, 0)?;
+            let pieces = files
+                .iter()
+                .map(|f| f.length.div_ceil(piece_length))
+                .fold(0, u64::saturating_add);
+            (files, pieces)
+        } else {
+            (v1_files, 0)
+        };
+
+        let piece_count = if is_v1 {
+            let pieces = info
+                .get("pieces")
+                .and_then(Value::as_bytes)
+                .filter(|p| p.len() % V1_PIECE_HASH_LEN == 0)
+                .ok_or_else(|| invalid("pieces must be a multiple of 20 bytes"))?;
+            let count = (pieces.len() / V1_PIECE_HASH_LEN) as u64;
+            if count != v1_size.div_ceil(piece_length) {
+                return Err(invalid("piece count does not match total size"));
+            }
+            count
+        } else {
+            piece_count
+        };
+
+        let total_size = files.iter().map(|f| f.length).fold(0, u64::saturating_add);
+
+        let sha256 = hex(&Sha256::digest(raw_info));
+        let (info_hash, info_hash_v2) = match version {
+            MetaVersion::V1 => (hex(&Sha1::digest(raw_info)), None),
+            MetaVersion::Hybrid => (hex(&Sha1::digest(raw_info)), Some(sha256)),
+            MetaVersion::V2 => (sha256[..40].to_string(), Some(sha256)),
+        };
+
+        Ok(Self {
+            name,
+            version,
+            info_hash,
+            info_hash_v2,
+            files,
+            total_size,
+            piece_length,
+            piece_count,
+            private: info.get("private").and_then(Value::as_int) == Some(1),
+            trackers: trackers(&root),
+        })
+    }
+
+    pub fn magnet(&self) -> String {
+        let mut magnet = String::from("magnet:?");
+        if self.version != MetaVersion::V2 {
+            magnet.push_str(&format!("xt=urn:btih:{}&", self.info_hash));
+        }
+        if let Some(v2) = &self.info_hash_v2 {
+            magnet.push_str(&format!("xt=urn:btmh:1220{}&", v2));
+        }
+        magnet.push_str(&format!(
+            "dn={}&xl={}",
+            uri_encode(&self.name),
+            self.total_size
+        ));
+        for tracker in &self.trackers {
+            magnet.push_str(&format!("&tr={}", uri_encode(tracker)));
+        }
+        magnet
+    }
+}
+
+/// Lists the files of a v1 info dictionary along with the size covered by its pieces,
+/// which includes padding files.
+fn v1_files(info: &Value, name: &str) -> AppResult<(Vec<MetaFile>, u64)> {
+    let list = match info.get("files") {
+        Some(list) => list
+            .as_list()
+            .ok_or_else(|| invalid("files is not a list"))?,
+        None => {
+            let length = length_of(info)?;
+            let file = MetaFile {
+                path: name.to_string(),
+                length,
+                pieces_root: None,
+            };
+            return Ok((vec![file], length));
+        }
+    };
+
+    let mut files = Vec::with_capacity(list.len());
+    let mut size = 0u64;
+    for file in list {
+        let length = length_of(file)?;
+        size = size.saturating_add(length);
+        // BEP 47 padding files only exist to align pieces and are never written to disk
+        let is_padding = file
+            .get("attr")
+            .and_then(Value::as_str)
+            .is_some_and(|attr| attr.contains('p'));
+        if is_padding {
+            continue;
+        }
+        let path = file
+            .get("path")
+            .and_then(Value::as_list)
+            .filter(|p| !p.is_empty())
+            .ok_or_else(|| invalid("file without path"))?
+            .iter()
+            .map(|p| path_component(p.as_str()))
+            .collect::<AppResult<Vec<_>>>()?;
+        files.push(MetaFile {
+            path: format!("{}/{}", name, path.join("/")),
+            length,
+            pieces_root: None,
+        });
+    }
+    Ok((files, size))
+}
+
+fn walk_file_tree(
+    node: &Value,
+    path: &mut Vec<String>,
+    files: &mut Vec<MetaFile>,
+    depth: usize,
+) -> AppResult<()> {
+    let dict = node
+        .as_dict()
+        .ok_or_else(|| invalid("file tree node is not a dictionary"))?;
+    if depth > 0 {
+        if let Some(file) = dict.get(&b""[..]) {
+            files.push(MetaFile {
+                path: path.join("/"),
+                length: length_of(file)?,
+                pieces_root: file.get("pieces root").and_then(Value::as_bytes).map(hex),
+            });
+            return Ok(());
+        }
+    }
+    for (key, child) in dict {
+        path.push(path_component(std::str::from_utf8(key).ok())?.to_string());
+        walk_file_tree(child, path, files, depth + 1)?;
+        path.pop();
+    }
+    Ok(())
+}
+
+fn trackers(root: &Value) -> Vec<String> {
+    let mut trackers: Vec<String> = root
+        .get("announce-list")
+        .and_then(Value::as_list)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_list)
+        .flatten()
+        .filter_map(Value::as_str)
+        .map(String::from)
+        .collect();
+    if let Some(announce) = root.get("announce").and_then(Value::as_str) {
+        trackers.insert(0, announce.to_string());
+    }
+    let mut seen = std::collections::HashSet::new();
+    trackers.retain(|t| seen.insert(t.clone()));
+    trackers
+}
+
+fn path_component(component: Option<&str>) -> AppResult<&str> {
+    component
+        .filter(|c| !c.is_empty() && *c != "." && *c != ".." && !c.contains('/'))
+        .ok_or_else(|| invalid("invalid path component"))
+}
+
+fn length_of(value: &Value) -> AppResult<u64> {
+    value
+        .get("length")
+        .and_then(Value::as_int)
+        .filter(|l| *l >= 0)
+        .map(|l| l as u64)
+        .ok_or_else(|| invalid("missing or invalid length"))
+}
+
+fn invalid(msg: &str) -> AppError {
+    AppError::ParseError(format!("metainfo: {}", msg))
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn uri_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bencode::{encode, raw_entry};
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn dict(entries: Vec<(&str, Value)>) -> Value {
+        Value::Dict(
+            entries
+                .into_iter()
+                .map(|(k, v)| (k.as_bytes().to_vec(), v))
+                .collect::<BTreeMap<_, _>>(),
+        )
+    }
+
+    fn text(s: &str) -> Value {
+        Value::Bytes(s.as_bytes().to_vec())
+    }
+
+    fn pieces(count: usize) -> Value {
+        Value::Bytes(vec![0; count * V1_PIECE_HASH_LEN])
+    }
+
+    fn single_file() -> Vec<u8> {
+        encode(&dict(vec![
+            ("announce", text("http://tracker/announce")),
+            (
+                "info",
+                dict(vec![
+                    ("length", Value::Int(1024)),
+                    ("name", text("file.bin")),
+                    ("piece length", Value::Int(512)),
+                    ("pieces", pieces(2)),
+                ]),
+            ),
+        ]))
+    }
+
+    fn multi_file() -> Vec<u8> {
+        let file = |length, path: Vec<&str>| {
+            dict(vec![
+                ("length", Value::Int(length)),
+                ("path", Value::List(path.into_iter().map(text).collect())),
+            ])
+        };
+        encode(&dict(vec![
+            (
+                "announce-list",
+                Value::List(vec![
+                    Value::List(vec![text("udp://a:80"), text("udp://b:80")]),
+                    Value::List(vec![text("udp://a:80")]),
+                ]),
+            ),
+            (
+                "info",
+                dict(vec![
+                    (
+                        "files",
+                        Value::List(vec![
+                            file(10, vec!["a", "x.mkv"]),
+                            dict(vec![
+                                ("attr", text("p")),
+                                ("length", Value::Int(6)),
+                                ("path", Value::List(vec![text(".pad"), text("6")])),
+                            ]),
+                            file(5, vec!["y.srt"]),
+                        ]),
+                    ),
+                    ("name", text("dir")),
+                    ("piece length", Value::Int(16)),
+                    ("pieces", pieces(2)),
+                    ("private", Value::Int(1)),
+                ]),
+            ),
+        ]))
+    }
+
+    fn v2_file_tree() -> Value {
+        let leaf = |length, root: u8| {
+            dict(vec![(
+                "",
+                dict(vec![
+                    ("length", Value::Int(length)),
+                    ("pieces root", Value::Bytes(vec![root; 32])),
+                ]),
+            )])
+        };
+        dict(vec![
+            ("movie.mkv", leaf(40_000, 0xab)),
+            ("subs", dict(vec![("en.srt", leaf(1_000, 0xcd))])),
+        ])
+    }
+
+    #[test]
+    fn single_file_metainfo() {
+        let data = single_file();
+        let meta = Metainfo::from_bytes(&data).unwrap();
+        assert_eq!(meta.name, "file.bin");
+        assert_eq!(meta.version, MetaVersion::V1);
+        assert_eq!(meta.total_size, 1024);
+        assert_eq!(meta.piece_length, 512);
+        assert_eq!(meta.piece_count, 2);
+        assert!(!meta.private);
+        assert_eq!(meta.trackers, vec!["http://tracker/announce"]);
+        assert_eq!(
+            meta.files,
+            vec![MetaFile {
+                path: "file.bin".to_string(),
+                length: 1024,
+                pieces_root: None,
+            }]
+        );
+
+        let raw = raw_entry(&data, "info").unwrap().unwrap();
+        assert_eq!(meta.info_hash, hex(&Sha1::digest(raw)));
+        assert_eq!(meta.info_hash.len(), 40);
+        assert_eq!(meta.info_hash_v2, None);
+    }
+
+    #[test]
+    fn multi_file_metainfo() {
+        let meta = Metainfo::from_bytes(&multi_file()).unwrap();
+        assert_eq!(meta.name, "dir");
+        assert_eq!(meta.total_size, 15);
+        assert_eq!(meta.piece_count, 2);
+        assert!(meta.private);
+        assert_eq!(meta.files.len(), 2);
+        assert_eq!(meta.files[0].path, "dir/a/x.mkv");
+        assert_eq!(meta.files[1].path, "dir/y.srt");
+        assert_eq!(meta.trackers, vec!["udp://a:80", "udp://b:80"]);
+    }
+
+    #[test]
+    fn v2_metainfo() {
+        let data = encode(&dict(vec![(
+            "info",
+            dict(vec![
+                ("file tree", v2_file_tree()),
+                ("meta version", Value::Int(2)),
+                ("name", text("Movie")),
+                ("piece length", Value::Int(16_384)),
+            ]),
+        )]));
+        let meta = Metainfo::from_bytes(&data).unwrap();
+        let raw = raw_entry(&data, "info").unwrap().unwrap();
+        let sha256 = hex(&Sha256::digest(raw));
+
+        assert_eq!(meta.version, MetaVersion::V2);
+        assert_eq!(meta.info_hash_v2.as_deref(), Some(sha256.as_str()));
+        assert_eq!(meta.info_hash, sha256[..40]);
+        assert_eq!(meta.total_size, 41_000);
+        assert_eq!(meta.piece_count, 3 + 1);
+        assert_eq!(meta.files[0].path, "Movie/movie.mkv");
+        assert_eq!(meta.files[0].pieces_root, Some("ab".repeat(32)));
+        assert_eq!(meta.files[1].path, "Movie/subs/en.srt");
+    }
+
+    #[test]
+    fn hybrid_metainfo() {
+        let data = encode(&dict(vec![(
+            "info",
+            dict(vec![
+                ("file tree", v2_file_tree()),
+                (
+                    "files",
+                    Value::List(vec![
+                        dict(vec![
+                            ("length", Value::Int(40_000)),
+                            ("path", Value::List(vec![text("movie.mkv")])),
+                        ]),
+                        dict(vec![
+                            ("attr", text("p")),
+                            ("length", Value::Int(9_152)),
+                            ("path", Value::List(vec![text(".pad"), text("9152")])),
+                        ]),
+                        dict(vec![
+                            ("length", Value::Int(1_000)),
+                            ("path", Value::List(vec![text("subs"), text("en.srt")])),
+                        ]),
+                    ]),
+                ),
+                ("meta version", Value::Int(2)),
+                ("name", text("Movie")),
+                ("piece length", Value::Int(16_384)),
+                ("pieces", pieces(4)),
+            ]),
+        )]));
+        let meta = Metainfo::from_bytes(&data).unwrap();
+        let raw = raw_entry(&data, "info").unwrap().unwrap();
+
+        assert_eq!(meta.version, MetaVersion::Hybrid);
+        assert_eq!(meta.info_hash, hex(&Sha1::digest(raw)));
+        assert_eq!(meta.info_hash_v2, Some(hex(&Sha256::digest(raw))));
+        assert_eq!(meta.piece_count, 4);
+        assert_eq!(meta.total_size, 41_000);
+        assert_eq!(meta.files.len(), 2);
+    }
+
+    #[test]
+    fn magnet_uri() {
+        let meta = Metainfo::from_bytes(&multi_file()).unwrap();
+        assert_eq!(
+            meta.magnet(),
+            format!(
+                "magnet:?xt=urn:btih:{}&dn=dir&xl=15&tr=udp%3A%2F%2Fa%3A80&tr=udp%3A%2F%2Fb%3A80",
+                meta.info_hash
+            )
+        );
+    }
+
+    #[test]
+    fn invalid_metainfo_is_rejected() {
+        let reject =
+            |info: Value| Metainfo::from_bytes(&encode(&dict(vec![("info", info)]))).is_err();
+
+        assert!(Metainfo::from_bytes(b"d8:announce9:http://tre").is_err());
+        assert!(reject(dict(vec![("name", text("x"))])));
+        assert!(reject(dict(vec![
+            ("length", Value::Int(1024)),
+            ("name", text("x")),
+            ("piece length", Value::Int(512)),
+            ("pieces", Value::Bytes(vec![0; 21])),
+        ])));
+        assert!(reject(dict(vec![
+            ("length", Value::Int(1024)),
+            ("name", text("x")),
+            ("piece length", Value::Int(512)),
+            ("pieces", pieces(3)),
+        ])));
+        assert!(reject(dict(vec![
+            (
+                "files",
+                Value::List(vec![dict(vec![
+                    ("length", Value::Int(1)),
+                    ("path", Value::List(vec![text("..")])),
+                ])]),
+            ),
+            ("name", text("x")),
+            ("piece length", Value::Int(512)),
+            ("pieces", pieces(1)),
+        ])));
+        for name in ["x", "../x", "a/b", "..", ""] {
+            let info = dict(vec![
+                ("length", Value::Int(512)),
+                ("name", text(name)),
+                ("piece length", Value::Int(512)),
+                ("pieces", pieces(1)),
+            ]);
+            assert_eq!(reject(info), name != "x", "{:?}", name);
+        }
+        assert!(reject(dict(vec![
+            ("file tree", v2_file_tree()),
+            ("meta version", Value::Int(2)),
+            ("name", text("x")),
+            ("piece length", Value::Int(1000)),
+        ])));
+    }
+}
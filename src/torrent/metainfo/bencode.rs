@@ -0,0 +1,333 @@
+use std::collections::BTreeMap;
+
+use crate::error::{AppError, AppResult};
+
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes().and_then(|b| std::str::from_utf8(b).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&Vec<Value>> {
+        match self {
+            Value::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Value>> {
+        match self {
+            Value::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.as_dict().and_then(|d| d.get(key.as_bytes()))
+    }
+}
+
+pub fn decode(data: &[u8]) -> AppResult<Value> {
+    let mut decoder = Decoder::new(data);
+    let value = decoder.value(0)?;
+    if decoder.pos != data.len() {
+        return Err(parse_error("trailing data after value", decoder.pos));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_into(value, &mut out);
+    out
+}
+
+#[cfg(test)]
+fn encode_into(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Int(i) => out.extend_from_slice(format!("i{}e", i).as_bytes()),
+        Value::Bytes(b) => {
+            out.extend_from_slice(format!("{}:", b.len()).as_bytes());
+            out.extend_from_slice(b);
+        }
+        Value::List(list) => {
+            out.push(b'l');
+            list.iter().for_each(|v| encode_into(v, out));
+            out.push(b'e');
+        }
+        Value::Dict(dict) => {
+            out.push(b'd');
+            for (k, v) in dict {
+                encode_into(&Value::Bytes(k.clone()), out);
+                encode_into(v, out);
+            }
+            out.push(b'e');
+        }
+    }
+}
+
+/// Returns the raw bytes of `key` in the top-level dictionary, as they were encoded.
+/// Info hashes must be computed over these bytes rather than a re-encoding.
+pub fn raw_entry<'a>(data: &'a [u8], key: &str) -> AppResult<Option<&'a [u8]>> {
+    let mut decoder = Decoder::new(data);
+    decoder.expect(b'd')?;
+    while decoder.peek()? != b'e' {
+        let k = decoder.bytes()?;
+        let start = decoder.pos;
+        decoder.value(1)?;
+        if k == key.as_bytes() {
+            return Ok(Some(&data[start..decoder.pos]));
+        }
+    }
+    Ok(None)
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn peek(&self) -> AppResult<u8> {
+        self.data
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| parse_error("unexpected end of data", self.pos))
+    }
+
+    fn expect(&mut self, byte: u8) -> AppResult<()> {
+        if self.peek()? != byte {
+            return Err(parse_error(
+                &format!("expected '{}'", byte as char),
+                self.pos,
+            ));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn value(&mut self, depth: usize) -> AppResult<Value> {
+        if depth > MAX_DEPTH {
+            return Err(parse_error("nesting too deep", self.pos));
+        }
+        match self.peek()? {
+            b'i' => self.int().map(Value::Int),
+            b'0'..=b'9' => self.bytes().map(|b| Value::Bytes(b.to_vec())),
+            b'l' => {
+                self.pos += 1;
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    list.push(self.value(depth + 1)?);
+                }
+                self.pos += 1;
+                Ok(Value::List(list))
+            }
+            b'd' => {
+                self.pos += 1;
+                let mut dict = BTreeMap::new();
+                while self.peek()? != b'e' {
+                    let key = self.bytes()?.to_vec();
+                    let value = self.value(depth + 1)?;
+                    dict.insert(key, value);
+                }
+                self.pos += 1;
+                Ok(Value::Dict(dict))
+            }
+            _ => Err(parse_error("unexpected token", self.pos)),
+        }
+    }
+
+    fn int(&mut self) -> AppResult<i64> {
+        self.expect(b'i')?;
+        let start = self.pos;
+        while self.peek()? != b'e' {
+            self.pos += 1;
+        }
+        let text = std::str::from_utf8(&self.data[start..self.pos])
+            .map_err(|_| parse_error("invalid integer", start))?;
+        if text.is_empty()
+            || text == "-0"
+            || (text.len() > 1 && text.trim_start_matches('-').starts_with('0'))
+        {
+            return Err(parse_error("invalid integer", start));
+        }
+        let int = text
+            .parse()
+            .map_err(|_| parse_error("invalid integer", start))?;
+        self.pos += 1;
+        Ok(int)
+    }
+
+    fn bytes(&mut self) -> AppResult<&'a [u8]> {
+        let start = self.pos;
+        while self.peek()?.is_ascii_digit() {
+            self.pos += 1;
+        }
+        let len: usize = std::str::from_utf8(&self.data[start..self.pos])
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| parse_error("invalid string length", start))?;
+        self.expect(b':')?;
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| parse_error("string exceeds data", start))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+}
+
+fn parse_error(msg: &str, pos: usize) -> AppError {
+    AppError::ParseError(format!("bencode: {} at byte {}", msg, pos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Small xorshift generator so the fuzz cases are reproducible without extra crates
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    fn random_value(rng: &mut Rng, depth: usize) -> Value {
+        let kind = if depth > 4 {
+            rng.below(2)
+        } else {
+            rng.below(4)
+        };
+        match kind {
+            0 => Value::Int(rng.next() as i64),
+            1 => Value::Bytes((0..rng.below(24)).map(|_| rng.next() as u8).collect()),
+            2 => Value::List(
+                (0..rng.below(5))
+                    .map(|_| random_value(rng, depth + 1))
+                    .collect(),
+            ),
+            _ => Value::Dict(
+                (0..rng.below(5))
+                    .map(|_| {
+                        let key = (0..rng.below(8)).map(|_| rng.next() as u8).collect();
+                        (key, random_value(rng, depth + 1))
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    #[test]
+    fn decode_values() {
+        assert_eq!(decode(b"i42e").unwrap(), Value::Int(42));
+        assert_eq!(decode(b"i-7e").unwrap(), Value::Int(-7));
+        assert_eq!(decode(b"i0e").unwrap(), Value::Int(0));
+        assert_eq!(decode(b"4:spam").unwrap(), Value::Bytes(b"spam".to_vec()));
+        assert_eq!(
+            decode(b"l4:spami1ee").unwrap(),
+            Value::List(vec![Value::Bytes(b"spam".to_vec()), Value::Int(1)])
+        );
+        let dict = decode(b"d3:cow3:mooe").unwrap();
+        assert_eq!(dict.get("cow").and_then(Value::as_str), Some("moo"));
+    }
+
+    #[test]
+    fn decode_rejects_malformed() {
+        assert!(decode(b"i03e").is_err());
+        assert!(decode(b"i-0e").is_err());
+        assert!(decode(b"ie").is_err());
+        assert!(decode(b"5:abc").is_err());
+        assert!(decode(b"l4:spam").is_err());
+        assert!(decode(b"i1ei2e").is_err());
+        assert!(decode(b"x").is_err());
+        assert!(decode(&[b'l'; 200]).is_err());
+    }
+
+    #[test]
+    fn encode_sorts_dictionary_keys() {
+        let value = decode(b"d1:bi2e1:ai1ee").unwrap();
+        assert_eq!(encode(&value), b"d1:ai1e1:bi2ee".to_vec());
+    }
+
+    #[test]
+    fn raw_entry_returns_original_bytes() {
+        let data = b"d4:infod1:xi1ee4:zzzz0:e";
+        assert_eq!(raw_entry(data, "info").unwrap(), Some(&b"d1:xi1ee"[..]));
+        assert_eq!(raw_entry(data, "missing").unwrap(), None);
+    }
+
+    #[test]
+    fn fuzz_round_trip() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..500 {
+            let value = random_value(&mut rng, 0);
+            let encoded = encode(&value);
+            assert_eq!(decode(&encoded).unwrap(), value);
+            assert_eq!(encode(&decode(&encoded).unwrap()), encoded);
+        }
+    }
+
+    #[test]
+    fn fuzz_mutations_never_panic() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..500 {
+            let mut encoded = encode(&random_value(&mut rng, 0));
+            if encoded.is_empty() {
+                continue;
+            }
+            for _ in 0..=rng.below(4) {
+                let at = rng.below(encoded.len() as u64) as usize;
+                match rng.below(3) {
+                    0 => encoded[at] = rng.next() as u8,
+                    1 => encoded.truncate(at),
+                    _ => encoded.insert(at, b"ild0:e"[rng.below(6) as usize]),
+                }
+                if encoded.is_empty() {
+                    break;
+                }
+            }
+            if let Ok(value) = decode(&encoded) {
+                assert_eq!(decode(&encode(&value)).unwrap(), value);
+            }
+        }
+    }
+}
@@ -0,0 +1,781 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use futures::future::BoxFuture;
+use log::{error, warn};
+use reqwest::header::AUTHORIZATION;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::broadcast::{self, RecvError},
+};
+
+use tokio_rustls::{rustls::ClientConfig, webpki::DNSNameRef, TlsConnector};
+
+use super::events::{fill, EventKind, TorrentEvent};
+use crate::error::{AppError, AppResult};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Message {
+    pub title: String,
+    pub body: String,
+}
+
+/// A push, chat or mail service notifications are sent through.
+pub trait Notifier: Send + Sync {
+    fn send<'a>(&'a self, message: &'a Message) -> BoxFuture<'a, AppResult<()>>;
+}
+
+/// Message templates with "{{field}}" placeholders filled from the event and torrent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Template {
+    pub title: String,
+    pub body: String,
+}
+
+impl Template {
+    fn default_for(event: EventKind) -> Self {
+        Self {
+            title: format!("Torrent {}", event.name()),
+            body: match event {
+                EventKind::Errored => "{{name}}: {{error}}".to_string(),
+                _ => "{{name}}".to_string(),
+            },
+        }
+    }
+
+    pub fn render(&self, event: &TorrentEvent) -> Message {
+        let fields = event.placeholders();
+        Message {
+            title: fill(&self.title, &fields),
+            body: fill(&self.body, &fields),
+        }
+    }
+}
+
+fn telegram_api() -> String {
+    "https://api.telegram.org".to_string()
+}
+
+fn smtp_port() -> u16 {
+    25
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Provider {
+    /// Publishes to the topic at `url`, such as https://ntfy.sh/my-topic
+    Ntfy {
+        url: String,
+        #[serde(default)]
+        token: Option<String>,
+    },
+    Gotify {
+        url: String,
+        token: String,
+    },
+    /// The Telegram bot API or anything speaking it
+    Telegram {
+        #[serde(default = "telegram_api")]
+        api_url: String,
+        token: String,
+        chat_id: String,
+    },
+    /// Mail through an SMTP server, credentials are only sent over TLS
+    Smtp {
+        host: String,
+        #[serde(default = "smtp_port")]
+        port: u16,
+        #[serde(default)]
+        tls: SmtpTls,
+        from: String,
+        to: Vec<String>,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Upgrades when the server offers STARTTLS, stays plain otherwise
+    #[default]
+    Starttls,
+    /// TLS from the start, as on port 465
+    Tls,
+    /// Never encrypts, for a relay on the same machine
+    Plain,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NotifierConfig {
+    #[serde(flatten)]
+    pub provider: Provider,
+    /// Events to notify about, completed and errored when empty
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+}
+
+impl NotifierConfig {
+    pub fn wants(&self, event: EventKind) -> bool {
+        if self.events.is_empty() {
+            matches!(event, EventKind::Completed | EventKind::Errored)
+        } else {
+            self.events.contains(&event)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct NotificationConfig {
+    pub providers: Vec<NotifierConfig>,
+    pub templates: HashMap<EventKind, Template>,
+}
+
+impl NotificationConfig {
+    pub fn message(&self, event: &TorrentEvent) -> Message {
+        match self.templates.get(&event.event) {
+            Some(template) => template.render(event),
+            None => Template::default_for(event.event).render(event),
+        }
+    }
+}
+
+pub fn notifier(provider: &Provider, http: &reqwest::Client) -> Arc<dyn Notifier> {
+    match provider.clone() {
+        Provider::Ntfy { url, token } => Arc::new(Ntfy {
+            http: http.clone(),
+            url,
+            token,
+        }),
+        Provider::Gotify { url, token } => Arc::new(Gotify {
+            http: http.clone(),
+            url,
+            token,
+        }),
+        Provider::Telegram {
+            api_url,
+            token,
+            chat_id,
+        } => Arc::new(Telegram {
+            http: http.clone(),
+            api_url,
+            token,
+            chat_id,
+        }),
+        Provider::Smtp {
+            host,
+            port,
+            tls,
+            from,
+            to,
+            username,
+            password,
+        } => Arc::new(Smtp {
+            host,
+            port,
+            tls,
+            from,
+            to,
+            credentials: username.zip(password),
+        }),
+    }
+}
+
+async fn check(response: reqwest::Result<reqwest::Response>) -> AppResult<reqwest::Response> {
+    let response = response?;
+    let status = response.status();
+    if status.is_success() {
+        Ok(response)
+    } else {
+        let text = response.text().await.unwrap_or_default();
+        Err(AppError::NetworkError(format!("{}: {}", status, text)))
+    }
+}
+
+pub struct Ntfy {
+    http: reqwest::Client,
+    url: String,
+    token: Option<String>,
+}
+
+impl Notifier for Ntfy {
+    fn send<'a>(&'a self, message: &'a Message) -> BoxFuture<'a, AppResult<()>> {
+        Box::pin(async move {
+            let mut request = self
+                .http
+                .post(&self.url)
+                .header("Title", encode_header(&message.title))
+                .body(message.body.clone());
+            if let Some(token) = &self.token {
+                request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+            }
+            check(request.send().await).await.map(|_| ())
+        })
+    }
+}
+
+pub struct Gotify {
+    http: reqwest::Client,
+    url: String,
+    token: String,
+}
+
+impl Notifier for Gotify {
+    fn send<'a>(&'a self, message: &'a Message) -> BoxFuture<'a, AppResult<()>> {
+        Box::pin(async move {
+            let request = self
+                .http
+                .post(&format!("{}/message", self.url.trim_end_matches('/')))
+                .header("X-Gotify-Key", self.token.as_str())
+                .json(&json!({ "title": message.title, "message": message.body }));
+            check(request.send().await).await.map(|_| ())
+        })
+    }
+}
+
+pub struct Telegram {
+    http: reqwest::Client,
+    api_url: String,
+    token: String,
+    chat_id: String,
+}
+
+impl Notifier for Telegram {
+    fn send<'a>(&'a self, message: &'a Message) -> BoxFuture<'a, AppResult<()>> {
+        Box::pin(async move {
+            let url = format!(
+                "{}/bot{}/sendMessage",
+                self.api_url.trim_end_matches('/'),
+                self.token
+            );
+            let text = format!("{}\n{}", message.title, message.body);
+            let request = self
+                .http
+                .post(&url)
+                .json(&json!({ "chat_id": self.chat_id, "text": text }));
+            let reply: Value = check(request.send().await).await?.json().await?;
+            if reply["ok"] == true {
+                Ok(())
+            } else {
+                Err(AppError::NetworkError(format!(
+                    "Telegram refused the message: {}",
+                    reply["description"]
+                )))
+            }
+        })
+    }
+}
+
+pub struct Smtp {
+    host: String,
+    port: u16,
+    tls: SmtpTls,
+    from: String,
+    to: Vec<String>,
+    credentials: Option<(String, String)>,
+}
+
+impl Smtp {
+    fn mail(&self, message: &Message) -> String {
+        let mut mail = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\n\
+             Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+            self.from,
+            self.to.join(", "),
+            encode_header(&message.title),
+            chrono::Local::now().to_rfc2822()
+        );
+        for line in message.body.lines() {
+            // Dot stuffing, a lone "." would end the message
+            if line.starts_with('.') {
+                mail.push('.');
+            }
+            mail.push_str(line);
+            mail.push_str("\r\n");
+        }
+        mail
+    }
+
+    async fn deliver(&self, message: &Message) -> AppResult<()> {
+        let stream: Box<dyn Stream> =
+            Box::new(TcpStream::connect((self.host.as_str(), self.port)).await?);
+        let mut encrypted = self.tls == SmtpTls::Tls;
+        let mut smtp = BufReader::new(match self.tls {
+            SmtpTls::Tls => self.encrypt(stream).await?,
+            _ => stream,
+        });
+
+        reply(&mut smtp, 220).await?;
+        let extensions = command(&mut smtp, "EHLO localhost", 250).await?;
+        let starttls = extensions.iter().any(|e| e.eq_ignore_ascii_case("STARTTLS"));
+        if self.tls == SmtpTls::Starttls && starttls {
+            command(&mut smtp, "STARTTLS", 220).await?;
+            smtp = BufReader::new(self.encrypt(smtp.into_inner()).await?);
+            encrypted = true;
+            command(&mut smtp, "EHLO localhost", 250).await?;
+        }
+        if let Some((username, password)) = &self.credentials {
+            if !encrypted {
+                let _ = command(&mut smtp, "QUIT", 221).await;
+                return Err(AppError::NetworkError(format!(
+                    "Refusing to send SMTP credentials to {} without TLS",
+                    self.host
+                )));
+            }
+            let token = base64::encode(format!("\0{}\0{}", username, password));
+            command(&mut smtp, &format!("AUTH PLAIN {}", token), 235).await?;
+        }
+        command(&mut smtp, &format!("MAIL FROM:<{}>", self.from), 250).await?;
+        for to in &self.to {
+            command(&mut smtp, &format!("RCPT TO:<{}>", to), 250).await?;
+        }
+        command(&mut smtp, "DATA", 354).await?;
+        command(&mut smtp, &format!("{}.", self.mail(message)), 250).await?;
+        command(&mut smtp, "QUIT", 221).await?;
+        Ok(())
+    }
+
+    // TLS with the usual web roots, checking the certificate against `host`
+    async fn encrypt(&self, stream: Box<dyn Stream>) -> AppResult<Box<dyn Stream>> {
+        let mut config = ClientConfig::new();
+        config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        let name = DNSNameRef::try_from_ascii_str(&self.host).map_err(|_| {
+            AppError::NetworkError(format!("{} is not a name TLS can verify", self.host))
+        })?;
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(name, stream)
+            .await?;
+        Ok(Box::new(stream))
+    }
+}
+
+// A plain or encrypted connection to the SMTP server
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+impl Notifier for Smtp {
+    fn send<'a>(&'a self, message: &'a Message) -> BoxFuture<'a, AppResult<()>> {
+        Box::pin(async move {
+            match tokio::time::timeout(TIMEOUT, self.deliver(message)).await {
+                Ok(result) => result,
+                Err(_) => Err(AppError::NetworkError(format!(
+                    "SMTP server {} timed out",
+                    self.host
+                ))),
+            }
+        })
+    }
+}
+
+// RFC 2047 encoded word for subjects that are not plain ASCII. Line breaks, which torrent
+// names can smuggle into titles, are encoded as well so they cannot start new headers.
+fn encode_header(text: &str) -> String {
+    if text.is_ascii() && !text.chars().any(|c| c.is_ascii_control()) {
+        text.to_string()
+    } else {
+        format!("=?utf-8?B?{}?=", base64::encode(text))
+    }
+}
+
+async fn command(
+    smtp: &mut BufReader<Box<dyn Stream>>,
+    line: &str,
+    expect: u16,
+) -> AppResult<Vec<String>> {
+    smtp.get_mut()
+        .write_all(format!("{}\r\n", line).as_bytes())
+        .await?;
+    reply(smtp, expect).await
+}
+
+// Reads a possibly multi-line reply ("250-..." lines up to "250 ...") and returns the text
+// of its lines
+async fn reply(smtp: &mut BufReader<Box<dyn Stream>>, expect: u16) -> AppResult<Vec<String>> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        if smtp.read_line(&mut line).await? == 0 {
+            return Err(AppError::NetworkError("SMTP server hung up".into()));
+        }
+        let code: Option<u16> = line.get(..3).and_then(|c| c.parse().ok());
+        if code != Some(expect) {
+            return Err(AppError::NetworkError(format!(
+                "SMTP server answered {}",
+                line.trim()
+            )));
+        }
+        lines.push(line.get(4..).unwrap_or("").trim().to_string());
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(lines);
+        }
+    }
+}
+
+/// Sends notifications for events published on the bus.
+pub struct Notifications {
+    config: NotificationConfig,
+    notifiers: Vec<Arc<dyn Notifier>>,
+}
+
+impl Notifications {
+    pub fn new(config: NotificationConfig) -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        let notifiers = config
+            .providers
+            .iter()
+            .map(|p| notifier(&p.provider, &http))
+            .collect();
+        Self { config, notifiers }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.notifiers.is_empty()
+    }
+
+    pub async fn run(&self, mut events: broadcast::Receiver<TorrentEvent>) {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Notifications missed {} events", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            let message = self.config.message(&event);
+            let wanted = self.config.providers.iter().zip(&self.notifiers);
+            for (config, notifier) in wanted.filter(|(c, _)| c.wants(event.event)) {
+                let (notifier, message) = (notifier.clone(), message.clone());
+                let kind = provider_name(&config.provider);
+                tokio::spawn(async move {
+                    if let Err(e) = notifier.send(&message).await {
+                        error!("Could not send {} notification: {}", kind, e);
+                    }
+                });
+            }
+        }
+    }
+}
+
+fn provider_name(provider: &Provider) -> &'static str {
+    match provider {
+        Provider::Ntfy { .. } => "ntfy",
+        Provider::Gotify { .. } => "Gotify",
+        Provider::Telegram { .. } => "Telegram",
+        Provider::Smtp { .. } => "SMTP",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::Mutex,
+        thread,
+    };
+
+    use super::*;
+    use crate::{
+        testing::{self, http_server},
+        torrent::client::Torrent,
+    };
+
+    fn event(kind: EventKind) -> TorrentEvent {
+        TorrentEvent {
+            event: kind,
+            torrent: Torrent {
+                id: 7,
+                name: "Big Buck Bunny".to_string(),
+                hash: format!("{:040x}", 7),
+                status: "done".to_string(),
+                size: 2048,
+                progress: 100,
+                category: Some("movies".to_string()),
+                ..testing::torrent()
+            },
+            time: "2024-01-01T00:00:00+00:00".to_string(),
+        }
+    }
+
+    fn message() -> Message {
+        Message {
+            title: "Torrent completed".to_string(),
+            body: "Big Buck Bunny".to_string(),
+        }
+    }
+
+    #[test]
+    fn config_and_templates() {
+        let config: NotificationConfig = serde_json::from_value(json!({
+            "providers": [
+                { "type": "ntfy", "url": "https://ntfy.sh/films" },
+                { "type": "telegram", "token": "123:abc", "chat_id": "42", "events": ["added"] }
+            ],
+            "templates": {
+                "completed": { "title": "Ready to watch", "body": "{{name}} ({{category}})" }
+            }
+        }))
+        .unwrap();
+
+        assert!(config.providers[0].wants(EventKind::Completed));
+        assert!(!config.providers[0].wants(EventKind::Added));
+        assert!(config.providers[1].wants(EventKind::Added));
+        assert_eq!(
+            config.providers[1].provider,
+            Provider::Telegram {
+                api_url: telegram_api(),
+                token: "123:abc".to_string(),
+                chat_id: "42".to_string(),
+            }
+        );
+
+        let message = config.message(&event(EventKind::Completed));
+        assert_eq!(message.title, "Ready to watch");
+        assert_eq!(message.body, "Big Buck Bunny (movies)");
+        let message = config.message(&event(EventKind::Removed));
+        assert_eq!(message.title, "Torrent removed");
+    }
+
+    #[tokio::test]
+    async fn ntfy_and_gotify() {
+        let http = reqwest::Client::new();
+        let (url, received) = http_server(vec![(200, "{}"), (200, "{}")]);
+
+        let ntfy = Provider::Ntfy {
+            url: format!("{}/films", url),
+            token: Some("tk".to_string()),
+        };
+        notifier(&ntfy, &http).send(&message()).await.unwrap();
+        let gotify = Provider::Gotify {
+            url: url.clone(),
+            token: "app".to_string(),
+        };
+        notifier(&gotify, &http).send(&message()).await.unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received[0].target, "POST /films");
+        assert_eq!(received[0].header("title"), Some("torrent completed"));
+        assert_eq!(received[0].header("authorization"), Some("bearer tk"));
+        assert_eq!(received[0].body, "Big Buck Bunny");
+
+        assert_eq!(received[1].target, "POST /message");
+        assert_eq!(received[1].header("x-gotify-key"), Some("app"));
+        let body: Value = serde_json::from_str(&received[1].body).unwrap();
+        assert_eq!(
+            body,
+            json!({ "title": "Torrent completed", "message": "Big Buck Bunny" })
+        );
+    }
+
+    #[tokio::test]
+    async fn telegram() {
+        let http = reqwest::Client::new();
+        let (url, received) = http_server(vec![
+            (200, r#"{"ok":true,"result":{}}"#),
+            (400, r#"{"ok":false,"description":"chat not found"}"#),
+        ]);
+        let provider = Provider::Telegram {
+            api_url: url,
+            token: "123:abc".to_string(),
+            chat_id: "42".to_string(),
+        };
+        let telegram = notifier(&provider, &http);
+
+        telegram.send(&message()).await.unwrap();
+        assert!(telegram.send(&message()).await.is_err());
+
+        let received = received.lock().unwrap();
+        assert_eq!(received[0].target, "POST /bot123:abc/sendMessage");
+        let body: Value = serde_json::from_str(&received[0].body).unwrap();
+        assert_eq!(
+            body,
+            json!({ "chat_id": "42", "text": "Torrent completed\nBig Buck Bunny" })
+        );
+    }
+
+    #[derive(Default)]
+    struct Mail {
+        commands: Vec<String>,
+        data: String,
+    }
+
+    // Accepts one mail and records the commands and the DATA it got. `ehlo` is the reply to
+    // EHLO, STARTTLS is always turned down.
+    fn smtp_server(ehlo: &'static [u8]) -> (u16, Arc<Mutex<Mail>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let received = Arc::new(Mutex::new(Mail::default()));
+        let log = received.clone();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            reader
+                .get_mut()
+                .write_all(b"220 stand-in ready\r\n")
+                .unwrap();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    return;
+                }
+                let answer: &[u8] = if in_data {
+                    if line != ".\r\n" {
+                        log.lock().unwrap().data.push_str(&line);
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else {
+                    log.lock().unwrap().commands.push(line.trim().to_string());
+                    match line.get(..4).unwrap_or("") {
+                        "EHLO" => ehlo,
+                        "STAR" => b"454 TLS not available\r\n",
+                        "AUTH" => b"235 ok\r\n",
+                        "DATA" => {
+                            in_data = true;
+                            b"354 go ahead\r\n"
+                        }
+                        "QUIT" => b"221 bye\r\n",
+                        _ => b"250 ok\r\n",
+                    }
+                };
+                reader.get_mut().write_all(answer).unwrap();
+            }
+        });
+        (port, received)
+    }
+
+    #[tokio::test]
+    async fn smtp() {
+        let (port, received) = smtp_server(b"250-stand-in\r\n250 8BITMIME\r\n");
+        let provider = Provider::Smtp {
+            host: "127.0.0.1".to_string(),
+            port,
+            tls: SmtpTls::Starttls,
+            from: "pi@home.lan".to_string(),
+            to: vec!["me@home.lan".to_string(), "you@home.lan".to_string()],
+            username: None,
+            password: None,
+        };
+        let message = Message {
+            title: "Torrent completed ✓".to_string(),
+            body: "Big Buck Bunny\n.hidden".to_string(),
+        };
+        notifier(&provider, &reqwest::Client::new())
+            .send(&message)
+            .await
+            .unwrap();
+
+        let mail = received.lock().unwrap();
+        let (commands, data) = (&mail.commands, &mail.data);
+        assert_eq!(
+            commands,
+            &vec![
+                "EHLO localhost".to_string(),
+                "MAIL FROM:<pi@home.lan>".to_string(),
+                "RCPT TO:<me@home.lan>".to_string(),
+                "RCPT TO:<you@home.lan>".to_string(),
+                "DATA".to_string(),
+                "QUIT".to_string(),
+            ]
+        );
+        assert!(data.contains("To: me@home.lan, you@home.lan\r\n"));
+        assert!(data.contains(&format!(
+            "Subject: =?utf-8?B?{}?=\r\n",
+            base64::encode("Torrent completed ✓")
+        )));
+        assert!(data.ends_with("\r\n\r\nBig Buck Bunny\r\n..hidden\r\n"));
+    }
+
+    async fn send_with_credentials(port: u16, tls: SmtpTls) -> AppResult<()> {
+        let provider = Provider::Smtp {
+            host: "127.0.0.1".to_string(),
+            port,
+            tls,
+            from: "pi@home.lan".to_string(),
+            to: vec!["me@home.lan".to_string()],
+            username: Some("pi".to_string()),
+            password: Some("secret".to_string()),
+        };
+        let message = Message {
+            title: "Torrent completed".to_string(),
+            body: "Big Buck Bunny".to_string(),
+        };
+        notifier(&provider, &reqwest::Client::new())
+            .send(&message)
+            .await
+    }
+
+    #[tokio::test]
+    async fn smtp_keeps_credentials_off_plain_connections() {
+        // No STARTTLS on offer
+        let (port, received) = smtp_server(b"250-stand-in\r\n250 AUTH PLAIN\r\n");
+        let error = send_with_credentials(port, SmtpTls::Starttls)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("without TLS"));
+        assert_eq!(
+            received.lock().unwrap().commands,
+            vec!["EHLO localhost".to_string(), "QUIT".to_string()]
+        );
+
+        // STARTTLS offered but failing does not fall back to plain
+        let ehlo = b"250-stand-in\r\n250-STARTTLS\r\n250 AUTH PLAIN\r\n";
+        let (port, received) = smtp_server(ehlo);
+        assert!(send_with_credentials(port, SmtpTls::Starttls).await.is_err());
+        assert_eq!(
+            received.lock().unwrap().commands,
+            vec!["EHLO localhost".to_string(), "STARTTLS".to_string()]
+        );
+
+        // Nor does turning TLS off
+        let (port, received) = smtp_server(b"250-stand-in\r\n250 AUTH PLAIN\r\n");
+        assert!(send_with_credentials(port, SmtpTls::Plain).await.is_err());
+        assert!(!received
+            .lock()
+            .unwrap()
+            .commands
+            .iter()
+            .any(|c| c.starts_with("AUTH")));
+    }
+
+    #[test]
+    fn subjects_cannot_add_headers() {
+        let smtp = Smtp {
+            host: "localhost".to_string(),
+            port: 25,
+            tls: SmtpTls::Plain,
+            from: "pi@home.lan".to_string(),
+            to: vec!["me@home.lan".to_string()],
+            credentials: None,
+        };
+        let title = "Movie completed\r\nBcc: victim@example.com";
+        let mail = smtp.mail(&Message {
+            title: title.to_string(),
+            body: "Movie".to_string(),
+        });
+        assert!(mail.contains(&format!(
+            "Subject: =?utf-8?B?{}?=\r\n",
+            base64::encode(title)
+        )));
+        assert!(!mail.contains("\nBcc:"));
+        assert_eq!(encode_header("Torrent completed"), "Torrent completed");
+    }
+}
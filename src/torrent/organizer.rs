@@ -0,0 +1,640 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use super::release::{resolution_rank, Release};
+
+pub const VIDEO_EXTENSIONS: [&str; 9] = [
+    "mkv", "mp4", "avi", "m4v", "mov", "wmv", "ts", "webm", "mpg",
+];
+const SUBTITLE_EXTENSIONS: [&str; 6] = ["srt", "ass", "ssa", "sub", "idx", "vtt"];
+
+/// How files get into the library. Hardlinks keep seeding without taking extra space.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OrganizeMode {
+    #[default]
+    Hardlink,
+    Copy,
+    Move,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "decision", rename_all = "snake_case")]
+pub enum Decision {
+    Place,
+    /// Replaces a lower resolution copy already in the library
+    Upgrade {
+        replaces: String,
+    },
+    Skip {
+        reason: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Placement {
+    pub source: String,
+    pub target: Option<String>,
+    pub resolution: Option<String>,
+    #[serde(flatten)]
+    pub decision: Decision,
+}
+
+impl Placement {
+    fn skip(source: &Path, target: Option<&Path>, reason: String) -> Self {
+        Self {
+            source: display(source),
+            target: target.map(display),
+            resolution: None,
+            decision: Decision::Skip { reason },
+        }
+    }
+}
+
+fn display(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+fn stem(path: &Path) -> String {
+    path.file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+pub fn is_sample(path: &Path) -> bool {
+    let stem = stem(path).to_lowercase();
+    stem == "sample"
+        || stem.ends_with(".sample")
+        || stem.ends_with("-sample")
+        || stem.ends_with("_sample")
+        || path
+            .parent()
+            .and_then(Path::file_name)
+            .is_some_and(|d| d.eq_ignore_ascii_case("sample"))
+}
+
+fn files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut found = Vec::new();
+    for entry in fs::read_dir(path)? {
+        found.extend(files(&entry?.path())?);
+    }
+    found.sort();
+    Ok(found)
+}
+
+// Titles become folder names, so drop what file systems or media servers choke on
+fn clean(title: &str) -> String {
+    let title: String = title
+        .chars()
+        .filter(|c| !matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|'))
+        .collect();
+    title
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_matches(|c| c == ' ' || c == '-')
+        .to_string()
+}
+
+// A title that is only the episode marker, as in "S01E03.mkv", tells nothing
+fn usable(release: &Release) -> bool {
+    !clean(&release.title).is_empty() && Release::parse(&release.title).season.is_none()
+}
+
+/// The library path of a video without its extension, like "Movies/Title (Year)/Title (Year)".
+fn library_name(video: &Release, torrent: &Release) -> Option<PathBuf> {
+    let tv = if video.episode.is_some() {
+        Some(video)
+    } else if torrent.episode.is_some() {
+        Some(torrent)
+    } else {
+        None
+    };
+
+    if let Some(episode) = tv {
+        let show = clean(&[video, torrent].iter().find(|r| usable(r))?.title);
+        let season = episode.season.unwrap_or(1);
+        let mut name = format!(
+            "{} - S{:02}E{:02}",
+            show,
+            season,
+            episode.episode.unwrap_or(1)
+        );
+        if let Some(last) = episode.last_episode {
+            name.push_str(&format!("-E{:02}", last));
+        }
+        return Some(
+            Path::new("TV")
+                .join(&show)
+                .join(format!("Season {:02}", season))
+                .join(name),
+        );
+    }
+
+    let movie = if usable(video) && (video.year.is_some() || torrent.year.is_none()) {
+        video
+    } else {
+        torrent
+    };
+    if !usable(movie) {
+        return None;
+    }
+    let title = clean(&movie.title);
+    let title = match movie.year.or(torrent.year) {
+        Some(year) => format!("{} ({})", title, year),
+        None => title,
+    };
+    Some(Path::new("Movies").join(&title).join(&title))
+}
+
+// Subtitles next to a video in the library, named after it as "Movie (2020).en.srt"
+fn subtitles_of(video: &Path) -> Vec<PathBuf> {
+    let video_stem = stem(video);
+    let entries = match video.parent().map(fs::read_dir) {
+        Some(Ok(entries)) => entries,
+        _ => return Vec::new(),
+    };
+    entries
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| SUBTITLE_EXTENSIONS.contains(&extension(p).as_str()))
+        .filter(|p| {
+            let name = stem(p);
+            name == video_stem || name.starts_with(&format!("{}.", video_stem))
+        })
+        .collect()
+}
+
+// The language part of a subtitle name, ".en" for "Movie.en.srt" or ".English" for "Subs/2_English.srt"
+fn subtitle_suffix(subtitle: &Path, video: &Path) -> String {
+    let name = stem(subtitle);
+    // Compared char by char, lowercasing can change how long a name is
+    let mut rest = name.chars();
+    let named_after_video = stem(video)
+        .chars()
+        .all(|v| rest.next().is_some_and(|c| c.eq_ignore_ascii_case(&v)));
+    if named_after_video {
+        return rest.as_str().to_string();
+    }
+    match name.rsplit_once('.') {
+        Some((_, last)) if last.len() <= 3 && last.chars().all(|c| c.is_ascii_alphabetic()) => {
+            format!(".{}", last)
+        }
+        Some(_) => String::new(),
+        None => {
+            let language = clean(name.trim_start_matches(|c: char| c.is_ascii_digit() || c == '_'));
+            if language.is_empty() {
+                String::new()
+            } else {
+                format!(".{}", language)
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_file(_: &Path, _: &Path) -> bool {
+    false
+}
+
+/// Places finished downloads into a media server library layout. The resolution of every
+/// video it places is remembered, so a better release can replace it later.
+pub struct Organizer {
+    path: PathBuf,
+    resolutions: Mutex<HashMap<String, String>>,
+}
+
+impl Organizer {
+    pub fn load(path: PathBuf) -> Self {
+        let resolutions = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                error!("Invalid organizer file {}: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Self {
+            path,
+            resolutions: Mutex::new(resolutions),
+        }
+    }
+
+    /// What organizing the files of `source` into `root` would do, nothing is changed.
+    pub fn plan(
+        &self,
+        source: &Path,
+        torrent_name: &str,
+        root: &Path,
+    ) -> io::Result<Vec<Placement>> {
+        let torrent = Release::parse(torrent_name);
+        let (videos, subtitles): (Vec<PathBuf>, Vec<PathBuf>) = files(source)?
+            .into_iter()
+            .filter(|f| {
+                let ext = extension(f);
+                SUBTITLE_EXTENSIONS.contains(&ext.as_str())
+                    || (VIDEO_EXTENSIONS.contains(&ext.as_str()) && !is_sample(f))
+            })
+            .partition(|f| VIDEO_EXTENSIONS.contains(&extension(f).as_str()));
+
+        let resolutions = self.resolutions.lock().unwrap();
+        let mut placements = Vec::new();
+        // Videos being placed and their target without extension, for the subtitles
+        let mut placed: Vec<(PathBuf, PathBuf, Release)> = Vec::new();
+
+        for video in &videos {
+            let release = Release::parse(&stem(video));
+            let name = match library_name(&release, &torrent) {
+                Some(name) => root.join(name),
+                None => {
+                    placements.push(Placement::skip(video, None, "Unknown title".into()));
+                    continue;
+                }
+            };
+            let target = name.with_extension(extension(video));
+            let resolution = release
+                .resolution
+                .clone()
+                .or_else(|| torrent.resolution.clone());
+
+            if placed.iter().any(|(_, n, _)| *n == name) {
+                let reason = "Another file of the torrent goes there".to_string();
+                placements.push(Placement::skip(video, Some(&target), reason));
+                continue;
+            }
+
+            let existing = VIDEO_EXTENSIONS
+                .iter()
+                .map(|ext| name.with_extension(ext))
+                .find(|p| p.exists());
+            let decision = match existing {
+                None => Decision::Place,
+                Some(existing) if same_file(video, &existing) => Decision::Skip {
+                    reason: "Already in place".into(),
+                },
+                Some(existing) => {
+                    let known = resolutions.get(&display(&existing));
+                    let better = match (known, &resolution) {
+                        (Some(old), Some(new)) => resolution_rank(new) > resolution_rank(old),
+                        _ => false,
+                    };
+                    if better {
+                        Decision::Upgrade {
+                            replaces: display(&existing),
+                        }
+                    } else {
+                        Decision::Skip {
+                            reason: format!(
+                                "{} is already in the library",
+                                known.map_or("A copy of unknown quality", String::as_str)
+                            ),
+                        }
+                    }
+                }
+            };
+            placed.push((video.clone(), name.clone(), release));
+            placements.push(Placement {
+                source: display(video),
+                target: Some(display(&target)),
+                resolution,
+                decision,
+            });
+        }
+
+        let mut taken: Vec<PathBuf> = Vec::new();
+        for subtitle in &subtitles {
+            let video = placed
+                .iter()
+                .find(|(v, _, _)| {
+                    stem(subtitle)
+                        .to_lowercase()
+                        .starts_with(&stem(v).to_lowercase())
+                })
+                .or_else(|| {
+                    let release = Release::parse(&stem(subtitle));
+                    placed.iter().find(|(_, _, r)| {
+                        release.episode.is_some()
+                            && (release.season, release.episode) == (r.season, r.episode)
+                    })
+                })
+                .or_else(|| placed.first().filter(|_| placed.len() == 1));
+            let (video, name, _) = match video {
+                Some(video) => video,
+                None => {
+                    let reason = "No video to go with".to_string();
+                    placements.push(Placement::skip(subtitle, None, reason));
+                    continue;
+                }
+            };
+
+            let base = format!("{}{}", display(name), subtitle_suffix(subtitle, video));
+            let mut target = PathBuf::from(format!("{}.{}", base, extension(subtitle)));
+            let mut n = 2;
+            while taken.contains(&target) {
+                target = PathBuf::from(format!("{}.{}.{}", base, n, extension(subtitle)));
+                n += 1;
+            }
+            taken.push(target.clone());
+
+            // Subtitles follow their video, so they are only skipped if the video is
+            let skipped = placements.iter().find_map(|p| match &p.decision {
+                Decision::Skip { reason } if p.source == display(video) => Some(reason.clone()),
+                _ => None,
+            });
+            placements.push(Placement {
+                source: display(subtitle),
+                target: Some(display(&target)),
+                resolution: None,
+                decision: match skipped {
+                    Some(reason) => Decision::Skip { reason },
+                    None => Decision::Place,
+                },
+            });
+        }
+        Ok(placements)
+    }
+
+    /// Carries out a plan, returning how many files were placed.
+    pub fn apply(&self, placements: &[Placement], mode: OrganizeMode) -> io::Result<usize> {
+        let mut count = 0;
+        for placement in placements {
+            let target = match (&placement.decision, &placement.target) {
+                (Decision::Skip { .. }, _) | (_, None) => continue,
+                (_, Some(target)) => Path::new(target),
+            };
+            let source = Path::new(&placement.source);
+
+            if let Decision::Upgrade { replaces } = &placement.decision {
+                fs::remove_file(replaces)?;
+                // Its subtitles are timed for the old release, the new one's are placed later
+                for subtitle in subtitles_of(Path::new(replaces)) {
+                    fs::remove_file(subtitle)?;
+                }
+                self.resolutions.lock().unwrap().remove(replaces);
+            }
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            if target.exists() {
+                fs::remove_file(target)?;
+            }
+            match mode {
+                OrganizeMode::Hardlink => fs::hard_link(source, target)?,
+                OrganizeMode::Copy => {
+                    fs::copy(source, target)?;
+                }
+                OrganizeMode::Move => {
+                    if fs::rename(source, target).is_err() {
+                        fs::copy(source, target)?;
+                        fs::remove_file(source)?;
+                    }
+                }
+            }
+            if let Some(resolution) = &placement.resolution {
+                self.resolutions
+                    .lock()
+                    .unwrap()
+                    .insert(display(target), resolution.clone());
+            }
+            count += 1;
+        }
+
+        let json =
+            serde_json::to_string_pretty(&*self.resolutions.lock().unwrap()).unwrap_or_default();
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, json)?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{scratch, write};
+
+    fn targets(placements: &[Placement], root: &Path) -> Vec<(String, String)> {
+        placements
+            .iter()
+            .map(|p| {
+                let target = p
+                    .target
+                    .as_ref()
+                    .map(|t| t.trim_start_matches(&display(root)).to_string())
+                    .unwrap_or_default();
+                let decision = match &p.decision {
+                    Decision::Place => "place".to_string(),
+                    Decision::Upgrade { .. } => "upgrade".to_string(),
+                    Decision::Skip { reason } => format!("skip: {}", reason),
+                };
+                (target, decision)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn names_movies_and_episodes() {
+        let torrent = Release::parse("The.Matrix.1999.1080p.BluRay.x264");
+        let video = Release::parse("matrix");
+        assert_eq!(
+            library_name(&video, &torrent).unwrap(),
+            Path::new("Movies/The Matrix (1999)/The Matrix (1999)")
+        );
+
+        let pack = Release::parse("Some.Show.S01.1080p.WEB");
+        let episode = Release::parse("Some.Show.S01E03.1080p.WEB");
+        assert_eq!(
+            library_name(&episode, &pack).unwrap(),
+            Path::new("TV/Some Show/Season 01/Some Show - S01E03")
+        );
+        let bare = Release::parse("s01e04");
+        assert_eq!(
+            library_name(&bare, &pack).unwrap(),
+            Path::new("TV/Some Show/Season 01/Some Show - S01E04")
+        );
+        let double = Release::parse("Some.Show.S02E01E02.720p");
+        assert_eq!(
+            library_name(&double, &double).unwrap(),
+            Path::new("TV/Some Show/Season 02/Some Show - S02E01-E02")
+        );
+        assert!(library_name(&Release::parse("s01e01"), &Release::parse("s01e01")).is_none());
+    }
+
+    #[test]
+    fn subtitle_languages() {
+        let video = Path::new("/d/Movie.2020.1080p.mkv");
+        assert_eq!(
+            subtitle_suffix(Path::new("/d/Movie.2020.1080p.en.srt"), video),
+            ".en"
+        );
+        assert_eq!(
+            subtitle_suffix(Path::new("/d/Subs/2_English.srt"), video),
+            ".English"
+        );
+        assert_eq!(
+            subtitle_suffix(Path::new("/d/Subs/other.name.pt.srt"), video),
+            ".pt"
+        );
+        assert_eq!(
+            subtitle_suffix(Path::new("/d/Movie.2020.1080p.srt"), video),
+            ""
+        );
+        // Kelvin signs lowercase to a shorter "k"
+        assert_eq!(
+            subtitle_suffix(
+                Path::new("/d/\u{212A}\u{212A}.en.srt"),
+                Path::new("/d/kk.mkv")
+            ),
+            ".en"
+        );
+    }
+
+    #[test]
+    fn plan_season_pack_with_subtitles() {
+        let dir = scratch("pack");
+        let source = dir.join("downloads/Some.Show.S01.1080p.WEB");
+        write(&source.join("Some.Show.S01E01.1080p.WEB.mkv"), "video");
+        write(&source.join("Some.Show.S01E01.1080p.WEB.en.srt"), "video");
+        write(&source.join("Some.Show.S01E02.1080p.WEB.mkv"), "video");
+        write(&source.join("Subs/Some.Show.S01E02.fr.srt"), "video");
+        write(&source.join("Sample/sample.mkv"), "video");
+        write(&source.join("readme.nfo"), "video");
+
+        let organizer = Organizer::load(dir.join("organized.json"));
+        let root = dir.join("library");
+        let plan = organizer
+            .plan(&source, "Some.Show.S01.1080p.WEB", &root)
+            .unwrap();
+        let place = "place".to_string();
+        assert_eq!(
+            targets(&plan, &root),
+            vec![
+                (
+                    "/TV/Some Show/Season 01/Some Show - S01E01.mkv".to_string(),
+                    place.clone()
+                ),
+                (
+                    "/TV/Some Show/Season 01/Some Show - S01E02.mkv".to_string(),
+                    place.clone()
+                ),
+                (
+                    "/TV/Some Show/Season 01/Some Show - S01E01.en.srt".to_string(),
+                    place.clone()
+                ),
+                (
+                    "/TV/Some Show/Season 01/Some Show - S01E02.fr.srt".to_string(),
+                    place
+                ),
+            ]
+        );
+
+        assert_eq!(organizer.apply(&plan, OrganizeMode::Hardlink).unwrap(), 4);
+        let episode = root.join("TV/Some Show/Season 01/Some Show - S01E02.mkv");
+        assert!(same_file(
+            &episode,
+            &source.join("Some.Show.S01E02.1080p.WEB.mkv")
+        ));
+
+        // Organizing again finds everything in place
+        let again = organizer
+            .plan(&source, "Some.Show.S01.1080p.WEB", &root)
+            .unwrap();
+        assert!(again.iter().all(|p| p.decision
+            == Decision::Skip {
+                reason: "Already in place".into()
+            }));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn upgrades_lower_resolution() {
+        let dir = scratch("upgrade");
+        let root = dir.join("library");
+        let organizer = Organizer::load(dir.join("organized.json"));
+
+        let old = dir.join("downloads/The.Matrix.1999.720p.BluRay/The.Matrix.1999.720p.BluRay.avi");
+        write(&old, "video");
+        let plan = organizer
+            .plan(old.parent().unwrap(), "The.Matrix.1999.720p.BluRay", &root)
+            .unwrap();
+        organizer.apply(&plan, OrganizeMode::Copy).unwrap();
+        let placed = root.join("Movies/The Matrix (1999)/The Matrix (1999).avi");
+        assert!(placed.exists());
+        let old_subtitle = root.join("Movies/The Matrix (1999)/The Matrix (1999).fr.srt");
+        write(&old_subtitle, "video");
+        let other_movie = root.join("Movies/The Matrix (1999)/The Matrix (1999) Extras.srt");
+        write(&other_movie, "video");
+
+        // A file of unknown quality is never replaced
+        let manual = root.join("Movies/Alien (1979)/Alien (1979).mkv");
+        write(&manual, "video");
+        let alien = dir.join("downloads/Alien.1979.2160p.mkv");
+        write(&alien, "video");
+        let plan = organizer
+            .plan(&alien, "Alien.1979.2160p.mkv", &root)
+            .unwrap();
+        assert_eq!(
+            plan[0].decision,
+            Decision::Skip {
+                reason: "A copy of unknown quality is already in the library".into()
+            }
+        );
+
+        // Same resolution is a collision, a better one an upgrade
+        let same = dir.join("downloads/The.Matrix.1999.720p.WEB.mkv");
+        write(&same, "video");
+        let plan = organizer
+            .plan(&same, "The.Matrix.1999.720p.WEB.mkv", &root)
+            .unwrap();
+        assert!(matches!(plan[0].decision, Decision::Skip { .. }));
+
+        let better = dir.join("downloads/The.Matrix.1999.1080p.WEB.mkv");
+        write(&better, "video");
+        let reloaded = Organizer::load(dir.join("organized.json"));
+        let plan = reloaded
+            .plan(&better, "The.Matrix.1999.1080p.WEB.mkv", &root)
+            .unwrap();
+        assert_eq!(
+            plan[0].decision,
+            Decision::Upgrade {
+                replaces: display(&placed)
+            }
+        );
+        reloaded.apply(&plan, OrganizeMode::Move).unwrap();
+        assert!(!placed.exists());
+        assert!(!old_subtitle.exists());
+        assert!(other_movie.exists());
+        assert!(!better.exists());
+        assert!(root
+            .join("Movies/The Matrix (1999)/The Matrix (1999).mkv")
+            .exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,910 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    process::Command,
+    sync::broadcast::{self, RecvError},
+};
+
+use super::{
+    category::{self, Category, PostAction},
+    client::{Client, Torrent},
+    events::{EventKind, TorrentEvent},
+    extract::Extractor,
+    organizer::{OrganizeMode, Organizer, Placement},
+};
+use crate::{
+    config::ServerConfig,
+    error::{AppError, AppResult},
+    paths::within_roots,
+};
+
+const DEFAULT_JUNK: [&str; 10] = [
+    "sample",
+    "sample.*",
+    "*.sample.*",
+    "*-sample.*",
+    "*_sample.*",
+    "*.nfo",
+    "*.txt",
+    "*.exe",
+    "*.url",
+    "*.lnk",
+];
+
+/// How long a move by Transmission may take before the step fails.
+const MOVE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StepState {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Step {
+    #[serde(flatten)]
+    pub action: PostAction,
+    pub state: StepState,
+    pub error: Option<String>,
+}
+
+/// The post-processing of one completed torrent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Job {
+    pub torrent_id: i64,
+    pub name: String,
+    pub hash: String,
+    pub category: String,
+    /// Where the files are now, moves, copies and hardlinks change it
+    pub path: String,
+    /// Whether `path` is still the data Transmission seeds from
+    pub seeding_data: bool,
+    pub steps: Vec<Step>,
+    pub updated: String,
+}
+
+impl Job {
+    pub fn new(torrent: &Torrent, category: &Category) -> Self {
+        Self {
+            torrent_id: torrent.id,
+            name: torrent.name.clone(),
+            hash: torrent.hash.clone(),
+            category: category.name.clone(),
+            path: Path::new(&torrent.download_dir)
+                .join(&torrent.name)
+                .to_string_lossy()
+                .into_owned(),
+            seeding_data: true,
+            steps: category
+                .on_complete
+                .iter()
+                .map(|action| Step {
+                    action: action.clone(),
+                    state: StepState::Pending,
+                    error: None,
+                })
+                .collect(),
+            updated: now(),
+        }
+    }
+
+    pub fn running(&self) -> bool {
+        self.steps.iter().any(|s| s.state == StepState::Running)
+    }
+}
+
+fn now() -> String {
+    chrono::Local::now().to_rfc3339()
+}
+
+/// Runs the `on_complete` steps of a torrent's category once it finishes. Jobs are
+/// kept in a JSON file so their status survives restarts, failed steps can be retried.
+pub struct Pipeline {
+    path: PathBuf,
+    categories: Vec<Category>,
+    library_roots: Vec<String>,
+    jobs: Mutex<HashMap<i64, Job>>,
+    organizer: Arc<Organizer>,
+    extractor: Arc<Extractor>,
+}
+
+impl Pipeline {
+    pub fn load(path: PathBuf, config: &ServerConfig) -> Self {
+        let jobs: Vec<Job> = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                error!("Invalid pipeline file {}: {}", path.display(), e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        let jobs = jobs
+            .into_iter()
+            .map(|mut job| {
+                for step in job.steps.iter_mut() {
+                    if step.state == StepState::Running {
+                        step.state = StepState::Failed;
+                        step.error = Some("Interrupted by a restart".to_string());
+                    }
+                }
+                (job.torrent_id, job)
+            })
+            .collect();
+
+        Self {
+            path: path.clone(),
+            categories: config.categories.clone(),
+            library_roots: config.library_roots.clone(),
+            jobs: Mutex::new(jobs),
+            organizer: Arc::new(Organizer::load(path.with_file_name("organized.json"))),
+            extractor: Arc::new(Extractor::load(path.with_file_name("extractions.json"))),
+        }
+    }
+
+    pub fn jobs(&self) -> Vec<Job> {
+        let mut jobs: Vec<Job> = self.jobs.lock().unwrap().values().cloned().collect();
+        jobs.sort_by_key(|j| j.torrent_id);
+        jobs
+    }
+
+    pub fn job(&self, id: i64) -> Option<Job> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    pub fn extractor(&self) -> &Extractor {
+        &self.extractor
+    }
+
+    fn save(&self, jobs: &HashMap<i64, Job>) {
+        let mut list: Vec<&Job> = jobs.values().collect();
+        list.sort_by_key(|j| j.torrent_id);
+        let saved = self
+            .path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| {
+                let json = serde_json::to_string_pretty(&list).unwrap_or_default();
+                fs::write(&self.path, json)
+            });
+        if let Err(e) = saved {
+            error!("Could not save {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// Creates the job for a completed torrent whose category has steps, unless it has one.
+    pub fn start(&self, torrent: &Torrent) -> bool {
+        let category = match &torrent.category {
+            Some(name) => match category::find(&self.categories, name) {
+                Ok(category) if !category.on_complete.is_empty() => category,
+                _ => return false,
+            },
+            None => return false,
+        };
+
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.contains_key(&torrent.id) {
+            return false;
+        }
+        jobs.insert(torrent.id, Job::new(torrent, category));
+        self.save(&jobs);
+        true
+    }
+
+    /// Sets the failed steps of a job back to pending, false when there is nothing to retry.
+    pub fn retry(&self, id: i64) -> bool {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = match jobs.get_mut(&id) {
+            Some(job) if !job.running() => job,
+            _ => return false,
+        };
+        let mut retried = false;
+        for step in job
+            .steps
+            .iter_mut()
+            .filter(|s| s.state == StepState::Failed)
+        {
+            step.state = StepState::Pending;
+            step.error = None;
+            retried = true;
+        }
+        if retried {
+            job.updated = now();
+            self.save(&jobs);
+        }
+        retried
+    }
+
+    fn forget(&self, id: i64) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.remove(&id).is_some() {
+            self.save(&jobs);
+        }
+    }
+
+    /// Runs the pending steps of a job in order, stopping at the first failure.
+    pub async fn run(&self, client: &Client, id: i64) {
+        loop {
+            let (mut job, index) = {
+                let mut jobs = self.jobs.lock().unwrap();
+                let job = match jobs.get_mut(&id) {
+                    Some(job) => job,
+                    None => return,
+                };
+                let index = match job.steps.iter().position(|s| s.state != StepState::Done) {
+                    Some(index) if job.steps[index].state == StepState::Pending => index,
+                    _ => return,
+                };
+                job.steps[index].state = StepState::Running;
+                job.updated = now();
+                let started = (job.clone(), index);
+                self.save(&jobs);
+                started
+            };
+
+            let action = job.steps[index].action.clone();
+            let result = self.execute(client, &mut job, &action).await;
+
+            let mut jobs = self.jobs.lock().unwrap();
+            let stored = match jobs.get_mut(&id) {
+                Some(stored) => stored,
+                None => return,
+            };
+            stored.path = job.path;
+            stored.seeding_data = job.seeding_data;
+            stored.updated = now();
+            let step = &mut stored.steps[index];
+            match result {
+                Ok(_) => step.state = StepState::Done,
+                Err(e) => {
+                    error!("Post-processing of {} failed: {}", stored.name, e);
+                    step.state = StepState::Failed;
+                    step.error = Some(e.to_string());
+                }
+            }
+            let failed = step.state == StepState::Failed;
+            self.save(&jobs);
+            if failed {
+                return;
+            }
+        }
+    }
+
+    async fn execute(&self, client: &Client, job: &mut Job, action: &PostAction) -> AppResult<()> {
+        let source = PathBuf::from(&job.path);
+        let file_name = source
+            .file_name()
+            .map(PathBuf::from)
+            .ok_or_else(|| AppError::ValidationError(format!("Invalid path {}", job.path)))?;
+
+        match action {
+            PostAction::Move { to } => {
+                self.check_root(to)?;
+                let target = Path::new(to).join(&file_name);
+                if job.seeding_data {
+                    // Transmission moves the data itself so it keeps seeding
+                    client
+                        .set_location(job.torrent_id, to, true)
+                        .await
+                        .map_err(AppError::TransmissionError)?;
+                    wait_for_location(client, job.torrent_id, to).await?;
+                } else {
+                    let target = target.clone();
+                    blocking(move || move_tree(&source, &target)).await?;
+                }
+                job.path = target.to_string_lossy().into_owned();
+            }
+            PostAction::Copy { to } | PostAction::Hardlink { to } => {
+                self.check_root(to)?;
+                let target = Path::new(to).join(&file_name);
+                let (from, into) = (source.clone(), target.clone());
+                if let PostAction::Copy { .. } = action {
+                    blocking(move || copy_tree(&from, &into)).await?;
+                } else {
+                    blocking(move || link_tree(&from, &into)).await?;
+                }
+                job.path = target.to_string_lossy().into_owned();
+                job.seeding_data = false;
+            }
+            PostAction::Extract { to } => {
+                let target = match to {
+                    Some(to) => {
+                        self.check_root(to)?;
+                        Some(Path::new(to).join(&file_name))
+                    }
+                    None => None,
+                };
+                let count = self
+                    .extractor
+                    .extract(job.torrent_id, &source, target.as_deref())
+                    .await?;
+                info!("Extracted {} archives of {}", count, job.name);
+            }
+            PostAction::DeleteJunk { patterns } => {
+                if job.seeding_data {
+                    return Err(AppError::ValidationError(
+                        "delete_junk would break seeding, copy or hardlink the files first".into(),
+                    ));
+                }
+                let patterns = if patterns.is_empty() {
+                    DEFAULT_JUNK.iter().map(|p| p.to_string()).collect()
+                } else {
+                    patterns.clone()
+                };
+                let deleted = blocking(move || delete_junk(&source, &patterns)).await?;
+                info!("Deleted {} junk files of {}", deleted.len(), job.name);
+            }
+            PostAction::Command { run } => run_command(run, job).await?,
+            PostAction::Organize { to, mode } => {
+                self.check_root(to)?;
+                if *mode == OrganizeMode::Move && job.seeding_data {
+                    return Err(AppError::ValidationError(
+                        "Moving would break seeding, hardlink or copy the files instead".into(),
+                    ));
+                }
+                let (organizer, name) = (self.organizer.clone(), job.name.clone());
+                let (root, mode) = (PathBuf::from(to), *mode);
+                let placed = blocking(move || {
+                    let plan = organizer.plan(&source, &name, &root)?;
+                    organizer.apply(&plan, mode)
+                })
+                .await?;
+                info!("Organized {} files of {}", placed, job.name);
+            }
+        }
+        Ok(())
+    }
+
+    /// What an organize step would do with a torrent's files, into `to` or the root
+    /// of its category's organize step.
+    pub async fn preview(
+        &self,
+        torrent: &Torrent,
+        to: Option<String>,
+    ) -> AppResult<Vec<Placement>> {
+        let to = to
+            .or_else(|| {
+                let category = category::categorize(&self.categories, torrent)?;
+                category.on_complete.iter().find_map(|action| match action {
+                    PostAction::Organize { to, .. } => Some(to.clone()),
+                    _ => None,
+                })
+            })
+            .ok_or_else(|| {
+                AppError::ValidationError("No organize step for this torrent, pass a root".into())
+            })?;
+        self.check_root(&to)?;
+
+        let source = match self.job(torrent.id) {
+            Some(job) => PathBuf::from(job.path),
+            None => Path::new(&torrent.download_dir).join(&torrent.name),
+        };
+        let (organizer, name) = (self.organizer.clone(), torrent.name.clone());
+        blocking(move || organizer.plan(&source, &name, Path::new(&to))).await
+    }
+
+    fn check_root(&self, to: &str) -> AppResult<()> {
+        if within_roots(to, &self.library_roots) {
+            Ok(())
+        } else {
+            Err(AppError::ValidationError(format!(
+                "{} is outside the library roots",
+                to
+            )))
+        }
+    }
+
+    /// Starts the jobs of completed torrents and forgets those of removed ones. Jobs run
+    /// on their own tasks so events keep being received while a step takes long.
+    pub async fn listen(
+        self: Arc<Self>,
+        client: Arc<Client>,
+        mut events: broadcast::Receiver<TorrentEvent>,
+    ) {
+        // Torrents complete before listening started are not post-processed by a resync
+        let mut complete = self.resync(&client, None).await;
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Post-processing missed {} events, catching up", missed);
+                    complete = self.resync(&client, complete).await;
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            match event.event {
+                EventKind::Completed => {
+                    if let Some(complete) = complete.as_mut() {
+                        complete.insert(event.torrent.id);
+                    }
+                    self.launch(&client, &event.torrent);
+                }
+                EventKind::Removed => self.clean_up(event.torrent.id),
+                _ => {}
+            }
+        }
+    }
+
+    fn launch(self: &Arc<Self>, client: &Arc<Client>, torrent: &Torrent) {
+        if !self.start(torrent) {
+            return;
+        }
+        info!("Post-processing {}", torrent.name);
+        let (pipeline, client, id) = (self.clone(), client.clone(), torrent.id);
+        tokio::spawn(async move { pipeline.run(&client, id).await });
+    }
+
+    fn clean_up(&self, id: i64) {
+        self.forget(id);
+        let extractor = self.extractor.clone();
+        tokio::spawn(async move {
+            match blocking(move || Ok(extractor.clean(id))).await {
+                Ok(0) => {}
+                Ok(removed) => info!("Deleted {} extracted files of {}", removed, id),
+                Err(e) => error!("Could not clean up extracted files of {}: {}", id, e),
+            }
+        });
+    }
+
+    /// Catches up with events that were missed, from the current torrent list: starts the
+    /// jobs of torrents that completed since `complete` was taken and cleans up after
+    /// removed ones. Returns the torrents complete now, or `complete` if listing failed.
+    async fn resync(
+        self: &Arc<Self>,
+        client: &Arc<Client>,
+        complete: Option<HashSet<i64>>,
+    ) -> Option<HashSet<i64>> {
+        let mut torrents = match client.list_torrents().await {
+            Ok(torrents) => torrents,
+            Err(e) => {
+                error!("Could not list torrents for post-processing: {}", e);
+                return complete;
+            }
+        };
+        let present: HashSet<i64> = torrents.iter().map(|t| t.id).collect();
+        let mut known: HashSet<i64> = self.jobs.lock().unwrap().keys().copied().collect();
+        known.extend(self.extractor.all().keys());
+        for id in known.difference(&present) {
+            self.clean_up(*id);
+        }
+
+        torrents.retain(|t| t.progress >= 100);
+        if let Some(complete) = &complete {
+            for torrent in torrents.iter_mut().filter(|t| !complete.contains(&t.id)) {
+                torrent.category =
+                    category::categorize(&self.categories, torrent).map(|c| c.name.clone());
+                self.launch(client, torrent);
+            }
+        }
+        Some(torrents.iter().map(|t| t.id).collect())
+    }
+}
+
+async fn wait_for_location(client: &Client, id: i64, to: &str) -> AppResult<()> {
+    let started = Instant::now();
+    while started.elapsed() < MOVE_TIMEOUT {
+        let detail = client
+            .torrent_detail(id)
+            .await
+            .map_err(AppError::TransmissionError)?;
+        match detail {
+            Some(detail) if Path::new(&detail.torrent.download_dir) == Path::new(to) => {
+                return Ok(())
+            }
+            Some(_) => tokio::time::delay_for(Duration::from_secs(2)).await,
+            None => return Err(AppError::ValidationError("The torrent was removed".into())),
+        }
+    }
+    Err(AppError::NetworkError(format!(
+        "Transmission did not finish moving to {}",
+        to
+    )))
+}
+
+pub(super) async fn blocking<T, F>(f: F) -> AppResult<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result.map_err(AppError::from),
+        Err(e) => Err(AppError::IoError(io::Error::other(e))),
+    }
+}
+
+fn copy_tree(from: &Path, to: &Path) -> io::Result<()> {
+    if from.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else {
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+// Hardlinks need the target on the same filesystem, but take no extra space
+fn link_tree(from: &Path, to: &Path) -> io::Result<()> {
+    if from.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            link_tree(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else {
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if to.exists() {
+            fs::remove_file(to)?;
+        }
+        fs::hard_link(from, to)?;
+    }
+    Ok(())
+}
+
+fn move_tree(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Only a move across filesystems is copied, anything else, like a target that is there
+    // already, fails the step rather than merging into it
+    match fs::rename(from, to) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.raw_os_error() != Some(libc::EXDEV) => return Err(e),
+        Err(_) => {}
+    }
+    copy_tree(from, to)?;
+    if from.is_dir() {
+        fs::remove_dir_all(from)
+    } else {
+        fs::remove_file(from)
+    }
+}
+
+/// Deletes what matches `patterns` below `root`, which itself is never deleted.
+fn delete_junk(root: &Path, patterns: &[String]) -> io::Result<Vec<PathBuf>> {
+    let mut deleted = Vec::new();
+    if !root.is_dir() {
+        return Ok(deleted);
+    }
+    for entry in fs::read_dir(root)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if patterns.iter().any(|p| wildcard(p, &name)) {
+            if path.is_dir() {
+                fs::remove_dir_all(&path)?;
+            } else {
+                fs::remove_file(&path)?;
+            }
+            deleted.push(path);
+        } else if path.is_dir() {
+            deleted.extend(delete_junk(&path, patterns)?);
+        }
+    }
+    Ok(deleted)
+}
+
+// Case-insensitive match where "*" stands for any run of characters
+fn wildcard(pattern: &str, name: &str) -> bool {
+    let (pattern, name) = (pattern.to_lowercase(), name.to_lowercase());
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == name;
+    }
+
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if name.len() < first.len() + last.len() || !name.starts_with(first) || !name.ends_with(last) {
+        return false;
+    }
+    let mut rest = &name[first.len()..name.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+pub(super) fn last_line(output: &[u8]) -> String {
+    String::from_utf8_lossy(output)
+        .lines()
+        .rev()
+        .find(|l| !l.trim().is_empty())
+        .unwrap_or("")
+        .trim()
+        .to_string()
+}
+
+/// Runs a user command through the shell with the torrent described in `TORRENT_*` variables.
+async fn run_command(run: &str, job: &Job) -> AppResult<()> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(run)
+        .env("TORRENT_ID", job.torrent_id.to_string())
+        .env("TORRENT_NAME", &job.name)
+        .env("TORRENT_HASH", &job.hash)
+        .env("TORRENT_CATEGORY", &job.category)
+        .env("TORRENT_PATH", &job.path)
+        .output()
+        .await?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(AppError::ValidationError(format!(
+            "Command failed with {}: {}",
+            output.status,
+            last_line(&output.stderr)
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{self, http_server, scratch, write},
+        torrent::rpc::Rpc,
+    };
+    use serde_json::{json, Value};
+    use transmission_rpc::TransClient;
+
+    fn torrent(category: Option<&str>) -> Torrent {
+        Torrent {
+            id: 5,
+            name: "Some.Movie.2020.1080p".to_string(),
+            hash: format!("{:040x}", 5),
+            status: "done".to_string(),
+            progress: 100,
+            category: category.map(String::from),
+            ..testing::torrent()
+        }
+    }
+
+    fn config(dir: &Path) -> ServerConfig {
+        ServerConfig {
+            library_roots: vec![dir.to_string_lossy().into_owned()],
+            categories: vec![Category {
+                name: "movies".to_string(),
+                download_dir: "/downloads".to_string(),
+                labels: vec![],
+                seed_ratio_limit: None,
+                on_complete: vec![
+                    PostAction::Extract { to: None },
+                    PostAction::Command {
+                        run: "true".to_string(),
+                    },
+                ],
+                media: None,
+            }],
+            ..ServerConfig::default()
+        }
+    }
+
+    #[test]
+    fn wildcard_patterns() {
+        assert!(wildcard("*.nfo", "Movie.NFO"));
+        assert!(wildcard("sample.*", "Sample.mkv"));
+        assert!(wildcard("*-sample.*", "movie-sample.mkv"));
+        assert!(wildcard("a*b*c", "aXXbYYc"));
+        assert!(wildcard("sample", "Sample"));
+        assert!(!wildcard("sample.*", "Example.mkv"));
+        assert!(!wildcard("*.sample.*", "movie.mkv"));
+        assert!(!wildcard("a*a", "a"));
+    }
+
+    #[test]
+    fn copy_link_and_clean() {
+        let dir = scratch("files");
+        let source = dir.join("downloads/Movie");
+        write(&source.join("movie.mkv"), "video");
+        write(&source.join("movie.nfo"), "info");
+        write(&source.join("Sample/sample.mkv"), "sample");
+        write(&source.join("Subs/movie.en.srt"), "subs");
+
+        let copy = dir.join("library/copy/Movie");
+        copy_tree(&source, &copy).unwrap();
+        assert_eq!(
+            fs::read_to_string(copy.join("Subs/movie.en.srt")).unwrap(),
+            "subs"
+        );
+
+        let link = dir.join("library/link/Movie");
+        link_tree(&source, &link).unwrap();
+        link_tree(&source, &link).unwrap();
+        assert_eq!(fs::read_to_string(link.join("movie.mkv")).unwrap(), "video");
+
+        let patterns: Vec<String> = DEFAULT_JUNK.iter().map(|p| p.to_string()).collect();
+        let mut deleted = delete_junk(&copy, &patterns).unwrap();
+        deleted.sort();
+        assert_eq!(deleted, vec![copy.join("Sample"), copy.join("movie.nfo")]);
+        assert!(copy.join("movie.mkv").exists());
+        // The original is left alone
+        assert!(source.join("movie.nfo").exists());
+
+        let moved = dir.join("library/moved/Movie");
+        move_tree(&copy, &moved).unwrap();
+        assert!(!copy.exists());
+        assert!(moved.join("Subs/movie.en.srt").exists());
+
+        // Moving onto a folder that is there already fails and leaves both alone
+        assert!(move_tree(&link, &moved).is_err());
+        assert!(link.join("movie.mkv").exists());
+        assert!(!moved.join("movie.nfo").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn commands_get_torrent_variables() {
+        let dir = scratch("command");
+        let pipeline = Pipeline::load(dir.join("pipeline.json"), &config(&dir));
+        assert!(pipeline.start(&torrent(Some("movies"))));
+        let job = pipeline.job(5).unwrap();
+
+        let out = dir.join("out.txt");
+        let run = format!(
+            "echo \"$TORRENT_ID $TORRENT_NAME $TORRENT_CATEGORY $TORRENT_PATH\" > {}",
+            out.display()
+        );
+        run_command(&run, &job).await.unwrap();
+        assert_eq!(
+            fs::read_to_string(&out).unwrap().trim(),
+            "5 Some.Movie.2020.1080p movies /downloads/Some.Movie.2020.1080p"
+        );
+
+        let failed = run_command("echo broken >&2; exit 3", &job).await;
+        assert!(failed.unwrap_err().to_string().contains("broken"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn jobs_persist_and_retry() {
+        let dir = scratch("jobs");
+        let path = dir.join("pipeline.json");
+        let pipeline = Pipeline::load(path.clone(), &config(&dir));
+
+        assert!(!pipeline.start(&torrent(None)));
+        assert!(pipeline.start(&torrent(Some("movies"))));
+        assert!(!pipeline.start(&torrent(Some("movies"))));
+        let job = pipeline.job(5).unwrap();
+        assert_eq!(job.steps.len(), 2);
+        assert!(job.seeding_data);
+        assert!(!pipeline.retry(5));
+
+        // A step running when the server stopped comes back as failed
+        {
+            let mut jobs = pipeline.jobs.lock().unwrap();
+            jobs.get_mut(&5).unwrap().steps[0].state = StepState::Running;
+            pipeline.save(&jobs);
+        }
+        let reloaded = Pipeline::load(path, &config(&dir));
+        let job = reloaded.job(5).unwrap();
+        assert_eq!(job.steps[0].state, StepState::Failed);
+        assert!(job.steps[0].error.is_some());
+
+        assert!(reloaded.retry(5));
+        let job = reloaded.job(5).unwrap();
+        assert_eq!(job.steps[0].state, StepState::Pending);
+        assert_eq!(job.steps[0].error, None);
+
+        reloaded.forget(5);
+        assert!(reloaded.jobs().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn steps_serialize_flat() {
+        let step = Step {
+            action: PostAction::Copy {
+                to: "/library".to_string(),
+            },
+            state: StepState::Done,
+            error: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&step).unwrap(),
+            serde_json::json!({ "action": "copy", "to": "/library", "state": "done", "error": null })
+        );
+    }
+
+    async fn wait_until(done: impl Fn() -> bool) {
+        let started = Instant::now();
+        while !done() {
+            assert!(started.elapsed() < Duration::from_secs(20), "Timed out");
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_listening_while_a_job_runs() {
+        let dir = scratch("listen");
+        let release = dir.join("release");
+        let category = |name: &str, run: String| Category {
+            name: name.to_string(),
+            download_dir: format!("/downloads/{}", name),
+            labels: vec![],
+            seed_ratio_limit: None,
+            on_complete: vec![PostAction::Command { run }],
+            media: None,
+        };
+        let config = ServerConfig {
+            categories: vec![
+                category(
+                    "slow",
+                    format!("while [ ! -e {} ]; do sleep 0.05; done", release.display()),
+                ),
+                category("fast", "true".to_string()),
+            ],
+            ..ServerConfig::default()
+        };
+        let pipeline = Arc::new(Pipeline::load(dir.join("pipeline.json"), &config));
+
+        // Nothing is complete when listening starts, the fast torrents are when it catches up
+        let reply = |torrents: Vec<Value>| {
+            let reply = json!({ "result": "success", "arguments": { "torrents": torrents } });
+            reply.to_string()
+        };
+        let listed = (1..=101)
+            .map(|id| {
+                let dir = if id == 1 { "/downloads/slow" } else { "/downloads/fast" };
+                json!({ "id": id, "name": "Some.Movie", "percentDone": 1.0, "downloadDir": dir })
+            })
+            .collect();
+        let (url, received) = http_server(vec![(200, reply(vec![])), (200, reply(listed))]);
+        let client = Arc::new(Client::new(TransClient::new(&url), Rpc::new(&url)));
+        let (sender, receiver) = broadcast::channel(64);
+        tokio::spawn(pipeline.clone().listen(client, receiver));
+
+        let completed = |id: i64, category: &str| TorrentEvent {
+            event: EventKind::Completed,
+            torrent: Torrent {
+                id,
+                download_dir: format!("/downloads/{}", category),
+                ..torrent(Some(category))
+            },
+            time: now(),
+        };
+        sender.send(completed(1, "slow")).unwrap();
+        wait_until(|| pipeline.job(1).is_some_and(|job| job.running())).await;
+
+        // More events than the channel holds arrive while the first job is blocked
+        for id in 2..=101 {
+            sender.send(completed(id, "fast")).unwrap();
+        }
+        wait_until(|| {
+            (2..=101).all(|id| {
+                pipeline
+                    .job(id)
+                    .is_some_and(|job| job.steps.iter().all(|s| s.state == StepState::Done))
+            })
+        })
+        .await;
+        assert_eq!(received.lock().unwrap().len(), 2);
+        assert!(pipeline.job(1).unwrap().running());
+
+        fs::write(&release, "").unwrap();
+        wait_until(|| !pipeline.job(1).unwrap().running()).await;
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
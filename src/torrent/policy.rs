@@ -0,0 +1,519 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    batch::BatchAction,
+    category::{self, Category},
+    client::{Client, SeedingTorrent},
+};
+use crate::{
+    config::ServerConfig,
+    disk,
+    error::{AppError, AppResult},
+};
+
+const GB: f64 = 1024.0 * 1024.0 * 1024.0;
+const AUDIT_ENTRIES: usize = 500;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyAction {
+    Stop,
+    /// Removes the torrent and keeps its data
+    Remove,
+}
+
+/// A seeding rule. The scope fields that are set must all match, then the rule
+/// fires when any of its limits is reached, or right away when it has none.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Policy {
+    pub name: String,
+    pub action: PolicyAction,
+    pub category: Option<String>,
+    pub private: Option<bool>,
+    pub done: Option<bool>,
+    pub ratio: Option<f64>,
+    pub seeding_days: Option<f64>,
+    pub idle_hours: Option<f64>,
+    pub free_space_below_gb: Option<f64>,
+}
+
+impl Policy {
+    pub fn validate(&self) -> AppResult<()> {
+        let limits = [
+            self.ratio,
+            self.seeding_days,
+            self.idle_hours,
+            self.free_space_below_gb,
+        ];
+        if limits.iter().flatten().any(|l| l.is_nan() || *l < 0.0) {
+            return Err(AppError::ValidationError(format!(
+                "Limits of policy {} must not be negative",
+                self.name
+            )));
+        }
+        Ok(())
+    }
+
+    fn in_scope(&self, t: &SeedingTorrent) -> bool {
+        let category = self.category.as_ref().is_none_or(|name| {
+            t.torrent
+                .category
+                .as_ref()
+                .is_some_and(|c| c.eq_ignore_ascii_case(name))
+        });
+        let private = self.private.is_none_or(|p| p == t.private);
+        let done = self.done.is_none_or(|d| d == (t.torrent.progress >= 100));
+        category && private && done
+    }
+
+    // Why the rule fires for the torrent, if it does
+    fn reason(&self, t: &SeedingTorrent, free_space: Option<u64>) -> Option<String> {
+        let days = t.seeding_secs as f64 / 86400.0;
+        let idle = t.idle_secs as f64 / 3600.0;
+        let free = free_space.map(|f| f as f64 / GB);
+
+        let reasons = [
+            self.ratio
+                .filter(|r| t.ratio >= *r)
+                .map(|r| format!("ratio {:.2} reached {}", t.ratio, r)),
+            self.seeding_days
+                .filter(|d| days >= *d)
+                .map(|d| format!("seeded {:.1} days of {}", days, d)),
+            self.idle_hours
+                .filter(|h| idle >= *h)
+                .map(|h| format!("idle {:.1} hours of {}", idle, h)),
+            self.free_space_below_gb
+                .zip(free)
+                .filter(|(limit, free)| free < limit)
+                .map(|(limit, free)| format!("{:.1} GB free, below {}", free, limit)),
+        ];
+
+        let has_limits = self.ratio.is_some()
+            || self.seeding_days.is_some()
+            || self.idle_hours.is_some()
+            || self.free_space_below_gb.is_some();
+        if !has_limits {
+            return Some("in scope".to_string());
+        }
+        reasons.iter().flatten().next().cloned()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PolicyConfig {
+    /// Only log what would be done
+    pub dry_run: bool,
+    pub interval_minutes: u64,
+    pub rules: Vec<Policy>,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            interval_minutes: 15,
+            rules: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Decision {
+    pub policy: String,
+    pub id: i64,
+    pub name: String,
+    pub action: PolicyAction,
+    pub reason: String,
+}
+
+/// Picks the first rule that fires for each torrent. Stopped torrents are
+/// skipped by stop rules so a decision is only made once.
+pub fn evaluate(
+    policies: &[Policy],
+    torrents: &[SeedingTorrent],
+    free_space: &HashMap<String, u64>,
+) -> Vec<Decision> {
+    torrents
+        .iter()
+        .filter_map(|t| {
+            policies
+                .iter()
+                .filter(|p| p.in_scope(t))
+                .filter(|p| p.action != PolicyAction::Stop || t.torrent.status != "paused")
+                .find_map(|p| {
+                    let free = free_space.get(&t.torrent.download_dir).copied();
+                    p.reason(t, free).map(|reason| Decision {
+                        policy: p.name.clone(),
+                        id: t.torrent.id,
+                        name: t.torrent.name.clone(),
+                        action: p.action,
+                        reason,
+                    })
+                })
+        })
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub time: String,
+    pub policy: String,
+    pub id: i64,
+    pub name: String,
+    pub action: PolicyAction,
+    pub reason: String,
+    pub dry_run: bool,
+    pub error: Option<String>,
+}
+
+/// Actions taken by the engine, appended to a JSON lines file.
+pub struct AuditLog {
+    path: PathBuf,
+    recent: Mutex<VecDeque<AuditEntry>>,
+}
+
+impl AuditLog {
+    pub fn load(path: PathBuf) -> Self {
+        let mut recent: VecDeque<AuditEntry> = fs::read_to_string(&path)
+            .map(|text| {
+                text.lines()
+                    .filter_map(|line| serde_json::from_str(line).ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        while recent.len() > AUDIT_ENTRIES {
+            recent.pop_front();
+        }
+
+        Self {
+            path,
+            recent: Mutex::new(recent),
+        }
+    }
+
+    pub fn entries(&self) -> Vec<AuditEntry> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn record(&self, entry: AuditEntry) {
+        if let Err(e) = self.append(&entry) {
+            error!(
+                "Could not write policy audit log {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+
+        let mut recent = self.recent.lock().unwrap();
+        recent.push_back(entry);
+        if recent.len() > AUDIT_ENTRIES {
+            recent.pop_front();
+        }
+    }
+
+    fn append(&self, entry: &AuditEntry) -> AppResult<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let line = serde_json::to_string(entry).map_err(|e| AppError::ParseError(e.to_string()))?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", line)?;
+        Ok(())
+    }
+}
+
+pub struct PolicyEngine {
+    pub config: PolicyConfig,
+    categories: Vec<Category>,
+    audit: AuditLog,
+    // Torrent and rule of each decision of the last dry run, which repeats them every interval
+    dry_decisions: Mutex<HashSet<(i64, String)>>,
+}
+
+impl PolicyEngine {
+    pub fn new(config: &ServerConfig) -> Self {
+        Self {
+            config: config.policies.clone(),
+            categories: config.categories.clone(),
+            audit: AuditLog::load(PathBuf::from(&config.data_dir).join("policy-audit.log")),
+            dry_decisions: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn audit(&self) -> Vec<AuditEntry> {
+        self.audit.entries()
+    }
+
+    pub async fn run(&self, client: &Client, dry_run: bool) -> AppResult<Vec<AuditEntry>> {
+        let dry_run = dry_run || self.config.dry_run;
+        let mut torrents = client
+            .seeding_torrents()
+            .await
+            .map_err(AppError::TransmissionError)?;
+        for t in torrents.iter_mut() {
+            t.torrent.category =
+                category::categorize(&self.categories, &t.torrent).map(|c| c.name.clone());
+        }
+
+        let mut free_space = HashMap::new();
+        if self
+            .config
+            .rules
+            .iter()
+            .any(|p| p.free_space_below_gb.is_some())
+        {
+            for t in &torrents {
+                let dir = &t.torrent.download_dir;
+                if !free_space.contains_key(dir) {
+                    if let Some(free) = disk::free_space(client, dir).await {
+                        free_space.insert(dir.clone(), free);
+                    }
+                }
+            }
+        }
+
+        let decisions = evaluate(&self.config.rules, &torrents, &free_space);
+        // Nothing happens in a dry run, so a decision is only audited when it is new
+        let seen = if dry_run {
+            let current = decisions.iter().map(|d| (d.id, d.policy.clone())).collect();
+            std::mem::replace(&mut *self.dry_decisions.lock().unwrap(), current)
+        } else {
+            HashSet::new()
+        };
+
+        let mut entries = Vec::new();
+        for decision in decisions {
+            let repeated = seen.contains(&(decision.id, decision.policy.clone()));
+            let error = if dry_run {
+                None
+            } else {
+                let action = match decision.action {
+                    PolicyAction::Stop => BatchAction::Stop,
+                    PolicyAction::Remove => BatchAction::Remove,
+                };
+                client
+                    .batch_action(action, vec![decision.id])
+                    .await
+                    .err()
+                    .map(|e| e.to_string())
+            };
+
+            if !repeated {
+                info!(
+                    "Policy {} {:?} torrent {} ({}){}",
+                    decision.policy,
+                    decision.action,
+                    decision.id,
+                    decision.reason,
+                    if dry_run { ", dry run" } else { "" }
+                );
+            }
+            let entry = AuditEntry {
+                time: chrono::Local::now().to_rfc3339(),
+                policy: decision.policy,
+                id: decision.id,
+                name: decision.name,
+                action: decision.action,
+                reason: decision.reason,
+                dry_run,
+                error,
+            };
+            if !repeated {
+                self.audit.record(entry.clone());
+            }
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{self, http_server},
+        torrent::{client::Torrent, rpc::Rpc},
+    };
+    use serde_json::json;
+    use transmission_rpc::TransClient;
+
+    fn policy(name: &str, action: PolicyAction) -> Policy {
+        Policy {
+            name: name.to_string(),
+            action,
+            category: None,
+            private: None,
+            done: None,
+            ratio: None,
+            seeding_days: None,
+            idle_hours: None,
+            free_space_below_gb: None,
+        }
+    }
+
+    fn seeding(id: i64, status: &str, ratio: f64, days: i64, private: bool) -> SeedingTorrent {
+        SeedingTorrent {
+            torrent: Torrent {
+                id,
+                name: format!("Torrent {}", id),
+                hash: format!("{:040x}", id),
+                status: status.to_string(),
+                progress: 100,
+                download_dir: "/mnt/usb".to_string(),
+                ..testing::torrent()
+            },
+            ratio,
+            seeding_secs: days * 86400,
+            idle_secs: 0,
+            private,
+        }
+    }
+
+    fn ids(decisions: &[Decision]) -> Vec<i64> {
+        decisions.iter().map(|d| d.id).collect()
+    }
+
+    #[test]
+    fn ratio_or_time_limit() {
+        let rules = vec![Policy {
+            ratio: Some(2.0),
+            seeding_days: Some(7.0),
+            ..policy("seed limit", PolicyAction::Stop)
+        }];
+        let torrents = vec![
+            seeding(1, "done", 2.5, 1, false),
+            seeding(2, "done", 0.5, 8, false),
+            seeding(3, "done", 0.5, 1, false),
+            seeding(4, "paused", 3.0, 9, false),
+        ];
+
+        let decisions = evaluate(&rules, &torrents, &HashMap::new());
+        assert_eq!(ids(&decisions), vec![1, 2]);
+        assert!(decisions[0].reason.starts_with("ratio 2.50"));
+        assert!(decisions[1].reason.starts_with("seeded 8.0 days"));
+    }
+
+    #[test]
+    fn scope_without_limits() {
+        let rules = vec![Policy {
+            private: Some(false),
+            done: Some(true),
+            ..policy("public cleanup", PolicyAction::Remove)
+        }];
+        let mut downloading = seeding(3, "downloading", 0.0, 0, false);
+        downloading.torrent.progress = 40;
+        let torrents = vec![
+            seeding(1, "paused", 0.1, 0, false),
+            seeding(2, "done", 0.1, 0, true),
+            downloading,
+        ];
+
+        let decisions = evaluate(&rules, &torrents, &HashMap::new());
+        assert_eq!(ids(&decisions), vec![1]);
+        assert_eq!(decisions[0].action, PolicyAction::Remove);
+    }
+
+    #[test]
+    fn free_space_limit() {
+        let rules = vec![Policy {
+            free_space_below_gb: Some(10.0),
+            ..policy("low disk", PolicyAction::Stop)
+        }];
+        let torrents = vec![seeding(1, "done", 0.0, 0, false)];
+
+        let mut free = HashMap::new();
+        free.insert("/mnt/usb".to_string(), 5 * GB as u64);
+        assert_eq!(ids(&evaluate(&rules, &torrents, &free)), vec![1]);
+
+        free.insert("/mnt/usb".to_string(), 50 * GB as u64);
+        assert!(evaluate(&rules, &torrents, &free).is_empty());
+        assert!(evaluate(&rules, &torrents, &HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn policy_validation() {
+        let negative = Policy {
+            ratio: Some(-1.0),
+            ..policy("bad", PolicyAction::Stop)
+        };
+        assert!(negative.validate().is_err());
+        assert!(policy("ok", PolicyAction::Stop).validate().is_ok());
+    }
+
+    #[test]
+    fn audit_log_persists() {
+        let path = std::env::temp_dir()
+            .join(format!("policy-audit-test-{}", std::process::id()))
+            .join("audit.log");
+        let entry = AuditEntry {
+            time: "2024-01-01T00:00:00+00:00".to_string(),
+            policy: "seed limit".to_string(),
+            id: 1,
+            name: "Torrent 1".to_string(),
+            action: PolicyAction::Stop,
+            reason: "ratio 2.50 reached 2".to_string(),
+            dry_run: true,
+            error: None,
+        };
+
+        AuditLog::load(path.clone()).record(entry.clone());
+        assert_eq!(AuditLog::load(path.clone()).entries(), vec![entry]);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dry_runs_audit_new_decisions_only() {
+        let dir = std::env::temp_dir().join(format!("policy-dry-run-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let list = |ratios: &[f64]| {
+            let torrents: Vec<_> = ratios
+                .iter()
+                .enumerate()
+                .map(|(i, ratio)| {
+                    json!({ "id": i + 1, "name": "Movie", "status": 6, "uploadRatio": ratio })
+                })
+                .collect();
+            let reply = json!({ "result": "success", "arguments": { "torrents": torrents } });
+            reply.to_string()
+        };
+        let (url, _) = http_server(vec![
+            (200, list(&[2.5, 1.0])),
+            (200, list(&[2.6, 1.0])),
+            (200, list(&[2.7, 2.1])),
+        ]);
+        let client = Client::new(TransClient::new(&url), Rpc::new(&url));
+        let mut rule = policy("seed limit", PolicyAction::Stop);
+        rule.ratio = Some(2.0);
+        let engine = PolicyEngine::new(&ServerConfig {
+            data_dir: dir.display().to_string(),
+            policies: PolicyConfig {
+                dry_run: true,
+                interval_minutes: 15,
+                rules: vec![rule],
+            },
+            ..ServerConfig::default()
+        });
+
+        for _ in 0..3 {
+            engine.run(&client, false).await.unwrap();
+        }
+        let audited: Vec<i64> = engine.audit().iter().map(|e| e.id).collect();
+        assert_eq!(audited, vec![1, 2]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,236 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    Movie,
+    Tv,
+    Music,
+    Software,
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Release {
+    pub title: String,
+    pub year: Option<u16>,
+    pub season: Option<u32>,
+    pub episode: Option<u32>,
+    /// Last episode of multi-episode files such as S01E01E02
+    #[serde(default)]
+    pub last_episode: Option<u32>,
+    pub resolution: Option<String>,
+    pub kind: MediaKind,
+}
+
+const RESOLUTIONS: [&str; 5] = ["2160p", "1080p", "720p", "576p", "480p"];
+const VIDEO_TAGS: [&str; 12] = [
+    "bluray", "bdrip", "brrip", "webrip", "web-dl", "webdl", "hdtv", "dvdrip", "x264", "x265",
+    "h264", "hevc",
+];
+const MUSIC_TAGS: [&str; 8] = [
+    "flac",
+    "mp3",
+    "320kbps",
+    "v0",
+    "discography",
+    "album",
+    "aac",
+    "lossless",
+];
+const SOFTWARE_TAGS: [&str; 8] = [
+    "x64", "x86", "win", "windows", "macos", "linux", "portable", "setup",
+];
+
+impl Release {
+    pub fn parse(name: &str) -> Self {
+        let words: Vec<String> = name
+            .split(['.', '_', ' ', '[', ']'])
+            .filter(|w| !w.is_empty())
+            .map(|w| w.trim_matches(|c| c == '(' || c == ')').to_string())
+            .collect();
+
+        let mut title_end = words.len();
+        let mut year = None;
+        let mut season = None;
+        let mut episode = None;
+        let mut last_episode = None;
+        let mut resolution = None;
+        let mut tags = Vec::new();
+
+        for (i, word) in words.iter().enumerate() {
+            let lower = word.to_lowercase();
+            let marker = if let Some((s, episodes)) = season_episode(&lower) {
+                season = season.or(Some(s));
+                if let (None, Some((first, last))) = (episode, episodes) {
+                    episode = Some(first);
+                    last_episode = Some(last).filter(|l| *l > first);
+                }
+                true
+            } else if let Some(y) = parse_year(&lower).filter(|_| i > 0) {
+                year = year.or(Some(y));
+                true
+            } else if RESOLUTIONS.contains(&lower.as_str()) {
+                resolution = resolution.or_else(|| Some(lower.clone()));
+                true
+            } else {
+                tags.push(lower.clone());
+                VIDEO_TAGS.contains(&lower.as_str())
+            };
+            if marker && i < title_end {
+                title_end = i.max(1);
+            }
+        }
+
+        let kind = if season.is_some() {
+            MediaKind::Tv
+        } else if tags.iter().any(|t| MUSIC_TAGS.contains(&t.as_str())) {
+            MediaKind::Music
+        } else if resolution.is_some() || tags.iter().any(|t| VIDEO_TAGS.contains(&t.as_str())) {
+            MediaKind::Movie
+        } else if tags.iter().any(|t| SOFTWARE_TAGS.contains(&t.as_str())) {
+            MediaKind::Software
+        } else if year.is_some() {
+            MediaKind::Movie
+        } else {
+            MediaKind::Unknown
+        };
+
+        Self {
+            title: words[..title_end.min(words.len())].join(" "),
+            year,
+            season,
+            episode,
+            last_episode,
+            resolution,
+            kind,
+        }
+    }
+}
+
+/// Higher for better resolutions, 0 when unknown.
+pub fn resolution_rank(resolution: &str) -> usize {
+    RESOLUTIONS
+        .iter()
+        .rev()
+        .position(|r| r.eq_ignore_ascii_case(resolution))
+        .map_or(0, |i| i + 1)
+}
+
+fn parse_year(word: &str) -> Option<u16> {
+    word.parse()
+        .ok()
+        .filter(|y| word.len() == 4 && (1900..=2099).contains(y))
+}
+
+// Matches S01E02, S01E02E03, S01E02-E03, S01 and 1x02, with the first and last episode
+fn season_episode(word: &str) -> Option<(u32, Option<(u32, u32)>)> {
+    if let Some(rest) = word.strip_prefix('s') {
+        let (season, rest) = split_number(rest)?;
+        if rest.is_empty() {
+            return Some((season, None));
+        }
+        let (first, mut rest) = split_number(rest.strip_prefix('e')?)?;
+        if !(rest.is_empty() || rest.starts_with('e') || rest.starts_with('-')) {
+            return None;
+        }
+        let mut last = first;
+        while let Some((episode, tail)) = next_episode(rest) {
+            last = last.max(episode);
+            rest = tail;
+        }
+        return Some((season, Some((first, last))));
+    }
+    let (season, rest) = split_number(word)?;
+    let (episode, rest) = split_number(rest.strip_prefix('x')?)?;
+    if rest.is_empty() {
+        Some((season, Some((episode, episode))))
+    } else {
+        None
+    }
+}
+
+fn next_episode(text: &str) -> Option<(u32, &str)> {
+    split_number(text.strip_prefix('-').unwrap_or(text).strip_prefix('e')?)
+}
+
+fn split_number(text: &str) -> Option<(u32, &str)> {
+    let end = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    if end == 0 || end > 3 {
+        return None;
+    }
+    Some((text[..end].parse().ok()?, &text[end..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_movie() {
+        let release = Release::parse("The.Matrix.1999.1080p.BluRay.x264-GROUP");
+        assert_eq!(release.title, "The Matrix");
+        assert_eq!(release.year, Some(1999));
+        assert_eq!(release.resolution.as_deref(), Some("1080p"));
+        assert_eq!(release.kind, MediaKind::Movie);
+    }
+
+    #[test]
+    fn parse_episode() {
+        let release = Release::parse("Some.Show.S02E05.720p.HDTV.x264");
+        assert_eq!(release.title, "Some Show");
+        assert_eq!(release.season, Some(2));
+        assert_eq!(release.episode, Some(5));
+        assert_eq!(release.kind, MediaKind::Tv);
+
+        let release = Release::parse("Another Show 3x07 WEB");
+        assert_eq!(release.title, "Another Show");
+        assert_eq!((release.season, release.episode), (Some(3), Some(7)));
+
+        let release = Release::parse("Show S01 Complete 1080p");
+        assert_eq!((release.season, release.episode), (Some(1), None));
+    }
+
+    #[test]
+    fn parse_multi_episode() {
+        let release = Release::parse("Some.Show.S01E01E02.1080p");
+        assert_eq!(release.title, "Some Show");
+        assert_eq!(release.episode, Some(1));
+        assert_eq!(release.last_episode, Some(2));
+
+        let release = Release::parse("Some Show S01E03-E05 720p");
+        assert_eq!((release.episode, release.last_episode), (Some(3), Some(5)));
+
+        let release = Release::parse("Some.Show.S01E03-720p");
+        assert_eq!((release.episode, release.last_episode), (Some(3), None));
+    }
+
+    #[test]
+    fn parse_music_and_software() {
+        assert_eq!(
+            Release::parse("Artist - Album (2020) [FLAC]").kind,
+            MediaKind::Music
+        );
+        assert_eq!(
+            Release::parse("Some Editor v2.1 x64 Portable").kind,
+            MediaKind::Software
+        );
+        assert_eq!(Release::parse("random-file").kind, MediaKind::Unknown);
+    }
+
+    #[test]
+    fn title_keeps_leading_numbers() {
+        let release = Release::parse("2012.2009.720p.BRRip");
+        assert_eq!(release.title, "2012");
+        assert_eq!(release.year, Some(2009));
+    }
+
+    #[test]
+    fn rank_resolutions() {
+        assert!(resolution_rank("1080p") > resolution_rank("720p"));
+        assert!(resolution_rank("2160P") > resolution_rank("1080p"));
+        assert_eq!(resolution_rank("4k"), 0);
+    }
+}
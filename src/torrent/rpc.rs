@@ -0,0 +1,68 @@
+use std::sync::Mutex;
+
+use reqwest::StatusCode;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+
+use crate::error::{AppError, AppResult};
+
+const SESSION_HEADER: &str = "X-Transmission-Session-Id";
+
+// Plain JSON-RPC access for the Transmission methods and arguments
+// that transmission_rpc does not expose.
+pub struct Rpc {
+    url: String,
+    http: reqwest::Client,
+    session_id: Mutex<Option<String>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Reply<T> {
+    result: String,
+    arguments: Option<T>,
+}
+
+impl Rpc {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            http: reqwest::Client::new(),
+            session_id: Mutex::new(None),
+        }
+    }
+
+    pub async fn call<T: DeserializeOwned>(&self, method: &str, arguments: Value) -> AppResult<T> {
+        let body = json!({ "method": method, "arguments": arguments });
+
+        // The first request of a session is answered with 409 and the id to use
+        for _ in 0..2 {
+            let mut request = self.http.post(&self.url).json(&body);
+            if let Some(id) = self.session_id.lock().unwrap().clone() {
+                request = request.header(SESSION_HEADER, id);
+            }
+
+            let response = request.send().await?;
+            if response.status() == StatusCode::CONFLICT {
+                *self.session_id.lock().unwrap() = response
+                    .headers()
+                    .get(SESSION_HEADER)
+                    .and_then(|v| v.to_str().ok())
+                    .map(String::from);
+                continue;
+            }
+
+            let reply: Reply<T> = response.error_for_status()?.json().await?;
+            if reply.result != "success" {
+                return Err(AppError::TransmissionError(reply.result.into()));
+            }
+            return reply
+                .arguments
+                .ok_or_else(|| AppError::ParseError(format!("{} returned no arguments", method)));
+        }
+
+        Err(AppError::NetworkError(format!(
+            "{} rejected the session id",
+            self.url
+        )))
+    }
+}
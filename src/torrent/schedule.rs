@@ -0,0 +1,358 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Mutex, RwLock},
+};
+
+use chrono::{Datelike, Local, Timelike};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use super::{client::Client, session::SessionSettings};
+use crate::error::{AppError, AppResult};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Day {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+const DAYS: [Day; 7] = [
+    Day::Mon,
+    Day::Tue,
+    Day::Wed,
+    Day::Thu,
+    Day::Fri,
+    Day::Sat,
+    Day::Sun,
+];
+
+impl Day {
+    fn previous(self) -> Day {
+        DAYS[(self as usize + 6) % 7]
+    }
+}
+
+/// Speed limits in KB/s, unlimited when not set.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SpeedProfile {
+    pub name: String,
+    pub download_limit: Option<i64>,
+    pub upload_limit: Option<i64>,
+}
+
+impl SpeedProfile {
+    fn settings(&self) -> SessionSettings {
+        SessionSettings {
+            speed_limit_down: self.download_limit,
+            speed_limit_down_enabled: Some(self.download_limit.is_some()),
+            speed_limit_up: self.upload_limit,
+            speed_limit_up_enabled: Some(self.upload_limit.is_some()),
+            ..SessionSettings::default()
+        }
+    }
+}
+
+/// Applies `profile` from `start` to `end` ("HH:MM") on the given days.
+/// A slot ending before it starts runs past midnight into the next day.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Slot {
+    pub days: Vec<Day>,
+    pub start: String,
+    pub end: String,
+    pub profile: String,
+}
+
+impl Slot {
+    fn contains(&self, day: Day, minute: u32) -> bool {
+        let (start, end) = match (parse_time(&self.start), parse_time(&self.end)) {
+            (Some(start), Some(end)) => (start, end),
+            _ => return false,
+        };
+        if start <= end {
+            self.days.contains(&day) && (start..end).contains(&minute)
+        } else {
+            (self.days.contains(&day) && minute >= start)
+                || (self.days.contains(&day.previous()) && minute < end)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Schedule {
+    pub enabled: bool,
+    pub profiles: Vec<SpeedProfile>,
+    /// The first matching slot wins
+    pub slots: Vec<Slot>,
+    /// Profile used outside every slot, limits are left alone when not set
+    pub default_profile: Option<String>,
+}
+
+impl Schedule {
+    pub fn validate(&self) -> AppResult<()> {
+        for (i, profile) in self.profiles.iter().enumerate() {
+            if profile.name.trim().is_empty() {
+                return Err(invalid("Profile names must not be empty".into()));
+            }
+            if self.profiles[..i].iter().any(|p| p.name == profile.name) {
+                return Err(invalid(format!("Duplicate profile: {}", profile.name)));
+            }
+            if matches!(profile.download_limit, Some(l) if l < 0)
+                || matches!(profile.upload_limit, Some(l) if l < 0)
+            {
+                return Err(invalid(format!(
+                    "Limits of {} must not be negative",
+                    profile.name
+                )));
+            }
+        }
+
+        for slot in &self.slots {
+            if slot.days.is_empty() {
+                return Err(invalid("Slots need at least one day".into()));
+            }
+            for time in [&slot.start, &slot.end].iter() {
+                if parse_time(time).is_none() {
+                    return Err(invalid(format!("Invalid time: {}", time)));
+                }
+            }
+            if slot.start == slot.end {
+                return Err(invalid(format!("Empty slot at {}", slot.start)));
+            }
+        }
+
+        let referenced = self
+            .slots
+            .iter()
+            .map(|s| &s.profile)
+            .chain(self.default_profile.as_ref());
+        for name in referenced {
+            if self.profile(name).is_none() {
+                return Err(invalid(format!("Unknown profile: {}", name)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn active_profile(&self, day: Day, minute: u32) -> Option<&SpeedProfile> {
+        if !self.enabled {
+            return None;
+        }
+        self.slots
+            .iter()
+            .find(|s| s.contains(day, minute))
+            .map(|s| &s.profile)
+            .or(self.default_profile.as_ref())
+            .and_then(|name| self.profile(name))
+    }
+
+    fn profile(&self, name: &str) -> Option<&SpeedProfile> {
+        self.profiles.iter().find(|p| p.name == name)
+    }
+}
+
+// Minutes since midnight, "24:00" is allowed as the end of a day
+fn parse_time(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.split_once(':')?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return None;
+    }
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    match (hours, minutes) {
+        (24, 0) => Some(24 * 60),
+        (0..=23, 0..=59) => Some(hours * 60 + minutes),
+        _ => None,
+    }
+}
+
+fn invalid(msg: String) -> AppError {
+    AppError::ValidationError(msg)
+}
+
+/// Applies the speed profiles of the weekly schedule, which is kept in a JSON file
+/// so it survives restarts. Limits are only set when the active profile changes,
+/// so manual changes stick until the next slot starts.
+pub struct Scheduler {
+    path: PathBuf,
+    schedule: RwLock<Schedule>,
+    applied: Mutex<Option<String>>,
+}
+
+impl Scheduler {
+    pub fn load(path: PathBuf) -> Self {
+        let schedule = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                error!("Invalid schedule file {}: {}", path.display(), e);
+                Schedule::default()
+            }),
+            Err(_) => Schedule::default(),
+        };
+
+        Self {
+            path,
+            schedule: RwLock::new(schedule),
+            applied: Mutex::new(None),
+        }
+    }
+
+    pub fn schedule(&self) -> Schedule {
+        self.schedule.read().unwrap().clone()
+    }
+
+    pub fn active(&self) -> Option<String> {
+        self.applied.lock().unwrap().clone()
+    }
+
+    pub fn update(&self, schedule: Schedule) -> AppResult<()> {
+        schedule.validate()?;
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let json = serde_json::to_string_pretty(&schedule)
+            .map_err(|e| AppError::ParseError(e.to_string()))?;
+        fs::write(&self.path, json)?;
+
+        *self.schedule.write().unwrap() = schedule;
+        *self.applied.lock().unwrap() = None;
+        Ok(())
+    }
+
+    pub async fn tick(&self, client: &Client) {
+        let now = Local::now();
+        let day = DAYS[now.weekday().num_days_from_monday() as usize];
+        let minute = now.hour() * 60 + now.minute();
+
+        let profile = self
+            .schedule
+            .read()
+            .unwrap()
+            .active_profile(day, minute)
+            .cloned();
+        let name = profile.as_ref().map(|p| p.name.clone());
+        if *self.applied.lock().unwrap() == name {
+            return;
+        }
+
+        if let Some(profile) = profile {
+            if let Err(e) = client.set_session_settings(&profile.settings()).await {
+                error!("Could not apply speed profile {}: {}", profile.name, e);
+                return;
+            }
+            info!("Applied speed profile {}", profile.name);
+        }
+        *self.applied.lock().unwrap() = name;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(name: &str, down: Option<i64>) -> SpeedProfile {
+        SpeedProfile {
+            name: name.to_string(),
+            download_limit: down,
+            upload_limit: None,
+        }
+    }
+
+    fn slot(days: Vec<Day>, start: &str, end: &str, profile: &str) -> Slot {
+        Slot {
+            days,
+            start: start.to_string(),
+            end: end.to_string(),
+            profile: profile.to_string(),
+        }
+    }
+
+    fn schedule() -> Schedule {
+        Schedule {
+            enabled: true,
+            profiles: vec![profile("throttled", Some(500)), profile("unlimited", None)],
+            slots: vec![
+                slot(DAYS.to_vec(), "18:00", "23:00", "throttled"),
+                slot(vec![Day::Fri], "23:00", "02:00", "throttled"),
+            ],
+            default_profile: Some("unlimited".to_string()),
+        }
+    }
+
+    fn active(schedule: &Schedule, day: Day, time: &str) -> Option<String> {
+        schedule
+            .active_profile(day, parse_time(time).unwrap())
+            .map(|p| p.name.clone())
+    }
+
+    #[test]
+    fn active_profile_by_time() {
+        let schedule = schedule();
+        assert!(schedule.validate().is_ok());
+        assert_eq!(active(&schedule, Day::Mon, "18:00").unwrap(), "throttled");
+        assert_eq!(active(&schedule, Day::Mon, "23:00").unwrap(), "unlimited");
+        assert_eq!(active(&schedule, Day::Fri, "23:30").unwrap(), "throttled");
+        assert_eq!(active(&schedule, Day::Sat, "01:59").unwrap(), "throttled");
+        assert_eq!(active(&schedule, Day::Sat, "02:00").unwrap(), "unlimited");
+        assert_eq!(active(&schedule, Day::Sun, "01:00").unwrap(), "unlimited");
+
+        let disabled = Schedule {
+            enabled: false,
+            ..schedule
+        };
+        assert!(active(&disabled, Day::Mon, "19:00").is_none());
+    }
+
+    #[test]
+    fn profile_settings() {
+        let settings = profile("throttled", Some(500)).settings();
+        assert_eq!(settings.speed_limit_down, Some(500));
+        assert_eq!(settings.speed_limit_down_enabled, Some(true));
+        assert_eq!(settings.speed_limit_up_enabled, Some(false));
+    }
+
+    #[test]
+    fn schedule_validation() {
+        let mut unknown = schedule();
+        unknown.slots[0].profile = "missing".to_string();
+        assert!(unknown.validate().is_err());
+
+        let mut bad_time = schedule();
+        bad_time.slots[0].end = "25:00".to_string();
+        assert!(bad_time.validate().is_err());
+
+        let mut duplicate = schedule();
+        duplicate.profiles.push(profile("unlimited", None));
+        assert!(duplicate.validate().is_err());
+
+        assert_eq!(parse_time("24:00"), Some(1440));
+        assert_eq!(parse_time("7:00"), None);
+    }
+
+    #[test]
+    fn scheduler_persists_updates() {
+        let path = std::env::temp_dir()
+            .join(format!("schedule-test-{}", std::process::id()))
+            .join("schedule.json");
+
+        let scheduler = Scheduler::load(path.clone());
+        assert_eq!(scheduler.schedule(), Schedule::default());
+        assert!(scheduler
+            .update(Schedule {
+                default_profile: Some("missing".to_string()),
+                ..Schedule::default()
+            })
+            .is_err());
+
+        scheduler.update(schedule()).unwrap();
+        assert_eq!(Scheduler::load(path.clone()).schedule(), schedule());
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,373 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    error::{AppError, AppResult},
+    paths::within_roots,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueMove {
+    Top,
+    Up,
+    Down,
+    Bottom,
+}
+
+impl QueueMove {
+    pub fn method(&self) -> &'static str {
+        match self {
+            QueueMove::Top => "queue-move-top",
+            QueueMove::Up => "queue-move-up",
+            QueueMove::Down => "queue-move-down",
+            QueueMove::Bottom => "queue-move-bottom",
+        }
+    }
+}
+
+/// Transmission's queue settings. Fields left out of an update are not changed.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct QueueSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_queue_enabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_queue_size: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed_queue_enabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed_queue_size: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_stalled_enabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_stalled_minutes: Option<i64>,
+}
+
+impl QueueSettings {
+    pub const FIELDS: [&'static str; 6] = [
+        "download-queue-enabled",
+        "download-queue-size",
+        "seed-queue-enabled",
+        "seed-queue-size",
+        "queue-stalled-enabled",
+        "queue-stalled-minutes",
+    ];
+
+    pub fn validate(&self) -> AppResult<()> {
+        let sizes = [
+            ("download_queue_size", self.download_queue_size),
+            ("seed_queue_size", self.seed_queue_size),
+            ("queue_stalled_minutes", self.queue_stalled_minutes),
+        ];
+        match sizes.iter().find(|(_, v)| matches!(v, Some(v) if *v < 1)) {
+            Some((name, _)) => Err(AppError::ValidationError(format!(
+                "{} must be at least 1",
+                name
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encryption {
+    Required,
+    Preferred,
+    Tolerated,
+}
+
+/// Global session settings, speeds in KB/s. Fields left out of an update are not changed.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SessionSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed_limit_down: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed_limit_down_enabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed_limit_up: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed_limit_up_enabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alt_speed_down: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alt_speed_up: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alt_speed_enabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_limit_global: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_limit_per_torrent: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_port: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed_ratio_limit: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed_ratio_limited: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_dir: Option<String>,
+    #[serde(flatten)]
+    pub queue: QueueSettings,
+}
+
+impl SessionSettings {
+    pub fn validate(&self, download_dirs: &[String]) -> AppResult<()> {
+        let speeds = [
+            ("speed_limit_down", self.speed_limit_down),
+            ("speed_limit_up", self.speed_limit_up),
+            ("alt_speed_down", self.alt_speed_down),
+            ("alt_speed_up", self.alt_speed_up),
+        ];
+        if let Some((name, _)) = speeds.iter().find(|(_, v)| matches!(v, Some(v) if *v < 0)) {
+            return Err(invalid(format!("{} must not be negative", name)));
+        }
+
+        let peers = [
+            ("peer_limit_global", self.peer_limit_global),
+            ("peer_limit_per_torrent", self.peer_limit_per_torrent),
+        ];
+        if let Some((name, _)) = peers.iter().find(|(_, v)| matches!(v, Some(v) if *v < 1)) {
+            return Err(invalid(format!("{} must be at least 1", name)));
+        }
+
+        if matches!(self.peer_port, Some(port) if !(1..=65535).contains(&port)) {
+            return Err(invalid("peer_port must be between 1 and 65535".into()));
+        }
+        if matches!(self.seed_ratio_limit, Some(ratio) if ratio.is_nan() || ratio < 0.0) {
+            return Err(invalid("seed_ratio_limit must not be negative".into()));
+        }
+        if let Some(dir) = &self.download_dir {
+            if !within_roots(dir, download_dirs) {
+                return Err(invalid(format!("Download dir not allowed: {}", dir)));
+            }
+        }
+        self.queue.validate()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct TransferStats {
+    pub uploaded_bytes: u64,
+    pub downloaded_bytes: u64,
+    pub files_added: u64,
+    pub session_count: u64,
+    pub seconds_active: u64,
+}
+
+/// Transfer rates in bytes per second and torrent counts, as reported by `session-stats`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct SessionStats {
+    pub download_speed: u64,
+    pub upload_speed: u64,
+    pub torrent_count: u64,
+    pub active_torrent_count: u64,
+    pub paused_torrent_count: u64,
+    #[serde(rename(deserialize = "cumulative-stats"))]
+    pub cumulative: TransferStats,
+    #[serde(rename(deserialize = "current-stats"))]
+    pub current: TransferStats,
+}
+
+// The only session keys Transmission spells in camelCase rather than kebab-case
+const CAMEL_CASE_KEYS: [(&str, &str); 2] = [
+    ("seed_ratio_limit", "seedRatioLimit"),
+    ("seed_ratio_limited", "seedRatioLimited"),
+];
+
+/// Session keys are kebab-case in Transmission and snake_case in this API.
+pub fn to_rpc<T: Serialize>(settings: &T) -> AppResult<Value> {
+    let value = serde_json::to_value(settings).map_err(|e| AppError::ParseError(e.to_string()))?;
+    Ok(rename_keys(value, |key| {
+        CAMEL_CASE_KEYS
+            .iter()
+            .find(|(snake, _)| *snake == key)
+            .map(|(_, camel)| camel.to_string())
+            .unwrap_or_else(|| key.replace('_', "-"))
+    }))
+}
+
+pub fn from_rpc<T: DeserializeOwned>(value: Value) -> AppResult<T> {
+    let value = rename_keys(value, |key| {
+        CAMEL_CASE_KEYS
+            .iter()
+            .find(|(_, camel)| *camel == key)
+            .map(|(snake, _)| snake.to_string())
+            .unwrap_or_else(|| key.replace('-', "_"))
+    });
+    serde_json::from_value(value)
+        .map_err(|e| AppError::ParseError(format!("Invalid session reply: {}", e)))
+}
+
+fn rename_keys(value: Value, rename: impl Fn(&str) -> String) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(k, v)| (rename(&k), v))
+                .collect::<Map<_, _>>(),
+        ),
+        other => other,
+    }
+}
+
+fn invalid(msg: String) -> AppError {
+    AppError::ValidationError(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn queue_settings_round_trip() {
+        let reply = json!({
+            "download-queue-enabled": true,
+            "download-queue-size": 3,
+            "queue-stalled-minutes": 30,
+            "speed-limit-down": 100
+        });
+        let settings: QueueSettings = from_rpc(reply).unwrap();
+        assert_eq!(settings.download_queue_size, Some(3));
+        assert_eq!(settings.seed_queue_size, None);
+
+        let update = QueueSettings {
+            download_queue_size: Some(5),
+            queue_stalled_enabled: Some(false),
+            ..QueueSettings::default()
+        };
+        assert_eq!(
+            to_rpc(&update).unwrap(),
+            json!({ "download-queue-size": 5, "queue-stalled-enabled": false })
+        );
+    }
+
+    #[test]
+    fn queue_settings_validation() {
+        assert!(QueueSettings::default().validate().is_ok());
+        let zero = QueueSettings {
+            seed_queue_size: Some(0),
+            ..QueueSettings::default()
+        };
+        assert!(zero.validate().is_err());
+    }
+
+    #[test]
+    fn session_settings_round_trip() {
+        let reply = json!({
+            "speed-limit-down": 500,
+            "alt-speed-enabled": true,
+            "encryption": "preferred",
+            "seedRatioLimit": 2.0,
+            "download-dir": "/mnt/usb/Downloads",
+            "download-queue-size": 4,
+            "version": "3.00"
+        });
+        let settings: SessionSettings = from_rpc(reply).unwrap();
+        assert_eq!(settings.speed_limit_down, Some(500));
+        assert_eq!(settings.encryption, Some(Encryption::Preferred));
+        assert_eq!(settings.seed_ratio_limit, Some(2.0));
+        assert_eq!(settings.queue.download_queue_size, Some(4));
+
+        let update = SessionSettings {
+            alt_speed_enabled: Some(false),
+            seed_ratio_limited: Some(true),
+            queue: QueueSettings {
+                seed_queue_size: Some(2),
+                ..QueueSettings::default()
+            },
+            ..SessionSettings::default()
+        };
+        assert_eq!(
+            to_rpc(&update).unwrap(),
+            json!({
+                "alt-speed-enabled": false,
+                "seedRatioLimited": true,
+                "seed-queue-size": 2
+            })
+        );
+    }
+
+    #[test]
+    fn session_settings_validation() {
+        let dirs = vec!["/mnt/usb".to_string()];
+        let settings = SessionSettings {
+            speed_limit_down: Some(0),
+            peer_port: Some(51413),
+            download_dir: Some("/mnt/usb/Downloads".to_string()),
+            ..SessionSettings::default()
+        };
+        assert!(settings.validate(&dirs).is_ok());
+
+        let invalid = [
+            SessionSettings {
+                alt_speed_up: Some(-1),
+                ..SessionSettings::default()
+            },
+            SessionSettings {
+                peer_limit_global: Some(0),
+                ..SessionSettings::default()
+            },
+            SessionSettings {
+                peer_port: Some(70000),
+                ..SessionSettings::default()
+            },
+            SessionSettings {
+                download_dir: Some("/etc".to_string()),
+                ..SessionSettings::default()
+            },
+            SessionSettings {
+                queue: QueueSettings {
+                    download_queue_size: Some(0),
+                    ..QueueSettings::default()
+                },
+                ..SessionSettings::default()
+            },
+        ];
+        for settings in invalid.iter() {
+            assert!(settings.validate(&dirs).is_err());
+        }
+    }
+
+    #[test]
+    fn session_stats_reply() {
+        let reply = json!({
+            "activeTorrentCount": 2,
+            "downloadSpeed": 1024,
+            "pausedTorrentCount": 1,
+            "torrentCount": 3,
+            "uploadSpeed": 512,
+            "cumulative-stats": {
+                "uploadedBytes": 10,
+                "downloadedBytes": 20,
+                "filesAdded": 3,
+                "sessionCount": 4,
+                "secondsActive": 50
+            },
+            "current-stats": {
+                "uploadedBytes": 1,
+                "downloadedBytes": 2,
+                "filesAdded": 0,
+                "sessionCount": 1,
+                "secondsActive": 5
+            }
+        });
+        let stats: SessionStats = serde_json::from_value(reply).unwrap();
+        assert_eq!(stats.active_torrent_count, 2);
+        assert_eq!(stats.cumulative.downloaded_bytes, 20);
+        assert_eq!(stats.current.seconds_active, 5);
+
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["cumulative"]["uploaded_bytes"], 10);
+    }
+
+    #[test]
+    fn queue_move_methods() {
+        let direction: QueueMove = serde_json::from_str("\"top\"").unwrap();
+        assert_eq!(direction.method(), "queue-move-top");
+        assert_eq!(QueueMove::Down.method(), "queue-move-down");
+    }
+}
@@ -0,0 +1,316 @@
+use std::{
+    io,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures::stream;
+use serde::{Deserialize, Deserializer};
+use tokio::{fs::File, io::AsyncReadExt};
+
+use super::client::Client;
+use crate::files::FileStream;
+
+/// Transmission 4.1 is the first to download pieces in order.
+pub const SEQUENTIAL_RPC_VERSION: i64 = 18;
+
+/// How often a waiting stream asks Transmission for the pieces it has.
+const PIECE_POLL: Duration = Duration::from_secs(1);
+
+/// How long a stream waits for a missing piece before giving up.
+const PIECE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TorrentFile {
+    /// Path below the download directory, starting with the torrent's folder
+    pub name: String,
+    pub length: u64,
+    pub bytes_completed: u64,
+}
+
+impl TorrentFile {
+    pub fn complete(&self) -> bool {
+        self.bytes_completed >= self.length
+    }
+}
+
+/// Which pieces of a torrent are downloaded and how its files are laid out on them.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PieceMap {
+    pub name: String,
+    pub download_dir: String,
+    pub piece_size: u64,
+    pub piece_count: u64,
+    /// One bit per piece, the first piece is the highest bit of the first byte
+    #[serde(deserialize_with = "bitfield")]
+    pub pieces: Vec<u8>,
+    pub files: Vec<TorrentFile>,
+}
+
+fn bitfield<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    base64::decode(encoded.trim()).map_err(serde::de::Error::custom)
+}
+
+impl PieceMap {
+    pub const FIELDS: [&'static str; 6] = [
+        "name",
+        "downloadDir",
+        "pieceSize",
+        "pieceCount",
+        "pieces",
+        "files",
+    ];
+
+    pub fn has(&self, piece: u64) -> bool {
+        piece < self.piece_count
+            && self
+                .pieces
+                .get((piece / 8) as usize)
+                .is_some_and(|byte| byte & (0x80 >> (piece % 8)) != 0)
+    }
+
+    /// Whether the bytes from `start` up to `end` of the torrent's data are downloaded.
+    pub fn covers(&self, start: u64, end: u64) -> bool {
+        if end <= start || self.piece_size == 0 {
+            return true;
+        }
+        (start / self.piece_size..=(end - 1) / self.piece_size).all(|piece| self.has(piece))
+    }
+
+    /// The index of the file at `path` below the torrent's folder, "" for single file torrents.
+    pub fn file(&self, path: &str) -> Option<usize> {
+        let path = path.trim_start_matches('/');
+        self.files.iter().position(|file| {
+            if file.name == self.name {
+                return path.is_empty();
+            }
+            file.name
+                .strip_prefix(&self.name)
+                .and_then(|rest| rest.strip_prefix('/'))
+                == Some(path)
+        })
+    }
+
+    /// Where a file starts in the torrent's data, files follow each other in order.
+    pub fn offset(&self, index: usize) -> u64 {
+        self.files[..index].iter().map(|f| f.length).sum()
+    }
+}
+
+struct Reader {
+    client: Arc<Client>,
+    id: i64,
+    map: PieceMap,
+    index: usize,
+    file: Option<File>,
+    position: u64,
+    remaining: u64,
+}
+
+impl Reader {
+    /// Waits until Transmission has the pieces holding the file's bytes from `start` to `end`.
+    async fn wait(&mut self, start: u64, end: u64) -> io::Result<()> {
+        let offset = self.map.offset(self.index);
+        let started = Instant::now();
+        while !self.map.covers(offset + start, offset + end) {
+            if started.elapsed() > PIECE_TIMEOUT {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "The piece did not arrive in time",
+                ));
+            }
+            tokio::time::delay_for(PIECE_POLL).await;
+            self.map = self
+                .client
+                .piece_map(self.id)
+                .await
+                .map_err(io::Error::other)?
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "The torrent was removed")
+                })?;
+        }
+        Ok(())
+    }
+
+    // Transmission may still name incomplete files with ".part"
+    async fn open(&self) -> io::Result<File> {
+        let path = Path::new(&self.map.download_dir).join(&self.map.files[self.index].name);
+        let mut partial = path.clone().into_os_string();
+        partial.push(".part");
+        match File::open(&path).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                File::open(PathBuf::from(partial)).await
+            }
+            result => result,
+        }
+    }
+
+    async fn next_chunk(&mut self) -> io::Result<Bytes> {
+        let size = CHUNK_SIZE.min(self.remaining);
+        self.wait(self.position, self.position + size).await?;
+
+        if self.file.is_none() {
+            let mut file = self.open().await?;
+            file.seek(SeekFrom::Start(self.position)).await?;
+            self.file = Some(file);
+        }
+        let file = self.file.as_mut().unwrap();
+        let mut buffer = vec![0; size as usize];
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buffer.truncate(read);
+        self.position += read as u64;
+        self.remaining -= read as u64;
+        Ok(Bytes::from(buffer))
+    }
+}
+
+/// Streams `len` bytes from `start` of a file of a torrent still downloading, holding
+/// back each chunk until its pieces are there.
+pub fn download_stream(
+    client: Arc<Client>,
+    id: i64,
+    map: PieceMap,
+    index: usize,
+    start: u64,
+    len: u64,
+) -> FileStream {
+    let reader = Reader {
+        client,
+        id,
+        map,
+        index,
+        file: None,
+        position: start,
+        remaining: len,
+    };
+    Box::pin(stream::unfold(reader, |mut reader| async move {
+        if reader.remaining == 0 {
+            return None;
+        }
+        match reader.next_chunk().await {
+            Ok(chunk) => Some((Ok(chunk), reader)),
+            Err(e) => {
+                reader.remaining = 0;
+                Some((Err(e), reader))
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::http_server, torrent::rpc::Rpc};
+    use futures::StreamExt;
+    use transmission_rpc::TransClient;
+
+    fn map(pieces: &str) -> PieceMap {
+        serde_json::from_value(serde_json::json!({
+            "name": "Show",
+            "downloadDir": "/downloads",
+            "pieceSize": 4,
+            "pieceCount": 10,
+            "pieces": pieces,
+            "files": [
+                { "name": "Show/info.nfo", "length": 6, "bytesCompleted": 6 },
+                { "name": "Show/Season 1/e01.mkv", "length": 30, "bytesCompleted": 8 },
+                { "name": "Show/e02.mkv", "length": 4, "bytesCompleted": 0 }
+            ]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn pieces_and_files() {
+        // Pieces 0, 1, 2 and 8
+        let map = map(&base64::encode([0b1110_0000, 0b1000_0000]));
+        assert!(map.has(0) && map.has(2) && map.has(8));
+        assert!(!map.has(3) && !map.has(9) && !map.has(64));
+
+        assert_eq!(map.file("Season 1/e01.mkv"), Some(1));
+        assert_eq!(map.file("/e02.mkv"), Some(2));
+        assert_eq!(map.file("Show/e02.mkv"), None);
+        assert_eq!(map.file(""), None);
+        assert_eq!(map.offset(2), 36);
+
+        // Pieces 0 to 2 hold bytes 0 to 11, e02.mkv is in piece 8
+        assert!(map.covers(0, 12));
+        assert!(!map.covers(0, 13));
+        assert!(map.covers(32, 36));
+        assert!(map.covers(5, 5));
+        assert!(!map.files[1].complete());
+    }
+
+    #[test]
+    fn single_file_torrents() {
+        let map: PieceMap = serde_json::from_value(serde_json::json!({
+            "name": "movie.mkv",
+            "downloadDir": "/downloads",
+            "pieceSize": 16384,
+            "pieceCount": 1,
+            "pieces": "gA==",
+            "files": [{ "name": "movie.mkv", "length": 100, "bytesCompleted": 100 }]
+        }))
+        .unwrap();
+        assert_eq!(map.file(""), Some(0));
+        assert_eq!(map.file("movie.mkv"), None);
+        assert!(map.covers(0, 100));
+    }
+
+    #[tokio::test]
+    async fn waits_for_missing_pieces() {
+        let dir = std::env::temp_dir().join(format!("stream-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("Show")).unwrap();
+        // Still downloading, so Transmission has it as .part
+        std::fs::write(dir.join("Show/e02.mkv.part"), "0123456789").unwrap();
+
+        let reply = |pieces: &str| {
+            let reply = serde_json::json!({
+                "result": "success",
+                "arguments": { "torrents": [{
+                    "name": "Show",
+                    "downloadDir": dir.to_string_lossy(),
+                    "pieceSize": 4,
+                    "pieceCount": 3,
+                    "pieces": pieces,
+                    "files": [{ "name": "Show/e02.mkv", "length": 10, "bytesCompleted": 8 }]
+                }]}
+            });
+            reply.to_string()
+        };
+        // The last piece arrives with the second poll
+        let (url, received) = http_server(vec![(200, reply("wA==")), (200, reply("4A=="))]);
+        let client = Arc::new(Client::new(TransClient::new(&url), Rpc::new(&url)));
+
+        let map: PieceMap = serde_json::from_value(serde_json::json!({
+            "name": "Show",
+            "downloadDir": dir.to_string_lossy(),
+            "pieceSize": 4,
+            "pieceCount": 3,
+            "pieces": "wA==",
+            "files": [{ "name": "Show/e02.mkv", "length": 10, "bytesCompleted": 8 }]
+        }))
+        .unwrap();
+        let mut stream = download_stream(client, 7, map, 0, 2, 8);
+        let mut read = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            read.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(read, b"23456789");
+        assert_eq!(received.lock().unwrap().len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
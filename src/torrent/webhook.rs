@@ -0,0 +1,310 @@
+use std::time::Duration;
+
+use hmac::{Hmac, Mac, NewMac};
+use log::{error, info, warn};
+use reqwest::{header::CONTENT_TYPE, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::Sha256;
+use tokio::sync::broadcast::{self, RecvError};
+
+use super::{
+    events::{fill, EventKind, TorrentEvent},
+    metainfo::hex,
+};
+use crate::error::{AppError, AppResult};
+
+/// Header carrying the HMAC-SHA256 of the body when the hook has a secret.
+pub const SIGNATURE_HEADER: &str = "X-Signature-256";
+
+const BACKOFF: Duration = Duration::from_secs(5);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn default_retries() -> u32 {
+    3
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Webhook {
+    pub url: String,
+    /// Events that fire the hook, all of them when empty
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    /// JSON sent instead of the event, "{{field}}" in its strings is replaced
+    /// with the event, time or torrent field of that name
+    #[serde(default)]
+    pub template: Option<Value>,
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// Further attempts after a network error or a 5xx/429 answer
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+}
+
+impl Webhook {
+    pub fn validate(&self) -> AppResult<()> {
+        if !(self.url.starts_with("http://") || self.url.starts_with("https://")) {
+            return Err(AppError::ValidationError(format!(
+                "Webhook url must be http or https: {}",
+                self.url
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn wants(&self, event: EventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+
+    pub fn body(&self, event: &TorrentEvent) -> String {
+        let value = match &self.template {
+            Some(template) => render(template, &event.placeholders()),
+            None => serde_json::to_value(event).unwrap_or_default(),
+        };
+        value.to_string()
+    }
+
+    /// Posts the event, retrying with exponential backoff starting at `backoff`.
+    pub async fn deliver(
+        &self,
+        http: &reqwest::Client,
+        event: &TorrentEvent,
+        backoff: Duration,
+    ) -> AppResult<()> {
+        let body = self.body(event);
+        let mut attempt = 0;
+        loop {
+            let mut request = http
+                .post(&self.url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(secret) = &self.secret {
+                request =
+                    request.header(SIGNATURE_HEADER, format!("sha256={}", sign(secret, &body)));
+            }
+
+            let (error, retry) = match request.send().await {
+                Ok(res) if res.status().is_success() => return Ok(()),
+                Ok(res) => {
+                    let status = res.status();
+                    (
+                        AppError::NetworkError(format!("{} answered {}", self.url, status)),
+                        status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+                    )
+                }
+                Err(e) => (AppError::HttpError(e), true),
+            };
+            if !retry || attempt >= self.retries {
+                return Err(error);
+            }
+
+            let delay = backoff * 2u32.saturating_pow(attempt);
+            warn!("Webhook failed, retrying in {:?}: {}", delay, error);
+            tokio::time::delay_for(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// Hex encoded HMAC-SHA256 of `body`.
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC takes any key size");
+    mac.update(body.as_bytes());
+    hex(&mac.finalize().into_bytes())
+}
+
+// A string that is only a placeholder takes the field's JSON value, so numbers stay numbers
+fn render(template: &Value, fields: &Map<String, Value>) -> Value {
+    match template {
+        Value::String(text) => {
+            let whole = text
+                .strip_prefix("{{")
+                .and_then(|t| t.strip_suffix("}}"))
+                .and_then(|name| fields.get(name.trim()));
+            if let Some(value) = whole {
+                return value.clone();
+            }
+
+            Value::String(fill(text, fields))
+        }
+        Value::Array(items) => Value::Array(items.iter().map(|v| render(v, fields)).collect()),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), render(v, fields)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+/// Fires the configured hooks for events published on the bus.
+pub struct Webhooks {
+    hooks: Vec<Webhook>,
+    http: reqwest::Client,
+}
+
+impl Webhooks {
+    pub fn new(hooks: Vec<Webhook>) -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        Self { hooks, http }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// Runs until the bus goes away. Each delivery gets its own task so a
+    /// hook that is down and retrying does not hold back the others.
+    pub async fn run(&self, mut events: broadcast::Receiver<TorrentEvent>) {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Webhooks missed {} events", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            for hook in self.hooks.iter().filter(|h| h.wants(event.event)) {
+                let (hook, http, event) = (hook.clone(), self.http.clone(), event.clone());
+                tokio::spawn(async move {
+                    match hook.deliver(&http, &event, BACKOFF).await {
+                        Ok(_) => info!("Webhook {} sent for {}", hook.url, event.torrent.name),
+                        Err(e) => error!("Webhook {} gave up: {}", hook.url, e),
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        testing::{self, http_server},
+        torrent::client::Torrent,
+    };
+
+    fn event(kind: EventKind) -> TorrentEvent {
+        TorrentEvent {
+            event: kind,
+            torrent: Torrent {
+                id: 7,
+                name: "Big Buck Bunny".to_string(),
+                hash: format!("{:040x}", 7),
+                status: "done".to_string(),
+                size: 2048,
+                progress: 100,
+                category: Some("movies".to_string()),
+                ..testing::torrent()
+            },
+            time: "2024-01-01T00:00:00+00:00".to_string(),
+        }
+    }
+
+    fn hook(url: &str) -> Webhook {
+        Webhook {
+            url: url.to_string(),
+            events: vec![],
+            template: None,
+            secret: None,
+            retries: 2,
+        }
+    }
+
+    #[test]
+    fn sign_known_vector() {
+        assert_eq!(
+            sign("key", "The quick brown fox jumps over the lazy dog"),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn render_template() {
+        let mut hook = hook("http://localhost/hook");
+        hook.template = Some(json!({
+            "message": "{{name}} is {{event}}",
+            "size": "{{size}}",
+            "tags": ["{{category}}", "fixed"],
+            "missing": "{{nope}}"
+        }));
+
+        let body: Value = serde_json::from_str(&hook.body(&event(EventKind::Completed))).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "message": "Big Buck Bunny is completed",
+                "size": 2048,
+                "tags": ["movies", "fixed"],
+                "missing": "{{nope}}"
+            })
+        );
+    }
+
+    #[test]
+    fn event_filter() {
+        let mut hook = hook("http://localhost/hook");
+        assert!(hook.wants(EventKind::Paused));
+        hook.events = vec![EventKind::Completed, EventKind::Errored];
+        assert!(hook.wants(EventKind::Errored));
+        assert!(!hook.wants(EventKind::Added));
+
+        assert!(hook.validate().is_ok());
+        assert!(Webhook {
+            url: "ftp://localhost".to_string(),
+            ..hook
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn deliver_signed_with_retries() {
+        let (url, received) = http_server(vec![(503, ""), (200, "")]);
+        let mut hook = hook(&format!("{}/hook", url));
+        hook.secret = Some("s3cret".to_string());
+
+        let http = reqwest::Client::new();
+        let event = event(EventKind::Completed);
+        hook.deliver(&http, &event, Duration::from_millis(10))
+            .await
+            .unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        let last = &received[1];
+        let body: Value = serde_json::from_str(&last.body).unwrap();
+        assert_eq!(body["event"], "completed");
+        assert_eq!(body["torrent"]["name"], "Big Buck Bunny");
+        assert_eq!(last.target, "POST /hook");
+        let signature = format!("sha256={}", sign("s3cret", &last.body));
+        assert_eq!(last.header(SIGNATURE_HEADER), Some(signature.as_str()));
+    }
+
+    #[tokio::test]
+    async fn deliver_gives_up() {
+        // Client errors are not retried
+        let (url, received) = http_server(vec![(404, "")]);
+        let http = reqwest::Client::new();
+        let result = hook(&url)
+            .deliver(&http, &event(EventKind::Added), Duration::from_millis(10))
+            .await;
+        assert!(result.is_err());
+        assert_eq!(received.lock().unwrap().len(), 1);
+
+        let (url, received) = http_server(vec![(500, ""), (500, ""), (500, "")]);
+        let result = hook(&url)
+            .deliver(&http, &event(EventKind::Added), Duration::from_millis(10))
+            .await;
+        assert!(result.is_err());
+        assert_eq!(received.lock().unwrap().len(), 3);
+    }
+}
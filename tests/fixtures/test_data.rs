@@ -8,23 +8,44 @@ pub fn sample_torrents() -> Vec<Torrent> {
         Torrent {
             id: 1,
             name: "Test Movie 2023".to_string(),
+            hash: "1111111111111111111111111111111111111111".to_string(),
             status: "downloading".to_string(),
             size: 1610612736, // 1.5 GB in bytes
             progress: 75,
+            queue_position: 0,
+            stalled: false,
+            error: None,
+            labels: vec!["movies".to_string()],
+            download_dir: "/mnt/usb/Movies".to_string(),
+            category: None,
         },
         Torrent {
             id: 2,
             name: "Another Show S01E01".to_string(),
+            hash: "2222222222222222222222222222222222222222".to_string(),
             status: "done".to_string(),
             size: 786432000, // 750 MB in bytes
             progress: 100,
+            queue_position: 0,
+            stalled: false,
+            error: None,
+            labels: vec!["tv".to_string()],
+            download_dir: "/mnt/usb/TV".to_string(),
+            category: None,
         },
         Torrent {
             id: 3,
             name: "Old Movie 1999".to_string(),
+            hash: "3333333333333333333333333333333333333333".to_string(),
             status: "paused".to_string(),
             size: 2147483648, // 2 GB in bytes
             progress: 25,
+            queue_position: 0,
+            stalled: false,
+            error: None,
+            labels: vec![],
+            download_dir: "/downloads".to_string(),
+            category: None,
         },
     ]
 }